@@ -0,0 +1,104 @@
+use proc_macro::TokenStream;
+use quote::{format_ident, quote};
+use syn::{parse_macro_input, Data, DeriveInput, Error, Fields};
+
+// `#[derive(SlotMapStruct)]` on `struct Particle { pos: Vec3, .. }` generates
+// `ParticleSlotMap`: one column per field, all sharing one key space. Besides
+// `insert`/`get`/`remove`, which take and rebuild the whole struct, every field
+// gets `pos(key)`, `pos_mut(key)`, `iter_pos()` and `iter_pos_mut()`. Fields
+// must be `Clone + Default`.
+#[proc_macro_derive(SlotMapStruct)]
+pub fn derive_slot_map_struct(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => &fields.named,
+            _ => return Error::new_spanned(&input.ident, "SlotMapStruct needs named fields").to_compile_error().into(),
+        },
+        _ => return Error::new_spanned(&input.ident, "SlotMapStruct only applies to structs").to_compile_error().into(),
+    };
+    if !input.generics.params.is_empty() {
+        return Error::new_spanned(&input.generics, "SlotMapStruct doesn't support generic structs").to_compile_error().into();
+    }
+
+    let (vis, item) = (&input.vis, &input.ident);
+    let map = format_ident!("{}SlotMap", item);
+    let names: Vec<_> = fields.iter().map(|field| field.ident.as_ref().unwrap()).collect();
+    let types: Vec<_> = fields.iter().map(|field| &field.ty).collect();
+    let getters: Vec<_> = names.iter().map(|name| format_ident!("{}_mut", name)).collect();
+    let iters: Vec<_> = names.iter().map(|name| format_ident!("iter_{}", name)).collect();
+    let iters_mut: Vec<_> = names.iter().map(|name| format_ident!("iter_{}_mut", name)).collect();
+
+    quote! {
+        #vis struct #map {
+            keys: ::fastslotmap::SoaKeys,
+            #(#names: Vec<#types>,)*
+        }
+
+        impl #map {
+            #vis fn new() -> Self {
+                Self { keys: ::fastslotmap::SoaKeys::new(), #(#names: Vec::new(),)* }
+            }
+
+            #vis fn insert(&mut self, item: #item) -> ::fastslotmap::Key {
+                let (key, slot) = self.keys.insert();
+                #(
+                    if slot >= self.#names.len() {
+                        self.#names.resize_with(slot + 1, Default::default);
+                    }
+                    self.#names[slot] = item.#names;
+                )*
+                key
+            }
+
+            #vis fn get(&self, key: ::fastslotmap::Key) -> Option<#item> {
+                let slot = self.keys.slot(key)?;
+                Some(#item { #(#names: self.#names[slot].clone(),)* })
+            }
+
+            #vis fn remove(&mut self, key: ::fastslotmap::Key) -> Option<#item> {
+                let slot = self.keys.remove(key)?;
+                Some(#item { #(#names: ::std::mem::take(&mut self.#names[slot]),)* })
+            }
+
+            #vis fn contains(&self, key: ::fastslotmap::Key) -> bool {
+                self.keys.slot(key).is_some()
+            }
+
+            #vis fn len(&self) -> u32 {
+                self.keys.len()
+            }
+
+            #vis fn is_empty(&self) -> bool {
+                self.keys.is_empty()
+            }
+
+            #(
+                #vis fn #names(&self, key: ::fastslotmap::Key) -> Option<&#types> {
+                    Some(&self.#names[self.keys.slot(key)?])
+                }
+
+                #vis fn #getters(&mut self, key: ::fastslotmap::Key) -> Option<&mut #types> {
+                    Some(&mut self.#names[self.keys.slot(key)?])
+                }
+
+                #vis fn #iters(&self) -> impl Iterator<Item = (::fastslotmap::Key, &#types)> + '_ {
+                    let keys = &self.keys;
+                    self.#names.iter().enumerate().filter_map(move |(slot, value)| Some((keys.key_at(slot)?, value)))
+                }
+
+                #vis fn #iters_mut(&mut self) -> impl Iterator<Item = (::fastslotmap::Key, &mut #types)> + '_ {
+                    let keys = &self.keys;
+                    self.#names.iter_mut().enumerate().filter_map(move |(slot, value)| Some((keys.key_at(slot)?, value)))
+                }
+            )*
+        }
+
+        impl Default for #map {
+            fn default() -> Self {
+                Self::new()
+            }
+        }
+    }
+    .into()
+}
@@ -1,95 +1,9732 @@
-use std::sync::atomic::{AtomicU32, Ordering};
-
-#[derive(Debug, Copy, Clone, PartialEq, Eq)]
-pub struct Key {
-    index: u32,
-    generation: u32,
-}
-
-pub struct FastSlotMap<T> {
-    values: Vec<T>,               // Packed storage for values
-    generations: Vec<u32>,        // Tracks slot validity
-    next_free: Vec<AtomicU32>,    // Atomic free-list for lock-free operations
-    free_head: AtomicU32,         // Head of free list (lock-free)
-    len: AtomicU32,               // Number of active elements
-}
-
-impl<T: Default + Copy> FastSlotMap<T> {
-    pub fn new() -> Self {
-        Self {
-            values: Vec::new(),
-            generations: Vec::new(),
-            next_free: Vec::new(),
-            free_head: AtomicU32::new(u32::MAX),
-            len: AtomicU32::new(0),
-        }
-    }
-
-    pub fn insert(&mut self, value: T) -> Key {
-        let index;
-        let generation;
-
-        loop {
-            let free_index = self.free_head.load(Ordering::Acquire);
-
-            if free_index != u32::MAX {
-                // Try to pop from the free list
-                let next_free = self.next_free[free_index as usize].load(Ordering::Relaxed);
-                if self.free_head.compare_exchange(free_index, next_free, Ordering::Release, Ordering::Relaxed).is_ok() {
-                    index = free_index;
-                    generation = self.generations[index as usize];
-                    self.values[index as usize] = value;
-                    self.len.fetch_add(1, Ordering::Relaxed);
-                    return Key { index, generation };
-                }
-            } else {
-                // Allocate a new slot
-                index = self.values.len() as u32;
-                self.values.push(value);
-                self.generations.push(0);
-                self.next_free.push(AtomicU32::new(u32::MAX));
-                self.len.fetch_add(1, Ordering::Relaxed);
-                return Key { index, generation: 0 };
-            }
-        }
-    }
-
-    pub fn get(&self, key: Key) -> Option<&T> {
-        self.values.get(key.index as usize).filter(|_| self.generations[key.index as usize] == key.generation)
-    }
-
-    pub fn get_mut(&mut self, key: Key) -> Option<&mut T> {
-        self.values.get_mut(key.index as usize).filter(|_| self.generations[key.index as usize] == key.generation)
-    }
-
-    pub fn remove(&mut self, key: Key) -> Option<T> {
-        if self.generations[key.index as usize] == key.generation {
-            self.generations[key.index as usize] = self.generations[key.index as usize].wrapping_add(1);
-            self.len.fetch_sub(1, Ordering::Relaxed);
-            let value = self.values[key.index as usize];
-
-            // Push this slot to the freelist
-            let mut free_head = self.free_head.load(Ordering::Acquire);
-            loop {
-                self.next_free[key.index as usize].store(free_head, Ordering::Relaxed);
-                if self.free_head.compare_exchange(free_head, key.index, Ordering::Release, Ordering::Relaxed).is_ok() {
-                    return Some(value);
-                }
-                free_head = self.free_head.load(Ordering::Acquire);
-            }
-        }
-        None
-    }
-
-    pub fn contains(&self, key: Key) -> bool {
-        self.get(key).is_some()
-    }
-
-    pub fn len(&self) -> u32 {
-        self.len.load(Ordering::Relaxed)
-    }
-
-    pub fn is_empty(&self) -> bool {
-        self.len() == 0
-    }
-}
+use std::any::{Any, TypeId};
+use std::cell::{RefCell, UnsafeCell};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap, HashMap};
+use std::future::Future;
+use std::hash::Hash;
+use std::hint;
+use std::iter::FusedIterator;
+use std::marker::PhantomData;
+use std::mem::MaybeUninit;
+use std::ops::{Deref, DerefMut, Index, IndexMut, Range};
+use std::pin::Pin;
+use std::ptr;
+use std::sync::atomic::{fence, AtomicPtr, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::task::{Context, Poll};
+#[cfg(all(debug_assertions, feature = "key-provenance"))]
+use std::sync::atomic::AtomicU16;
+
+#[cfg(feature = "derive")]
+pub use fastslotmap_derive::SlotMapStruct;
+
+// Derive output names this crate as `::fastslotmap`, which has to resolve in
+// its own tests too
+#[cfg(all(test, feature = "derive"))]
+extern crate self as fastslotmap;
+
+#[derive(Debug, Copy, Clone, Eq)]
+pub struct Key {
+    index: u32,
+    generation: u32,
+    // Id of the issuing `FastSlotMap`, 0 if unknown. Debug builds with the
+    // `key-provenance` feature only; release layout is unaffected.
+    #[cfg(all(debug_assertions, feature = "key-provenance"))]
+    map_id: u16,
+}
+
+// Provenance is a debugging aid and never affects equality
+impl PartialEq for Key {
+    fn eq(&self, other: &Self) -> bool {
+        self.index == other.index && self.generation == other.generation
+    }
+}
+
+// Feeds the `as_ffi` integer as a single `write_u64` (cheaper than two u32
+// writes with Fx-style hashers). This is a stability promise: with a hasher
+// that is itself deterministic, a key hashes the same on every run.
+impl Hash for Key {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        state.write_u64(self.as_ffi());
+    }
+}
+
+impl Key {
+    fn new(index: u32, generation: u32) -> Self {
+        Key {
+            index,
+            generation,
+            #[cfg(all(debug_assertions, feature = "key-provenance"))]
+            map_id: 0,
+        }
+    }
+
+    // A key that never resolves in any map
+    pub fn null() -> Self {
+        Key::new(u32::MAX, 0)
+    }
+
+    pub fn is_null(&self) -> bool {
+        self.index == u32::MAX
+    }
+
+    // Persistable form of a key. This layout is a compatibility promise and will
+    // only ever change by introducing a new format byte:
+    //   format 1: [0x01, index (u32 LE), generation (u32 LE)]
+    pub fn to_stable_bytes(&self) -> [u8; STABLE_KEY_LEN] {
+        let mut bytes = [0; STABLE_KEY_LEN];
+        bytes[0] = STABLE_KEY_FORMAT;
+        bytes[1..5].copy_from_slice(&self.index.to_le_bytes());
+        bytes[5..9].copy_from_slice(&self.generation.to_le_bytes());
+        bytes
+    }
+
+    // Accepts every format ever produced by `to_stable_bytes`
+    pub fn from_stable_bytes(bytes: &[u8]) -> Option<Key> {
+        match bytes {
+            &[1, i0, i1, i2, i3, g0, g1, g2, g3] => {
+                Some(Key::new(u32::from_le_bytes([i0, i1, i2, i3]), u32::from_le_bytes([g0, g1, g2, g3])))
+            }
+            _ => None,
+        }
+    }
+
+    // Single-integer form for FFI: generation in the high half, index in the low half
+    pub fn as_ffi(&self) -> u64 {
+        (self.generation as u64) << 32 | self.index as u64
+    }
+
+    pub fn from_ffi(value: u64) -> Key {
+        Key::new(value as u32, (value >> 32) as u32)
+    }
+}
+
+pub const STABLE_KEY_LEN: usize = 9;
+const STABLE_KEY_FORMAT: u8 = 1;
+
+// A run of adjacent slots sharing one generation, as returned by `insert_contiguous`
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct KeySpan {
+    base: Key,
+    len: u32,
+}
+
+impl KeySpan {
+    pub fn base(&self) -> Key {
+        self.base
+    }
+
+    pub fn len(&self) -> u32 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn key(&self, offset: u32) -> Option<Key> {
+        (offset < self.len).then_some(Key { index: self.base.index + offset, ..self.base })
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = Key> {
+        let base = self.base;
+        (0..self.len).map(move |offset| Key { index: base.index + offset, ..base })
+    }
+}
+
+pub struct PinGuard {
+    key: Key,
+    count: PinCount,
+}
+
+// Shared pin counter. Without the wasm atomics proposal there is only one
+// thread, so a plain `Rc<Cell>` replaces the atomic there; pins (and the maps
+// holding them) are then not `Send`.
+#[derive(Clone, Default)]
+struct PinCount(
+    #[cfg(not(all(target_arch = "wasm32", not(target_feature = "atomics"))))] Arc<AtomicU32>,
+    #[cfg(all(target_arch = "wasm32", not(target_feature = "atomics")))] std::rc::Rc<std::cell::Cell<u32>>,
+);
+
+impl PinCount {
+    #[cfg(not(all(target_arch = "wasm32", not(target_feature = "atomics"))))]
+    fn add(&self, delta: i32) {
+        self.0.fetch_add(delta as u32, Ordering::AcqRel);
+    }
+
+    #[cfg(all(target_arch = "wasm32", not(target_feature = "atomics")))]
+    fn add(&self, delta: i32) {
+        self.0.set(self.0.get().wrapping_add(delta as u32));
+    }
+
+    #[cfg(not(all(target_arch = "wasm32", not(target_feature = "atomics"))))]
+    fn get(&self) -> u32 {
+        self.0.load(Ordering::Acquire)
+    }
+
+    #[cfg(all(target_arch = "wasm32", not(target_feature = "atomics")))]
+    fn get(&self) -> u32 {
+        self.0.get()
+    }
+}
+
+impl PinGuard {
+    pub fn key(&self) -> Key {
+        self.key
+    }
+}
+
+impl Drop for PinGuard {
+    fn drop(&mut self) {
+        self.count.add(-1);
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Corruption {
+    ArrayLengthMismatch,                          // Slot arrays disagree on the number of slots
+    FreeListOutOfBounds { index: u32 },           // Free list links past the end of the slot arrays
+    FreeListCycle { index: u32 },                 // Free list revisits this slot
+    LiveSlotInFreeList { index: u32 },            // Occupied slot is reachable from the free list
+    UnreachableVacantSlot { index: u32 },         // Vacant slot is missing from the free list
+    LenMismatch { recorded: u32, counted: u32 },  // Stored len differs from the number of live slots
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValidationReport {
+    pub slots: u32,               // Total allocated slots
+    pub live: u32,                // Occupied slots counted
+    pub free: u32,                // Slots reached by walking the free list
+    pub issues: Vec<Corruption>,  // Empty when every invariant holds
+}
+
+impl ValidationReport {
+    pub fn is_valid(&self) -> bool {
+        self.issues.is_empty()
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Compaction {
+    cursor: u32,    // Entries at or above this slot are done for this pass
+    held: Vec<u32>, // Vacant slots above the cursor, kept off the free list until the pass ends
+}
+
+#[derive(Debug, Clone)]
+pub struct CompactProgress {
+    pub remap: KeyRemap, // Keys moved by this step
+    pub finished: bool,  // The pass is complete; the next step starts a new one
+}
+
+// Future that is pending once, handing the executor back to other tasks
+struct YieldNow(bool);
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.0 {
+            return Poll::Ready(());
+        }
+        self.0 = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+// Old key -> new key translation produced when entries move between slots
+#[derive(Debug, Clone)]
+pub struct KeyRemap {
+    mapping: Mapping,
+}
+
+#[derive(Debug, Clone)]
+enum Mapping {
+    Identity,                           // Storage was moved wholesale, every key maps to itself
+    Table(Vec<Option<(u32, Key)>>),     // Indexed by old slot: (old generation, new key); unlisted keys are gone
+    Moves(HashMap<u32, (u32, Key)>),    // Old slot -> (old generation, new key); unlisted keys did not move
+}
+
+impl KeyRemap {
+    pub fn remap(&self, key: Key) -> Option<Key> {
+        let entry = match &self.mapping {
+            Mapping::Identity => return Some(key),
+            Mapping::Table(entries) => entries.get(key.index as usize).copied().flatten(),
+            Mapping::Moves(moves) => match moves.get(&key.index) {
+                Some(&entry) => Some(entry),
+                None => return Some(key),
+            },
+        };
+        match entry {
+            Some((generation, new_key)) if generation == key.generation => Some(new_key),
+            _ => None,
+        }
+    }
+
+    // Number of entries that moved to a different key
+    pub fn len(&self) -> usize {
+        match &self.mapping {
+            Mapping::Identity => 0,
+            Mapping::Table(entries) => entries.iter().flatten().count(),
+            Mapping::Moves(moves) => moves.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Folds the moves of a later compaction step into this one's, so keys
+    // from before either step resolve through the result
+    fn chain(&mut self, next: KeyRemap) {
+        let (Mapping::Moves(moves), Mapping::Moves(mut later)) = (&mut self.mapping, next.mapping) else {
+            unreachable!("compaction steps only record moves");
+        };
+        for (_, key) in moves.values_mut() {
+            match later.get(&key.index) {
+                Some(&(generation, moved)) if generation == key.generation => {
+                    later.remove(&key.index);
+                    *key = moved;
+                }
+                _ => {}
+            }
+        }
+        moves.extend(later);
+    }
+
+    // Rewrites keys in place; keys with no mapping become `Key::null()`.
+    // Returns how many keys were remapped.
+    pub fn remap_slice(&self, keys: &mut [Key]) -> usize {
+        let mut remapped = 0;
+        for key in keys {
+            match self.remap(*key) {
+                Some(new_key) => {
+                    *key = new_key;
+                    remapped += 1;
+                }
+                None => *key = Key::null(),
+            }
+        }
+        remapped
+    }
+}
+
+// Slots changed since a write version, written by `FastSlotMap::export_since`:
+//   [since (u64 LE), version (u64 LE), slot count (u32 LE), entry count (u32 LE), entries...]
+//   entry: [index (u32 LE), generation (u32 LE), value length (u32 LE, u32::MAX if vacant), value]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeltaBlob(pub Vec<u8>);
+
+impl DeltaBlob {
+    // Write version the delta brings a snapshot up to; export the next delta since this
+    pub fn version(&self) -> Option<u64> {
+        Some(u64::from_le_bytes(self.0.get(8..16)?.try_into().ok()?))
+    }
+}
+
+// Splits `len` bytes off the front of `bytes`, for the binary readers
+fn take_bytes<'a>(bytes: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+    let (head, rest) = bytes.split_at_checked(len)?;
+    *bytes = rest;
+    Some(head)
+}
+
+// Key churn counted by a map with the `reuse-report` feature
+#[cfg(feature = "reuse-report")]
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct ReuseReport {
+    pub stale_keys: u64,       // Keys voided by removal, compaction or invalidate_all
+    pub failed_lookups: u64,   // Lookups and removals given a stale or out-of-bounds key
+    pub generation_wraps: u64, // Slot generations that wrapped back to 0
+    pub aborted_inserts: u64,  // Provisional entries aborted; not counted in the totals above
+}
+
+#[cfg(feature = "reuse-report")]
+impl std::fmt::Display for ReuseReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "stale keys: {}, failed lookups: {}, generation wraps: {}, aborted inserts: {}",
+            self.stale_keys, self.failed_lookups, self.generation_wraps, self.aborted_inserts
+        )
+    }
+}
+
+// Atomic so lookups through `&self` can count their failures
+#[cfg(feature = "reuse-report")]
+#[derive(Default)]
+struct ReuseCounters {
+    stale_keys: AtomicU64,
+    failed_lookups: AtomicU64,
+    generation_wraps: AtomicU64,
+    aborted_inserts: AtomicU64,
+}
+
+#[cfg(feature = "reuse-report")]
+impl ReuseCounters {
+    const fn new() -> Self {
+        Self {
+            stale_keys: AtomicU64::new(0),
+            failed_lookups: AtomicU64::new(0),
+            generation_wraps: AtomicU64::new(0),
+            aborted_inserts: AtomicU64::new(0),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SlotError {
+    OutOfBounds,                          // Index was never allocated by this map
+    StaleGeneration { current: u32 },     // Slot exists but has been removed (and maybe reused) since
+    Occupied { current: u32 },            // Slot is already live (from insert_at_raw)
+}
+
+impl std::fmt::Display for SlotError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SlotError::OutOfBounds => write!(f, "key index is out of bounds for this map"),
+            SlotError::StaleGeneration { current } => write!(f, "key is stale (slot is at generation {current})"),
+            SlotError::Occupied { current } => write!(f, "slot is already occupied (at generation {current})"),
+        }
+    }
+}
+
+impl std::error::Error for SlotError {}
+
+#[derive(Clone)]
+pub enum GrowthStrategy {
+    Factor(f64),                                        // Multiply capacity by this factor
+    Fixed(u32),                                         // Add this many slots at a time
+    Custom(Arc<dyn Fn(usize) -> usize + Send + Sync>),  // Maps old capacity to new capacity
+}
+
+impl GrowthStrategy {
+    fn next_capacity(&self, old: usize) -> usize {
+        let new = match self {
+            GrowthStrategy::Factor(factor) => (old as f64 * factor).ceil() as usize,
+            GrowthStrategy::Fixed(step) => old.saturating_add(*step as usize),
+            GrowthStrategy::Custom(f) => f(old),
+        };
+        new.max(old + 1) // Always make room for at least one more slot
+    }
+}
+
+impl Default for GrowthStrategy {
+    fn default() -> Self {
+        GrowthStrategy::Factor(2.0)
+    }
+}
+
+// Free-list link marking a slot as live
+const OCCUPIED: u32 = u32::MAX - 1;
+
+// Free-list link marking a removed slot whose value is kept for `get_removed`
+const TOMBSTONE: u32 = u32::MAX - 2;
+
+// Backing store for slot values. `VecStorage<T>` is the default; other backends
+// (memory-mapped files, arena pages, GPU-visible memory) can implement this.
+// Values are handed out as `&T`, so every backend must keep `T`'s alignment.
+pub trait Storage<T>: IndexMut<usize, Output = T> {
+    fn len(&self) -> usize;
+    fn push(&mut self, value: T);
+    fn get(&self, index: usize) -> Option<&T>;
+    fn get_mut(&mut self, index: usize) -> Option<&mut T>;
+    fn swap(&mut self, a: usize, b: usize);
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Slots that fit before the next reallocation; growth strategies size against this
+    fn capacity(&self) -> usize {
+        self.len()
+    }
+
+    fn reserve(&mut self, _additional: usize) {}
+
+    // `reserve` that reports allocation failure instead of aborting
+    fn try_reserve(&mut self, additional: usize) -> bool {
+        self.reserve(additional);
+        true
+    }
+
+    // Writes to every memory page backing the current capacity so first use
+    // doesn't page-fault. Backends that can't do this leave it as a no-op.
+    fn prefault(&mut self) {}
+
+    // Pointer to the value at `index` (in bounds) for handing out several
+    // `&mut T` at once: a pointer from one call must stay usable after later
+    // calls for other indices. `&mut self[index]` reborrows the whole store,
+    // which invalidates earlier pointers, so backends derive it from a raw
+    // base pointer instead. The default is only right for stores holding
+    // each value in its own allocation.
+    fn slot_ptr(&mut self, index: usize) -> *mut T {
+        &mut self[index]
+    }
+}
+
+const PAGE_BYTES: usize = 4096;
+
+// Writes one element per memory page of `vec`'s allocation, spare capacity included
+fn prefault_vec<U>(vec: &mut Vec<U>) {
+    let size = std::mem::size_of::<U>();
+    if size == 0 {
+        return;
+    }
+    let stride = (PAGE_BYTES / size).max(1);
+    for i in (0..vec.len()).step_by(stride) {
+        // Same bits written back; volatile so the access isn't optimized out
+        unsafe {
+            let slot = vec.as_mut_ptr().add(i);
+            ptr::write_volatile(slot, ptr::read_volatile(slot));
+        }
+    }
+    let spare = vec.spare_capacity_mut();
+    for i in (0..spare.len()).step_by(stride) {
+        unsafe { ptr::write_volatile(spare[i].as_mut_ptr() as *mut u8, 0) };
+    }
+}
+
+// Default storage: values packed in one contiguous `Vec`
+#[derive(Clone)]
+pub struct VecStorage<T>(Vec<T>);
+
+impl<T> VecStorage<T> {
+    pub const fn new() -> Self {
+        VecStorage(Vec::new())
+    }
+}
+
+impl<T> Default for VecStorage<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Index<usize> for VecStorage<T> {
+    type Output = T;
+
+    fn index(&self, index: usize) -> &T {
+        &self.0[index]
+    }
+}
+
+impl<T> IndexMut<usize> for VecStorage<T> {
+    fn index_mut(&mut self, index: usize) -> &mut T {
+        &mut self.0[index]
+    }
+}
+
+impl<T> AsRef<[T]> for VecStorage<T> {
+    fn as_ref(&self) -> &[T] {
+        &self.0
+    }
+}
+
+impl<T> AsMut<[T]> for VecStorage<T> {
+    fn as_mut(&mut self) -> &mut [T] {
+        &mut self.0
+    }
+}
+
+impl<T> Storage<T> for VecStorage<T> {
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    fn push(&mut self, value: T) {
+        self.0.push(value)
+    }
+
+    fn get(&self, index: usize) -> Option<&T> {
+        self.0.get(index)
+    }
+
+    fn get_mut(&mut self, index: usize) -> Option<&mut T> {
+        self.0.get_mut(index)
+    }
+
+    fn swap(&mut self, a: usize, b: usize) {
+        self.0.swap(a, b)
+    }
+
+    fn capacity(&self) -> usize {
+        self.0.capacity()
+    }
+
+    fn reserve(&mut self, additional: usize) {
+        self.0.reserve_exact(additional)
+    }
+
+    fn try_reserve(&mut self, additional: usize) -> bool {
+        self.0.try_reserve_exact(additional).is_ok()
+    }
+
+    fn prefault(&mut self) {
+        prefault_vec(&mut self.0)
+    }
+
+    fn slot_ptr(&mut self, index: usize) -> *mut T {
+        assert!(index < self.0.len(), "index out of bounds");
+        // `as_mut_ptr` doesn't go through a slice borrow
+        unsafe { self.0.as_mut_ptr().add(index) }
+    }
+}
+
+// Values split into fixed-size pages shared between clones. Cloning only bumps
+// page refcounts; a page is copied the first time either side writes to it.
+pub struct CowStorage<T, const PAGE: usize = 4096> {
+    pages: Vec<Arc<Vec<T>>>,
+    len: usize,
+}
+
+impl<T, const PAGE: usize> CowStorage<T, PAGE> {
+    pub fn new() -> Self {
+        assert!(PAGE > 0, "pages must hold at least one value");
+        CowStorage { pages: Vec::new(), len: 0 }
+    }
+}
+
+impl<T, const PAGE: usize> Default for CowStorage<T, PAGE> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, const PAGE: usize> Clone for CowStorage<T, PAGE> {
+    fn clone(&self) -> Self {
+        CowStorage { pages: self.pages.clone(), len: self.len }
+    }
+}
+
+impl<T, const PAGE: usize> Index<usize> for CowStorage<T, PAGE> {
+    type Output = T;
+
+    fn index(&self, index: usize) -> &T {
+        assert!(index < self.len, "index out of bounds");
+        &self.pages[index / PAGE][index % PAGE]
+    }
+}
+
+impl<T: Clone, const PAGE: usize> IndexMut<usize> for CowStorage<T, PAGE> {
+    fn index_mut(&mut self, index: usize) -> &mut T {
+        assert!(index < self.len, "index out of bounds");
+        &mut Arc::make_mut(&mut self.pages[index / PAGE])[index % PAGE]
+    }
+}
+
+impl<T: Clone, const PAGE: usize> Storage<T> for CowStorage<T, PAGE> {
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn push(&mut self, value: T) {
+        let page = self.len / PAGE;
+        if page == self.pages.len() {
+            self.pages.push(Arc::new(Vec::with_capacity(PAGE)));
+        }
+        Arc::make_mut(&mut self.pages[page]).push(value);
+        self.len += 1;
+    }
+
+    fn get(&self, index: usize) -> Option<&T> {
+        (index < self.len).then(|| &self[index])
+    }
+
+    fn get_mut(&mut self, index: usize) -> Option<&mut T> {
+        (index < self.len).then(|| &mut self[index])
+    }
+
+    fn swap(&mut self, a: usize, b: usize) {
+        let (page_a, page_b) = (a / PAGE, b / PAGE);
+        if page_a == page_b {
+            Arc::make_mut(&mut self.pages[page_a]).swap(a % PAGE, b % PAGE);
+        } else {
+            let (low, high) = self.pages.split_at_mut(page_a.max(page_b));
+            let (low_page, high_page) = (Arc::make_mut(&mut low[page_a.min(page_b)]), Arc::make_mut(&mut high[0]));
+            let (low_slot, high_slot) = if page_a < page_b { (a % PAGE, b % PAGE) } else { (b % PAGE, a % PAGE) };
+            std::mem::swap(&mut low_page[low_slot], &mut high_page[high_slot]);
+        }
+    }
+
+    fn capacity(&self) -> usize {
+        self.pages.len() * PAGE
+    }
+
+    fn reserve(&mut self, additional: usize) {
+        while self.capacity() < self.len + additional {
+            self.pages.push(Arc::new(Vec::with_capacity(PAGE)));
+        }
+    }
+
+    // Pages shared with a clone are skipped; writing them would force a copy
+    fn prefault(&mut self) {
+        for page in &mut self.pages {
+            if let Some(page) = Arc::get_mut(page) {
+                prefault_vec(page);
+            }
+        }
+    }
+
+    // Copies the page first if it is shared, like `index_mut`
+    fn slot_ptr(&mut self, index: usize) -> *mut T {
+        assert!(index < self.len, "index out of bounds");
+        unsafe { Arc::make_mut(&mut self.pages[index / PAGE]).as_mut_ptr().add(index % PAGE) }
+    }
+}
+
+// Keeps values of up to `N` bytes inline and boxes larger ones, so a map of
+// big values has a slot array of pointers and walking it stays cheap. Which
+// applies is fixed per `T`; the map's API is unchanged either way.
+pub struct OverflowStorage<T, const N: usize = 64> {
+    inline: Vec<T>,     // Used when `T` fits in `N` bytes
+    boxed: Vec<Box<T>>, // Used otherwise
+}
+
+impl<T, const N: usize> OverflowStorage<T, N> {
+    const BOXED: bool = std::mem::size_of::<T>() > N;
+
+    pub fn new() -> Self {
+        OverflowStorage { inline: Vec::new(), boxed: Vec::new() }
+    }
+
+    // Whether this `T` is stored out of line
+    pub fn is_boxed(&self) -> bool {
+        Self::BOXED
+    }
+}
+
+impl<T, const N: usize> Default for OverflowStorage<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Clone, const N: usize> Clone for OverflowStorage<T, N> {
+    fn clone(&self) -> Self {
+        OverflowStorage { inline: self.inline.clone(), boxed: self.boxed.clone() }
+    }
+}
+
+impl<T, const N: usize> Index<usize> for OverflowStorage<T, N> {
+    type Output = T;
+
+    fn index(&self, index: usize) -> &T {
+        if Self::BOXED {
+            &self.boxed[index]
+        } else {
+            &self.inline[index]
+        }
+    }
+}
+
+impl<T, const N: usize> IndexMut<usize> for OverflowStorage<T, N> {
+    fn index_mut(&mut self, index: usize) -> &mut T {
+        if Self::BOXED {
+            &mut self.boxed[index]
+        } else {
+            &mut self.inline[index]
+        }
+    }
+}
+
+impl<T, const N: usize> Storage<T> for OverflowStorage<T, N> {
+    fn len(&self) -> usize {
+        if Self::BOXED {
+            self.boxed.len()
+        } else {
+            self.inline.len()
+        }
+    }
+
+    fn push(&mut self, value: T) {
+        if Self::BOXED {
+            self.boxed.push(Box::new(value))
+        } else {
+            self.inline.push(value)
+        }
+    }
+
+    fn get(&self, index: usize) -> Option<&T> {
+        (index < self.len()).then(|| &self[index])
+    }
+
+    fn get_mut(&mut self, index: usize) -> Option<&mut T> {
+        (index < self.len()).then(|| &mut self[index])
+    }
+
+    // Swaps the boxes, not their contents
+    fn swap(&mut self, a: usize, b: usize) {
+        if Self::BOXED {
+            self.boxed.swap(a, b)
+        } else {
+            self.inline.swap(a, b)
+        }
+    }
+
+    fn capacity(&self) -> usize {
+        if Self::BOXED {
+            self.boxed.capacity()
+        } else {
+            self.inline.capacity()
+        }
+    }
+
+    fn reserve(&mut self, additional: usize) {
+        if Self::BOXED {
+            self.boxed.reserve_exact(additional)
+        } else {
+            self.inline.reserve_exact(additional)
+        }
+    }
+
+    fn try_reserve(&mut self, additional: usize) -> bool {
+        if Self::BOXED {
+            self.boxed.try_reserve_exact(additional).is_ok()
+        } else {
+            self.inline.try_reserve_exact(additional).is_ok()
+        }
+    }
+
+    fn prefault(&mut self) {
+        if Self::BOXED {
+            prefault_vec(&mut self.boxed)
+        } else {
+            prefault_vec(&mut self.inline)
+        }
+    }
+
+    fn slot_ptr(&mut self, index: usize) -> *mut T {
+        if Self::BOXED {
+            &mut *self.boxed[index]
+        } else {
+            assert!(index < self.inline.len(), "index out of bounds");
+            unsafe { self.inline.as_mut_ptr().add(index) }
+        }
+    }
+}
+
+// Aligns and pads a value to its own 64-byte cache line. Store `CachePadded<T>`
+// to keep slots that different threads write from sharing a line.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Hash)]
+#[repr(C, align(64))]
+pub struct CachePadded<T>(pub T);
+
+impl<T> std::ops::Deref for CachePadded<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> std::ops::DerefMut for CachePadded<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
+
+impl<T> From<T> for CachePadded<T> {
+    fn from(value: T) -> Self {
+        CachePadded(value)
+    }
+}
+
+// Order in which vacant slots are reused
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum FreeListPolicy {
+    // Most recently freed slot first: an intrusive stack, the fastest option
+    #[default]
+    Lifo,
+    // Lowest free index first, via a min-heap. Keeps live entries packed at the
+    // front for better iteration locality, at O(log n) per insert and remove.
+    LowestFirst,
+    // Least recently freed slot first, so a slot's generation advances as
+    // rarely as possible and stale keys take longest to alias a new entry
+    Fifo,
+}
+
+// What `get`, `get_mut` and `remove` do when handed a stale or unknown key.
+// The `try_*` methods always return the error instead; `Index` always panics.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum InvalidKeyPolicy {
+    #[default]
+    ReturnNone,
+    // Fail fast, e.g. in debug builds
+    Panic,
+    // Return `None` and report the key (through `tracing` when enabled, else stderr)
+    Log,
+}
+
+// Hands out map ids 1..=u16::MAX in turn; 0 marks keys of unknown origin
+#[cfg(all(debug_assertions, feature = "key-provenance"))]
+fn next_map_id() -> u16 {
+    static NEXT: AtomicU32 = AtomicU32::new(0);
+    (NEXT.fetch_add(1, Ordering::Relaxed) % u16::MAX as u32) as u16 + 1
+}
+
+// Stamps keys with the id of the map whose slots they name (provenance builds
+// only), for code that borrows the slots but not the map
+#[derive(Clone, Copy)]
+struct Issuer {
+    #[cfg(all(debug_assertions, feature = "key-provenance"))]
+    map_id: u16,
+}
+
+impl Issuer {
+    fn key(self, index: u32, generation: u32) -> Key {
+        Key {
+            index,
+            generation,
+            #[cfg(all(debug_assertions, feature = "key-provenance"))]
+            map_id: self.map_id,
+        }
+    }
+}
+
+// Log2-bucketed distribution of durations, in the units of the clock that fed it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Histogram {
+    buckets: [u64; 65], // Bucket 0 counts zeros, bucket `i` counts [2^(i-1), 2^i)
+    count: u64,
+    sum: u64,
+    max: u64,
+}
+
+impl Histogram {
+    pub const fn new() -> Self {
+        Self { buckets: [0; 65], count: 0, sum: 0, max: 0 }
+    }
+
+    pub fn record(&mut self, value: u64) {
+        self.buckets[64 - value.leading_zeros() as usize] += 1;
+        self.count += 1;
+        self.sum = self.sum.saturating_add(value);
+        self.max = self.max.max(value);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    // 0 when empty
+    pub fn mean(&self) -> f64 {
+        if self.count == 0 { 0.0 } else { self.sum as f64 / self.count as f64 }
+    }
+
+    pub fn max(&self) -> u64 {
+        self.max
+    }
+
+    // Upper bound of the bucket holding the `q` quantile (0.0 to 1.0), capped at `max`
+    pub fn quantile(&self, q: f64) -> u64 {
+        let rank = (q.clamp(0.0, 1.0) * self.count as f64).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (bucket, &count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return if bucket == 0 { 0 } else { (u64::MAX >> (64 - bucket)).min(self.max) };
+            }
+        }
+        self.max
+    }
+
+    // `(lowest value, count)` of each non-empty bucket, for charting
+    pub fn buckets(&self) -> impl Iterator<Item = (u64, u64)> + '_ {
+        self.buckets
+            .iter()
+            .enumerate()
+            .filter(|&(_, &count)| count > 0)
+            .map(|(bucket, &count)| (if bucket == 0 { 0 } else { 1 << (bucket - 1) }, count))
+    }
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Snapshot of `FastSlotMap::lifetime_stats`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LifetimeStats {
+    pub lifetimes: Histogram, // Insert to removal, per removed entry
+    pub idle: Histogram,      // Vacated to reused, per reused slot; its mean is the reuse latency
+}
+
+// Slot stamp meaning the time isn't known
+const UNSTAMPED: u64 = u64::MAX;
+
+struct LifetimeTracker {
+    clock: Box<dyn Clock + Send + Sync>,
+    stamps: Vec<u64>, // Per slot: when the entry was inserted, or when the vacant slot was vacated
+    stats: LifetimeStats,
+}
+
+impl LifetimeTracker {
+    // Sets a slot's stamp, returning the previous one if known
+    fn stamp(&mut self, index: usize, now: u64) -> Option<u64> {
+        if self.stamps.len() <= index {
+            self.stamps.resize(index + 1, UNSTAMPED);
+        }
+        let previous = std::mem::replace(&mut self.stamps[index], now);
+        (previous != UNSTAMPED).then_some(previous)
+    }
+}
+
+// Occupancy threshold registered by `on_occupancy_above`/`on_occupancy_below`
+struct Watermark {
+    threshold: f32,
+    above: bool, // Fires on rising above the threshold, else on falling below it
+    armed: bool, // Cleared when it fires, set again once occupancy is back across
+    callback: Box<dyn FnMut(f32) + Send + Sync>,
+}
+
+struct Finalizer<T, S> {
+    callback: Box<dyn FnMut(Key, T) + Send + Sync>,
+    // Runs the callback over the remaining entries when the map drops; a fn
+    // pointer made where `S: Storage<T>` holds, which `Drop` can't require
+    drain: fn(&mut FastSlotMap<T, S>),
+}
+
+// Every insert path ends in `fill` and every removal goes through `vacate`,
+// which keep the per-slot side tables below in step with the slots
+pub struct FastSlotMap<T, S = VecStorage<T>> {
+    values: S,                    // Storage for values (a packed Vec by default)
+    generations: Vec<u32>,        // Tracks slot validity
+    next_free: Vec<u32>,          // Intrusive free-list links, OCCUPIED for live slots
+    free_head: u32,               // Head of free list (u32::MAX when empty)
+    free_tail: u32,               // Last slot in the free list under `Fifo`
+    policy: FreeListPolicy,       // Which vacant slot insert reuses next
+    key_policy: InvalidKeyPolicy, // How the Option-returning API reacts to invalid keys
+    free_heap: BinaryHeap<Reverse<u32>>, // Vacant slots under `LowestFirst` (unlinked, `free_head` unused)
+    len: u32,                     // Number of active elements
+    version: u64,                 // Bumped on every structural change (insert/remove)
+    growth: GrowthStrategy,       // How slot arrays grow when full
+    on_grow: Option<Box<dyn FnMut(usize, usize) + Send + Sync>>, // Called with (old, new) capacity
+    finalizer: Option<Finalizer<T, S>>, // Called with each entry as it leaves the map
+    watermarks: Vec<Watermark>,   // Occupancy callbacks checked on insert and remove
+    pins: BTreeMap<u32, PinCount>, // Pin counts of slots that have been pinned
+    shuffle_seed: Option<u64>,    // Seed of the iteration order while shuffling is on
+    provisional: BTreeMap<u32, u32>, // Slot -> generation of entries awaiting commit or abort
+    dirty: Option<Vec<u64>>,      // Bitset of slots touched since the last take_dirty_ranges
+    alive: Option<Vec<u64>>,      // Bitset of occupied slots, checked before generations
+    write_versions: Option<Vec<u64>>, // Per-slot stamp of the last write, from `write_clock`
+    write_clock: u64,             // Advanced on every touch while write versioning is on
+    access_counts: Option<Vec<AtomicU32>>, // Per-slot get/get_mut hits while profiling is on
+    lifetimes: Option<Box<LifetimeTracker>>, // Entry lifetime and slot idle time telemetry
+    compaction: Option<Compaction>, // In-progress incremental compaction pass
+    sort_scratch: Vec<u32>,       // Slot order reused by iter_sorted_by_key
+    stale: Range<u32>,            // Slots voided by invalidate_all and not yet reclaimed
+    tombstones: Option<Vec<u32>>, // Removed slots kept off the free list until purge_tombstones
+    #[cfg(feature = "reuse-report")]
+    reuse: ReuseCounters,         // Totals behind reuse_report
+    #[cfg(all(debug_assertions, feature = "key-provenance"))]
+    map_id: AtomicU16,            // Stamped into issued keys, assigned on first use (0 until then)
+    _values: PhantomData<T>,
+}
+
+impl<T: Default + Copy> FastSlotMap<T> {
+    // Allocates nothing, so it can initialise a `static`, e.g.
+    // `static MAP: Mutex<FastSlotMap<u32>> = Mutex::new(FastSlotMap::new());`
+    pub const fn new() -> Self {
+        Self::empty(VecStorage::new())
+    }
+
+    pub fn with_growth(growth: GrowthStrategy) -> Self {
+        let mut map = Self::new();
+        map.growth = growth;
+        map
+    }
+
+    pub fn with_free_list_policy(policy: FreeListPolicy) -> Self {
+        let mut map = Self::with_storage(VecStorage::new());
+        map.policy = policy;
+        map
+    }
+
+    // Builds a map holding each value at an exact slot and generation; unlisted slots are vacant
+    #[cfg(any(feature = "slab", feature = "slotmap"))]
+    fn from_slots(slots: impl IntoIterator<Item = (u32, u32, T)>, vacant_generation: u32) -> Self {
+        let mut map = Self::new();
+        for (index, generation, value) in slots {
+            let index = index as usize;
+            if index >= map.values.len() {
+                map.values.0.resize(index + 1, T::default());
+                map.generations.resize(index + 1, vacant_generation);
+                map.next_free.resize(index + 1, u32::MAX);
+            }
+            map.values[index] = value;
+            map.generations[index] = generation;
+            map.next_free[index] = OCCUPIED;
+            map.len += 1;
+        }
+        map.rebuild_free_list();
+        map
+    }
+
+    // Rebuilds a map written by `save`; keys issued by the saved map resolve
+    // again. Returns `None` for truncated input or if `decode` fails.
+    pub fn load(bytes: &[u8], mut decode: impl FnMut(&[u8]) -> Option<T>) -> Option<Self> {
+        Self::load_with(bytes, |values, slots| decode_slots(values, slots, &mut decode))
+    }
+
+    // `load` for large snapshots: the slot arrays are sized exactly up front,
+    // pages of values are decoded in parallel on the rayon pool, and the free
+    // list is rebuilt once at the end
+    #[cfg(feature = "rayon")]
+    pub fn par_load(bytes: &[u8], decode: impl Fn(&[u8]) -> Option<T> + Sync) -> Option<Self>
+    where
+        T: Send,
+    {
+        use rayon::prelude::*;
+        let page = (PAGE_BYTES / std::mem::size_of::<T>().max(1)).max(1);
+        Self::load_with(bytes, |values, slots| {
+            values
+                .par_chunks_mut(page)
+                .zip(slots.par_chunks(page))
+                .all(|(values, slots)| decode_slots(values, slots, &decode))
+        })
+    }
+
+    // Parses the slot table of a `save` blob, lays out the slots and lets
+    // `fill` decode the values into them
+    fn load_with(bytes: &[u8], fill: impl FnOnce(&mut [T], &[SavedSlot]) -> bool) -> Option<Self> {
+        let mut bytes = bytes;
+        let count = u32::from_le_bytes(take_bytes(&mut bytes, 4)?.try_into().ok()?) as usize;
+        if count >= TOMBSTONE as usize {
+            return None;
+        }
+        let table = take_bytes(&mut bytes, count.checked_mul(8)?)?;
+        let mut slots = Vec::with_capacity(count);
+        for entry in table.chunks_exact(8) {
+            let generation = u32::from_le_bytes(entry[..4].try_into().ok()?);
+            let value = match u32::from_le_bytes(entry[4..].try_into().ok()?) {
+                u32::MAX => None,
+                len => Some(take_bytes(&mut bytes, len as usize)?),
+            };
+            slots.push((generation, value));
+        }
+        if !bytes.is_empty() {
+            return None;
+        }
+        Self::from_saved(&slots, fill)
+    }
+
+    // Lays out one slot per table entry and lets `fill` decode the values into them
+    fn from_saved(slots: &[SavedSlot], fill: impl FnOnce(&mut [T], &[SavedSlot]) -> bool) -> Option<Self> {
+        let count = slots.len();
+        let mut map = Self::new();
+        map.values.0.try_reserve_exact(count).ok()?;
+        map.values.0.resize(count, T::default());
+        if !fill(&mut map.values.0, slots) {
+            return None;
+        }
+        map.generations = slots.iter().map(|&(generation, _)| generation).collect();
+        map.next_free = slots.iter().map(|(_, value)| if value.is_some() { OCCUPIED } else { u32::MAX }).collect();
+        map.len = slots.iter().filter(|(_, value)| value.is_some()).count() as u32;
+        map.rebuild_free_list();
+        Some(map)
+    }
+}
+
+// Generation and encoded value (`None` if vacant) of one slot in a `save` blob
+type SavedSlot<'a> = (u32, Option<&'a [u8]>);
+
+fn decode_slots<T>(values: &mut [T], slots: &[SavedSlot], mut decode: impl FnMut(&[u8]) -> Option<T>) -> bool {
+    values.iter_mut().zip(slots).all(|(value, &(_, bytes))| match bytes {
+        Some(bytes) => decode(bytes).map(|decoded| *value = decoded).is_some(),
+        None => true,
+    })
+}
+
+impl<T: Default + Copy, S: Storage<T>> FastSlotMap<T, S> {
+    // `storage` must be empty; slots are allocated in it as the map grows
+    pub fn with_storage(storage: S) -> Self {
+        assert_eq!(storage.len(), 0, "storage must start out empty");
+        Self::empty(storage)
+    }
+
+    const fn empty(storage: S) -> Self {
+        Self {
+            values: storage,
+            generations: Vec::new(),
+            next_free: Vec::new(),
+            free_head: u32::MAX,
+            free_tail: u32::MAX,
+            policy: FreeListPolicy::Lifo,
+            key_policy: InvalidKeyPolicy::ReturnNone,
+            free_heap: BinaryHeap::new(),
+            len: 0,
+            version: 0,
+            growth: GrowthStrategy::Factor(2.0), // GrowthStrategy::default(), which isn't const
+            on_grow: None,
+            watermarks: Vec::new(),
+            finalizer: None,
+            pins: BTreeMap::new(),
+            shuffle_seed: None,
+            provisional: BTreeMap::new(),
+            dirty: None,
+            alive: None,
+            write_versions: None,
+            write_clock: 0,
+            access_counts: None,
+            lifetimes: None,
+            compaction: None,
+            sort_scratch: Vec::new(),
+            stale: 0..0,
+            tombstones: None,
+            #[cfg(feature = "reuse-report")]
+            reuse: ReuseCounters::new(),
+            #[cfg(all(debug_assertions, feature = "key-provenance"))]
+            map_id: AtomicU16::new(0),
+            _values: PhantomData,
+        }
+    }
+
+    pub fn set_growth_strategy(&mut self, growth: GrowthStrategy) {
+        self.growth = growth;
+    }
+
+    pub fn set_grow_callback(&mut self, callback: impl FnMut(usize, usize) + Send + Sync + 'static) {
+        self.on_grow = Some(Box::new(callback));
+    }
+
+    // Calls `callback` with the occupancy (live entries / capacity) when an
+    // insert takes it above `threshold`, e.g. 0.8 to start a new shard early.
+    // It fires once per crossing: occupancy has to drop back to the threshold
+    // before it can fire again. Clones don't inherit watermarks.
+    pub fn on_occupancy_above(&mut self, threshold: f32, callback: impl FnMut(f32) + Send + Sync + 'static) {
+        self.watermarks.push(Watermark { threshold, above: true, armed: true, callback: Box::new(callback) });
+    }
+
+    // `on_occupancy_above` for a removal taking occupancy below `threshold`
+    pub fn on_occupancy_below(&mut self, threshold: f32, callback: impl FnMut(f32) + Send + Sync + 'static) {
+        self.watermarks.push(Watermark { threshold, above: false, armed: true, callback: Box::new(callback) });
+    }
+
+    pub fn clear_watermarks(&mut self) {
+        self.watermarks.clear();
+    }
+
+    // `rising` after an insert; each watermark only fires in its own direction
+    fn check_watermarks(&mut self, rising: bool) {
+        if self.watermarks.is_empty() {
+            return;
+        }
+        let capacity = self.values.capacity();
+        let occupancy = if capacity == 0 { 0.0 } else { self.len as f32 / capacity as f32 };
+        for mark in &mut self.watermarks {
+            let crossed = if mark.above { occupancy > mark.threshold } else { occupancy < mark.threshold };
+            if crossed && mark.armed && mark.above == rising {
+                (mark.callback)(occupancy);
+            }
+            mark.armed = !crossed || (mark.armed && mark.above != rising);
+        }
+    }
+
+    // Called with every entry that leaves the map: on `remove` (before the
+    // value is returned), `clear`, `invalidate_all` and when the map drops.
+    // For releasing resources the values refer to but can't free themselves.
+    // Clones don't inherit it, and entries moved out by `absorb` skip it.
+    pub fn set_finalizer(&mut self, callback: impl FnMut(Key, T) + Send + Sync + 'static) {
+        self.finalizer = Some(Finalizer { callback: Box::new(callback), drain: Self::finalize_all });
+    }
+
+    fn finalize(&mut self, index: usize) {
+        let key = self.issue(Key::new(index as u32, self.generations[index]));
+        if let Some(finalizer) = &mut self.finalizer {
+            (finalizer.callback)(key, self.values[index]);
+        }
+    }
+
+    fn finalize_all(&mut self) {
+        if self.finalizer.is_some() {
+            for index in 0..self.next_free.len() {
+                if self.is_occupied(index) {
+                    self.finalize(index);
+                }
+            }
+        }
+    }
+
+    // Removes every entry except pinned ones
+    pub fn clear(&mut self) {
+        for index in 0..self.next_free.len() {
+            if self.is_occupied(index) {
+                self.vacate(Key::new(index as u32, self.generations[index]));
+            }
+        }
+    }
+
+    // `clear` for async code, yielding to the executor after every `batch` slots
+    pub async fn clear_async(&mut self, batch: u32) {
+        let batch = batch.max(1) as usize;
+        let mut start = 0;
+        while start < self.next_free.len() {
+            for index in start..(start + batch).min(self.next_free.len()) {
+                if self.is_occupied(index) {
+                    self.vacate(Key::new(index as u32, self.generations[index]));
+                }
+            }
+            start += batch;
+            YieldNow(false).await;
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.values.capacity()
+    }
+
+    pub fn insert(&mut self, value: T) -> Key {
+        let index = match self.take_stale().or_else(|| self.pop_free()) {
+            Some(index) => {
+                self.values[index as usize] = value;
+                index
+            }
+            None => {
+                // Allocate a new slot
+                if self.values.len() == self.values.capacity() {
+                    self.grow(self.values.len() + 1);
+                }
+                self.values.push(value);
+                self.generations.push(0);
+                self.next_free.push(OCCUPIED);
+                self.values.len() as u32 - 1
+            }
+        };
+        self.fill(index as usize, index as usize + 1);
+        self.issue(Key::new(index, self.generations[index as usize]))
+    }
+
+    // Bookkeeping shared by every insert path once values are in slots
+    // `start..end`: links, length, version, tracking, telemetry and
+    // watermarks. Per-slot features hook in here and in `vacate`.
+    fn fill(&mut self, start: usize, end: usize) {
+        self.next_free[start..end].fill(OCCUPIED);
+        self.len += (end - start) as u32;
+        self.version = self.version.wrapping_add(1);
+        self.mark_alive(start, end, true);
+        self.touch(start, end);
+        for index in start..end {
+            self.note_filled(index);
+        }
+        self.check_watermarks(true);
+    }
+
+    // `insert` that returns `None` instead of aborting when the slot arrays
+    // are full and growing them fails
+    pub fn try_insert(&mut self, value: T) -> Option<Key> {
+        if !self.has_vacant() && !self.try_reserve(1) {
+            return None;
+        }
+        Some(self.insert(value))
+    }
+
+    // Whether the next insert reuses a slot rather than allocating one
+    fn has_vacant(&self) -> bool {
+        !self.stale.is_empty() || self.free_head != u32::MAX || !self.free_heap.is_empty()
+    }
+
+    // Places a value at an exact slot and generation, so keys recorded before a
+    // snapshot or replay resolve again. Slots up to `index` are created vacant
+    // if needed; the target slot must be vacant.
+    pub fn insert_at_raw(&mut self, index: u32, generation: u32, value: T) -> Result<Key, SlotError> {
+        if index >= TOMBSTONE {
+            return Err(SlotError::OutOfBounds);
+        }
+        self.reclaim_stale();
+        self.extend_slots(index as usize + 1);
+        if self.is_occupied(index as usize) {
+            return Err(SlotError::Occupied { current: self.generations[index as usize] });
+        }
+
+        // Unlink the slot from the free list (or from compaction's held-back slots or the tombstones)
+        let held = self.compaction.as_mut().and_then(|pass| {
+            let position = pass.held.iter().position(|&held| held == index)?;
+            Some(pass.held.swap_remove(position))
+        });
+        if self.next_free[index as usize] == TOMBSTONE {
+            let tombstones = self.tombstones.as_mut().expect("tombstoned slot without tombstone mode");
+            tombstones.retain(|&tombstone| tombstone != index);
+        } else if held.is_none() {
+            self.unlink_free(index);
+        }
+
+        self.generations[index as usize] = generation;
+        self.values[index as usize] = value;
+        self.fill(index as usize, index as usize + 1);
+        Ok(self.issue(Key::new(index, generation)))
+    }
+
+    // Creates vacant slots until there are at least `slots`. They count as
+    // written, so deltas carry every slot created after their `since`.
+    fn extend_slots(&mut self, slots: usize) {
+        if slots > self.values.capacity() {
+            self.grow(slots);
+        }
+        let start = self.values.len();
+        while self.values.len() < slots {
+            self.values.push(T::default());
+            self.generations.push(0);
+            self.next_free.push(u32::MAX);
+            self.push_free(self.values.len() as u32 - 1);
+        }
+        self.touch(start, self.values.len());
+    }
+
+    // Moves live entries from the top of the slot array into vacant slots below
+    // them, at most `budget` entries per call. The pass resumes where the last
+    // call stopped and the map stays fully usable in between; apply each step's
+    // remap to stored keys before the next step. Pinned entries never move.
+    //
+    // While a pass is running, vacant slots above the compacted region are held
+    // back from reuse (new entries go to lower free slots or the end instead).
+    pub fn compact_step(&mut self, budget: u32) -> CompactProgress {
+        self.reclaim_stale();
+        let mut pass = self.compaction.take().unwrap_or_else(|| Compaction { cursor: self.values.len() as u32, held: Vec::new() });
+        let mut moves: HashMap<u32, (u32, Key)> = HashMap::new();
+        let mut origins: HashMap<u32, u32> = HashMap::new(); // Slot filled this step -> slot its entry came from
+        let mut finished = false;
+
+        while (moves.len() as u32) < budget {
+            // Highest movable entry below the cursor
+            let source = (0..pass.cursor).rev().find(|&i| self.is_occupied(i as usize) && !self.is_pinned_index(i));
+            let Some(source) = source else {
+                finished = true;
+                break;
+            };
+            pass.cursor = source;
+
+            // Any free slot below it; higher free slots are held back
+            let target = loop {
+                match self.pop_free() {
+                    None => break None,
+                    Some(free) if free < source => break Some(free),
+                    Some(free) => {
+                        self.next_free[free as usize] = u32::MAX;
+                        pass.held.push(free);
+                    }
+                }
+            };
+            let Some(target) = target else {
+                finished = true;
+                break;
+            };
+
+            let old_generation = self.generations[source as usize];
+            let new_key = self.issue(Key::new(target, self.generations[target as usize]));
+            self.values[target as usize] = self.values[source as usize];
+            self.next_free[target as usize] = OCCUPIED;
+            self.bump_generation(source as usize);
+            self.note_stale_keys(1);
+            self.next_free[source as usize] = u32::MAX;
+            self.mark_alive(target as usize, target as usize + 1, true);
+            self.mark_alive(source as usize, source as usize + 1, false);
+            pass.held.push(source);
+            self.pins.remove(&source);
+            if let Some(tracker) = &mut self.lifetimes {
+                // The entry keeps its insert time; the source slot starts idling now
+                let now = tracker.clock.now();
+                let inserted = tracker.stamp(source as usize, now).unwrap_or(UNSTAMPED);
+                tracker.stamp(target as usize, inserted);
+            }
+            if self.provisional.remove(&source).is_some() {
+                self.provisional.insert(target, new_key.generation);
+            }
+            self.touch(target as usize, target as usize + 1);
+            self.touch(source as usize, source as usize + 1);
+
+            // An entry may move twice in one step; keep the mapping from its original slot
+            let origin = origins.remove(&source).unwrap_or(source);
+            let generation = if origin == source { old_generation } else { moves[&origin].0 };
+            moves.insert(origin, (generation, new_key));
+            origins.insert(target, origin);
+        }
+
+        if !moves.is_empty() {
+            self.version = self.version.wrapping_add(1);
+        }
+        #[cfg(feature = "tracing")]
+        tracing::debug!(moved = moves.len(), cursor = pass.cursor, finished, "slot map compaction step");
+        self.compaction = Some(pass);
+        if finished {
+            self.finish_compaction();
+        }
+        CompactProgress { remap: KeyRemap { mapping: Mapping::Moves(moves) }, finished }
+    }
+
+    // Runs a complete compaction pass (restarting any pass in progress)
+    pub fn compact(&mut self) -> KeyRemap {
+        self.finish_compaction();
+        self.compact_step(u32::MAX).remap
+    }
+
+    // `compact` for async code: moves at most `batch` entries, then yields to
+    // the executor before the next step. The map is borrowed until it finishes.
+    pub async fn compact_async(&mut self, batch: u32) -> KeyRemap {
+        self.finish_compaction();
+        let mut remap = KeyRemap { mapping: Mapping::Moves(HashMap::new()) };
+        loop {
+            let progress = self.compact_step(batch.max(1));
+            remap.chain(progress.remap);
+            if progress.finished {
+                return remap;
+            }
+            YieldNow(false).await;
+        }
+    }
+
+    pub fn is_compacting(&self) -> bool {
+        self.compaction.is_some()
+    }
+
+    // Ends the current pass, returning held-back slots to the free list lowest-first
+    fn finish_compaction(&mut self) {
+        let Some(mut pass) = self.compaction.take() else { return };
+        pass.held.sort_unstable_by(|a, b| b.cmp(a));
+        for index in pass.held {
+            self.push_free(index);
+        }
+    }
+
+    fn is_pinned_index(&self, index: u32) -> bool {
+        self.is_pinned(Key::new(index, self.generations[index as usize]))
+    }
+
+    // Allocates `values.len()` adjacent fresh slots, so the values stay contiguous in memory
+    pub fn insert_contiguous(&mut self, values: &[T]) -> KeySpan {
+        let needed = self.values.len() + values.len();
+        if needed > self.values.capacity() {
+            self.grow(needed);
+        }
+        let index = self.values.len() as u32;
+        for &value in values {
+            self.values.push(value);
+        }
+        self.generations.resize(needed, 0);
+        self.next_free.resize(needed, OCCUPIED);
+        self.fill(index as usize, needed);
+        KeySpan { base: self.issue(Key::new(index, 0)), len: values.len() as u32 }
+    }
+
+    pub fn contains_span(&self, span: KeySpan) -> bool {
+        let start = span.base.index as usize;
+        let end = span.base.index + span.len;
+        if span.base.index < self.stale.end && self.stale.start < end {
+            return false;
+        }
+        self.generations
+            .get(start..start + span.len as usize)
+            .is_some_and(|generations| generations.iter().all(|&g| g == span.base.generation))
+    }
+
+    // Removes every slot of the span, or nothing if any of them is stale
+    pub fn remove_span(&mut self, span: KeySpan) -> bool {
+        if !self.contains_span(span) || span.keys().any(|key| self.is_pinned(key)) {
+            return false;
+        }
+        for key in span.keys() {
+            self.remove(key);
+        }
+        true
+    }
+
+    pub fn get(&self, key: Key) -> Option<&T> {
+        self.lookup(key).or_else(|| self.reject(key, "get"))
+    }
+
+    // `get` without the invalid-key policy, for callers expecting misses
+    fn lookup(&self, key: Key) -> Option<&T> {
+        self.check_provenance(key);
+        let value = self.values.get(key.index as usize).filter(|_| self.resolves(key));
+        match value {
+            Some(_) => self.note_access(key.index as usize),
+            None => self.note_failed_lookup(),
+        }
+        value
+    }
+
+    // Whether the key names a live entry, without counting the lookup; for
+    // checks the map makes on its own behalf
+    fn resolves(&self, key: Key) -> bool {
+        self.generations.get(key.index as usize) == Some(&key.generation) && !self.stale.contains(&key.index)
+    }
+
+    pub fn get_mut(&mut self, key: Key) -> Option<&mut T> {
+        match self.check(key) {
+            Ok(()) => Some(self.occupied_mut(key)),
+            Err(_) => self.reject(key, "get_mut"),
+        }
+    }
+
+    pub fn set_invalid_key_policy(&mut self, policy: InvalidKeyPolicy) {
+        self.key_policy = policy;
+    }
+
+    pub fn invalid_key_policy(&self) -> InvalidKeyPolicy {
+        self.key_policy
+    }
+
+    // Applies the invalid-key policy to a failed `op`
+    fn reject<R>(&self, key: Key, op: &'static str) -> Option<R> {
+        match self.key_policy {
+            InvalidKeyPolicy::ReturnNone => {}
+            InvalidKeyPolicy::Panic => panic!("{op} with invalid slot map key {key:?}"),
+            InvalidKeyPolicy::Log => {
+                #[cfg(feature = "tracing")]
+                tracing::warn!(?key, op, "invalid slot map key");
+                #[cfg(not(feature = "tracing"))]
+                eprintln!("{op} with invalid slot map key {key:?}");
+            }
+        }
+        None
+    }
+
+    pub fn try_get(&self, key: Key) -> Result<&T, SlotError> {
+        self.check(key)?;
+        self.note_access(key.index as usize);
+        Ok(&self.values[key.index as usize])
+    }
+
+    pub fn try_get_mut(&mut self, key: Key) -> Result<&mut T, SlotError> {
+        self.check(key)?;
+        Ok(self.occupied_mut(key))
+    }
+
+    // Mutable access to a slot `check` has accepted
+    fn occupied_mut(&mut self, key: Key) -> &mut T {
+        self.note_access(key.index as usize);
+        self.touch(key.index as usize, key.index as usize + 1);
+        &mut self.values[key.index as usize]
+    }
+
+    // Grows the slot arrays to hold at least `needed` slots
+    fn grow(&mut self, needed: usize) {
+        assert!(self.try_grow(needed), "slot map allocation failed");
+    }
+
+    fn try_grow(&mut self, needed: usize) -> bool {
+        if injected_growth_failure() {
+            return false;
+        }
+        let old = self.values.capacity();
+        let additional = self.growth.next_capacity(old).max(needed) - self.values.len();
+        if !self.values.try_reserve(additional)
+            || self.generations.try_reserve_exact(additional).is_err()
+            || self.next_free.try_reserve_exact(additional).is_err()
+        {
+            return false;
+        }
+        #[cfg(feature = "tracing")]
+        tracing::debug!(old_capacity = old, new_capacity = self.values.capacity(), len = self.len, "slot map grew");
+        if let Some(callback) = &mut self.on_grow {
+            if self.values.capacity() != old {
+                callback(old, self.values.capacity());
+            }
+        }
+        true
+    }
+
+    // Makes room for at least `additional` slots beyond those already created
+    pub fn reserve(&mut self, additional: usize) {
+        let needed = self.values.len() + additional;
+        if needed > self.values.capacity() {
+            self.grow(needed);
+        }
+    }
+
+    // `reserve` that returns false instead of aborting when allocation fails
+    pub fn try_reserve(&mut self, additional: usize) -> bool {
+        let needed = self.values.len() + additional;
+        needed <= self.values.capacity() || self.try_grow(needed)
+    }
+
+    // Writes every memory page of the reserved capacity (values, generations,
+    // free list and any tracking arrays) so live traffic takes no page faults.
+    // Call after `reserve`, typically at startup.
+    pub fn touch_all_pages(&mut self) {
+        let slots = self.values.capacity().max(self.generations.capacity());
+        self.generations.reserve_exact(slots - self.generations.len());
+        self.next_free.reserve_exact(slots - self.next_free.len());
+        self.values.prefault();
+        prefault_vec(&mut self.generations);
+        prefault_vec(&mut self.next_free);
+        if let Some(bits) = &mut self.dirty {
+            bits.reserve_exact(slots.div_ceil(64).saturating_sub(bits.len()));
+            prefault_vec(bits);
+        }
+        if let Some(stamps) = &mut self.write_versions {
+            stamps.reserve_exact(slots.saturating_sub(stamps.len()));
+            prefault_vec(stamps);
+        }
+    }
+
+    fn check(&self, key: Key) -> Result<(), SlotError> {
+        self.check_provenance(key);
+        let result = match self.generations.get(key.index as usize) {
+            None => Err(SlotError::OutOfBounds),
+            Some(&current) if current != key.generation => Err(SlotError::StaleGeneration { current }),
+            // Reclaiming a voided slot advances its generation
+            Some(&current) if self.stale.contains(&key.index) => {
+                Err(SlotError::StaleGeneration { current: current.wrapping_add(1) })
+            }
+            Some(_) => Ok(()),
+        };
+        if result.is_err() {
+            self.note_failed_lookup();
+        }
+        result
+    }
+
+    // Pinned entries are not removed; this returns `None` for them as well
+    pub fn remove(&mut self, key: Key) -> Option<T> {
+        if self.vacate(key) {
+            return Some(self.values[key.index as usize]);
+        }
+        if self.is_pinned(key) {
+            return None;
+        }
+        self.reject(key, "remove")
+    }
+
+    // Frees the key's slot without reading its value; false for stale,
+    // out-of-bounds and pinned keys
+    fn vacate(&mut self, key: Key) -> bool {
+        self.check_provenance(key);
+        if self.generations.get(key.index as usize) != Some(&key.generation) || self.stale.contains(&key.index) {
+            self.note_failed_lookup();
+            return false;
+        }
+        if self.is_pinned(key) {
+            return false;
+        }
+        if !self.pins.is_empty() {
+            self.pins.remove(&key.index);
+        }
+        if !self.provisional.is_empty() {
+            self.provisional.remove(&key.index);
+        }
+        self.finalize(key.index as usize);
+        self.note_vacated(key.index as usize);
+        self.bump_generation(key.index as usize);
+        self.note_stale_keys(1);
+        self.len -= 1;
+        self.version = self.version.wrapping_add(1);
+
+        if let Some(tombstones) = &mut self.tombstones {
+            self.next_free[key.index as usize] = TOMBSTONE;
+            tombstones.push(key.index);
+            self.mark_alive(key.index as usize, key.index as usize + 1, false);
+        } else {
+            self.push_free(key.index);
+        }
+        self.touch(key.index as usize, key.index as usize + 1);
+        self.check_watermarks(false);
+        true
+    }
+
+    // While enabled, removed entries stay readable through `get_removed` and
+    // their slots aren't reused until `purge_tombstones`. Disabling purges.
+    pub fn set_tombstones(&mut self, enabled: bool) {
+        self.purge_tombstones();
+        self.tombstones = enabled.then(Vec::new);
+    }
+
+    // Last value of an entry removed while tombstones were on, until purged
+    pub fn get_removed(&self, key: Key) -> Option<&T> {
+        let index = key.index as usize;
+        (self.next_free.get(index) == Some(&TOMBSTONE) && self.generations[index] == key.generation.wrapping_add(1))
+            .then(|| &self.values[index])
+    }
+
+    // Makes every tombstoned slot reusable; returns how many there were
+    pub fn purge_tombstones(&mut self) -> u32 {
+        let Some(tombstones) = self.tombstones.as_mut().map(std::mem::take) else { return 0 };
+        for &index in &tombstones {
+            self.push_free(index);
+        }
+        tombstones.len() as u32
+    }
+
+    pub fn contains(&self, key: Key) -> bool {
+        if let Some(bits) = &self.alive {
+            let index = key.index as usize;
+            if bits.get(index / 64).is_none_or(|word| word & (1 << (index % 64)) == 0) {
+                return false;
+            }
+        }
+        self.lookup(key).is_some()
+    }
+
+    // Order-independent hash of every live `(key, value)` pair, for comparing
+    // maps across machines. Stable across runs and platforms as long as `T`'s
+    // `Hash` impl is. O(len); `HashedSlotMap` keeps the same value up to date.
+    pub fn content_hash(&self) -> u64
+    where
+        T: Hash,
+    {
+        self.iter().fold(0u64, |sum, (key, value)| sum.wrapping_add(entry_hash(key, value)))
+    }
+
+    // Live entries in slot order (or shuffled, see `set_iteration_shuffle`);
+    // also double-ended and exact-size
+    pub fn iter(&self) -> Iter<'_, T, S> {
+        let shuffle = self.shuffle_seed.map(|seed| Shuffle::new(seed, self.next_free.len()));
+        Iter { shuffle, ..self.slot_iter() }
+    }
+
+    // Testing aid: with a seed, `iter`, `iter_mut` and `for` loops visit live
+    // entries in an order scrambled by it, to expose code that depends on
+    // slot order. Each seed gives one fixed order for a given slot count, so
+    // failures reproduce. Methods documented as slot-ordered are unaffected.
+    pub fn set_iteration_shuffle(&mut self, seed: Option<u64>) {
+        self.shuffle_seed = seed;
+    }
+
+    // `iter`, always in slot order
+    fn slot_iter(&self) -> Iter<'_, T, S> {
+        Iter {
+            shuffle: None,
+            values: &self.values,
+            generations: &self.generations,
+            next_free: &self.next_free,
+            stale: self.stale.start as usize..self.stale.end as usize,
+            front: 0,
+            back: self.next_free.len(),
+            remaining: self.len,
+            issuer: self.issuer(),
+            _values: PhantomData,
+        }
+    }
+
+    // Live entries ordered by `f` (ties in slot order). The order is built in a
+    // buffer kept by the map, so repeated calls don't allocate once it has grown.
+    pub fn iter_sorted_by_key<K: Ord>(&mut self, mut f: impl FnMut(&T) -> K) -> impl Iterator<Item = (Key, &T)> + '_ {
+        let mut order = std::mem::take(&mut self.sort_scratch);
+        order.clear();
+        order.extend((0..self.next_free.len() as u32).filter(|&index| self.is_occupied(index as usize)));
+        order.sort_unstable_by_key(|&index| (f(&self.values[index as usize]), index));
+        self.sort_scratch = order;
+        self.sort_scratch
+            .iter()
+            .map(|&index| (self.issue(Key::new(index, self.generations[index as usize])), &self.values[index as usize]))
+    }
+
+    // Replaces the contents of `out` with the live values in slot order,
+    // reserving exactly `len()` up front so a reused buffer never reallocates
+    pub fn collect_values_into(&self, out: &mut Vec<T>) {
+        out.clear();
+        out.reserve_exact(self.len as usize);
+        for index in 0..self.next_free.len() {
+            if self.is_occupied(index) {
+                out.push(self.values[index]);
+            }
+        }
+    }
+
+    // `collect_values_into` for the keys of the live entries
+    pub fn collect_keys_into(&self, out: &mut Vec<Key>) {
+        out.clear();
+        out.reserve_exact(self.len as usize);
+        for index in 0..self.next_free.len() {
+            if self.is_occupied(index) {
+                out.push(self.issue(Key::new(index as u32, self.generations[index])));
+            }
+        }
+    }
+
+    // Key of the first live entry (in slot order) matching `f`
+    pub fn find(&self, mut f: impl FnMut(&T) -> bool) -> Option<Key> {
+        self.slot_iter().find(|(_, value)| f(value)).map(|(key, _)| key)
+    }
+
+    // First `Some` produced by `f` over the live entries, in slot order
+    pub fn find_map<R>(&self, mut f: impl FnMut(Key, &T) -> Option<R>) -> Option<R> {
+        self.slot_iter().find_map(|(key, value)| f(key, value))
+    }
+
+    pub fn any(&self, mut f: impl FnMut(&T) -> bool) -> bool {
+        self.iter().any(|(_, value)| f(value))
+    }
+
+    pub fn all(&self, mut f: impl FnMut(&T) -> bool) -> bool {
+        self.iter().all(|(_, value)| f(value))
+    }
+
+    // Entries whose keys resolve in both maps (e.g. a map and one derived from
+    // it with `map`), walking both slot arrays once in slot order
+    pub fn iter_zip<'a, U: Default + Copy, R: Storage<U>>(
+        &'a self,
+        other: &'a FastSlotMap<U, R>,
+    ) -> impl Iterator<Item = (Key, &'a T, &'a U)> + 'a {
+        zip_live(self.slot_iter(), other.slot_iter())
+    }
+
+    // Occupied slots as a bitset (bit `i % 64` of word `i / 64`), for combining
+    // the key spaces of maps that share one (clones, derived maps) word by word.
+    // Generations are not included; use the `*_keys` methods for exact keys.
+    pub fn live_keys_bitset(&self) -> Vec<u64> {
+        let mut bits = vec![0u64; self.next_free.len().div_ceil(64)];
+        for index in (0..self.next_free.len()).filter(|&index| self.is_occupied(index)) {
+            bits[index / 64] |= 1 << (index % 64);
+        }
+        bits
+    }
+
+    // Keys live in both maps, in slot order
+    pub fn intersect_keys<'a, U: Default + Copy, R: Storage<U>>(&'a self, other: &'a FastSlotMap<U, R>) -> impl Iterator<Item = Key> + 'a {
+        self.iter_zip(other).map(|(key, _, _)| key)
+    }
+
+    // Keys live in either map, each once, in slot order
+    pub fn union_keys<'a, U: Default + Copy, R: Storage<U>>(&'a self, other: &'a FastSlotMap<U, R>) -> impl Iterator<Item = Key> + 'a {
+        let (mut a, mut b) = (self.slot_iter().map(|(key, _)| key).peekable(), other.slot_iter().map(|(key, _)| key).peekable());
+        std::iter::from_fn(move || match (a.peek(), b.peek()) {
+            (Some(x), Some(y)) if x.index > y.index => b.next(),
+            (Some(x), Some(y)) if x == y => {
+                b.next();
+                a.next()
+            }
+            // Same slot with different generations: both keys appear
+            (Some(_), _) => a.next(),
+            (None, _) => b.next(),
+        })
+    }
+
+    // Keys live in this map but not in `other`, in slot order
+    pub fn difference_keys<'a, U: Default + Copy, R: Storage<U>>(&'a self, other: &'a FastSlotMap<U, R>) -> impl Iterator<Item = Key> + 'a {
+        self.slot_iter().map(|(key, _)| key).filter(move |&key| !other.contains(key))
+    }
+
+    pub fn iter_zip_mut<'a, U: Default + Copy, R: Storage<U>>(
+        &'a mut self,
+        other: &'a mut FastSlotMap<U, R>,
+    ) -> impl Iterator<Item = (Key, &'a mut T, &'a mut U)> + 'a {
+        zip_live(self.slot_iter_mut(), other.slot_iter_mut())
+    }
+
+    // Calls `f` on every live entry in slot order, like a loop over
+    // `iter_mut`, with a `Remover` to queue keys (the current one or any
+    // other) for removal. The queue is applied once the last call returns.
+    pub fn for_each_with_remover(&mut self, mut f: impl FnMut(Key, &mut T, &Remover)) {
+        let remover = Remover::new();
+        for (key, value) in self.slot_iter_mut() {
+            f(key, value, &remover);
+        }
+        for key in remover.keys.into_inner() {
+            self.vacate(key);
+        }
+    }
+
+    // Runs `f` with a `Transaction`. Its inserts take effect at once and its
+    // removes when `f` returns `Ok`; if `f` returns an error or panics the
+    // inserted entries are taken out again (without the finalizer) and the
+    // removed ones stay. Keys handed out inside a rolled-back transaction
+    // stay stale.
+    pub fn transaction<R, E>(&mut self, f: impl FnOnce(&mut Transaction<'_, T, S>) -> Result<R, E>) -> Result<R, E> {
+        let mut tx = Transaction { map: self, inserted: Vec::new(), removed: Vec::new() };
+        let result = f(&mut tx);
+        if result.is_ok() {
+            tx.commit();
+        }
+        result
+    }
+
+    // Guard whose inserts are removed again when it drops, for per-frame
+    // temporaries. The map stays usable through the guard in the meantime.
+    pub fn scoped(&mut self) -> ScopedInserts<'_, T, S> {
+        ScopedInserts { map: self, keys: Vec::new() }
+    }
+
+    // Dirty tracking and write versions count every slot as written up front
+    pub fn iter_mut(&mut self) -> IterMut<'_, T, S> {
+        let shuffle = self.shuffle_seed.map(|seed| Shuffle::new(seed, self.next_free.len()));
+        IterMut { shuffle, ..self.slot_iter_mut() }
+    }
+
+    // `iter_mut`, always in slot order
+    fn slot_iter_mut(&mut self) -> IterMut<'_, T, S> {
+        self.reclaim_stale();
+        self.touch(0, self.next_free.len());
+        IterMut {
+            shuffle: None,
+            issuer: self.issuer(),
+            values: &mut self.values,
+            generations: &self.generations,
+            next_free: &self.next_free,
+            front: 0,
+            back: self.next_free.len(),
+            remaining: self.len,
+            _values: PhantomData,
+        }
+    }
+
+    // Mutable access to several entries at once. `None` if any key is stale or
+    // two keys name the same slot.
+    pub fn get_disjoint_mut<const N: usize>(&mut self, keys: [Key; N]) -> Option<[&mut T; N]> {
+        for (i, key) in keys.iter().enumerate() {
+            self.check_provenance(*key);
+            if !self.resolves(*key) {
+                self.note_failed_lookup();
+                return None;
+            }
+            if keys[..i].iter().any(|other| other.index == key.index) {
+                return None;
+            }
+        }
+        for key in &keys {
+            self.touch(key.index as usize, key.index as usize + 1);
+        }
+        // Distinct live slots, so the references never alias
+        let values = keys.map(|key| self.values.slot_ptr(key.index as usize));
+        Some(values.map(|value| unsafe { &mut *value }))
+    }
+
+    pub fn get2_mut(&mut self, a: Key, b: Key) -> Option<(&mut T, &mut T)> {
+        let [a, b] = self.get_disjoint_mut([a, b])?;
+        Some((a, b))
+    }
+
+    pub fn get3_mut(&mut self, a: Key, b: Key, c: Key) -> Option<(&mut T, &mut T, &mut T)> {
+        let [a, b, c] = self.get_disjoint_mut([a, b, c])?;
+        Some((a, b, c))
+    }
+
+    // New map with the same slots and generations holding `f` of each value, so
+    // every key of this map resolves in it too
+    pub fn map<U: Default + Copy>(&self, mut f: impl FnMut(&T) -> U) -> FastSlotMap<U> {
+        self.derive(|_, value| Some(f(value)))
+    }
+
+    // Like `map`; entries for which `f` returns `None` are left out (their keys
+    // don't resolve in the new map)
+    pub fn filter_map<U: Default + Copy>(&self, mut f: impl FnMut(Key, &T) -> Option<U>) -> FastSlotMap<U> {
+        self.derive(&mut f)
+    }
+
+    // Combines the entries whose keys resolve in both maps
+    pub fn zip<U: Default + Copy, R: Storage<U>, V: Default + Copy>(
+        &self,
+        other: &FastSlotMap<U, R>,
+        mut f: impl FnMut(&T, &U) -> V,
+    ) -> FastSlotMap<V> {
+        self.derive(|key, value| Some(f(value, other.get(key)?)))
+    }
+
+    fn derive<U: Default + Copy>(&self, mut f: impl FnMut(Key, &T) -> Option<U>) -> FastSlotMap<U> {
+        let mut dropped = Vec::new();
+        let values = (0..self.next_free.len())
+            .map(|index| {
+                if !self.is_occupied(index) {
+                    return U::default();
+                }
+                let key = self.issue(Key::new(index as u32, self.generations[index]));
+                f(key, &self.values[index]).unwrap_or_else(|| {
+                    dropped.push(key);
+                    U::default()
+                })
+            })
+            .collect();
+
+        let mut derived = FastSlotMap::with_growth(self.growth.clone());
+        derived.values = VecStorage(values);
+        derived.generations = self.generations.clone();
+        derived.next_free = self.next_free.clone();
+        derived.free_head = self.free_head;
+        derived.free_tail = self.free_tail;
+        derived.policy = self.policy;
+        derived.free_heap = self.free_heap.clone();
+        derived.len = self.len;
+        derived.stale = self.stale.clone();
+        derived.compaction = self.compaction.clone();
+        derived.tombstones = self.tombstones.clone();
+        derived.purge_tombstones();
+        // Keys are shared with this map, so the id is too
+        #[cfg(all(debug_assertions, feature = "key-provenance"))]
+        {
+            derived.map_id = AtomicU16::new(self.map_id());
+        }
+        for key in dropped {
+            derived.vacate(key);
+        }
+        derived.set_alive_tracking(self.alive.is_some());
+        derived
+    }
+
+    // Applies `f` to each live entry of a key batch in ascending slot order,
+    // which walks memory front to back. Returns the stale keys, also in slot order.
+    pub fn apply_sorted(&mut self, keys: &[Key], mut f: impl FnMut(Key, &mut T)) -> Vec<Key> {
+        let mut sorted = keys.to_vec();
+        sorted.sort_unstable_by_key(|key| key.index);
+        let mut stale = Vec::new();
+        for key in sorted {
+            match self.try_get_mut(key) {
+                Ok(value) => f(key, value),
+                Err(_) => stale.push(key),
+            }
+        }
+        stale
+    }
+
+    // Key in the lowest occupied slot. Scans from the front, O(capacity) worst case.
+    pub fn min_key(&self) -> Option<Key> {
+        if self.len == 0 {
+            return None;
+        }
+        let index = (0..self.next_free.len()).find(|&i| self.is_occupied(i))?;
+        Some(self.issue(Key::new(index as u32, self.generations[index])))
+    }
+
+    // Key in the highest occupied slot. Scans from the back, O(capacity) worst case.
+    pub fn max_key(&self) -> Option<Key> {
+        if self.len == 0 {
+            return None;
+        }
+        let index = (0..self.next_free.len()).rev().find(|&i| self.is_occupied(i))?;
+        Some(self.issue(Key::new(index as u32, self.generations[index])))
+    }
+
+    pub fn first(&self) -> Option<(Key, &T)> {
+        let key = self.min_key()?;
+        Some((key, self.values.get(key.index as usize)?))
+    }
+
+    pub fn last(&self) -> Option<(Key, &T)> {
+        let key = self.max_key()?;
+        Some((key, self.values.get(key.index as usize)?))
+    }
+
+    // Moves every live entry of `other` into this map. If this map has never
+    // allocated a slot, `other`'s storage is taken over as-is and keys stay valid.
+    // Provisional entries stay provisional; either way the entries count as
+    // inserted and written now for telemetry and write versions.
+    pub fn absorb(&mut self, mut other: FastSlotMap<T, S>) -> KeyRemap {
+        self.version = self.version.wrapping_add(1);
+        // The entries live on here
+        other.finalizer = None;
+        other.finish_compaction();
+        other.purge_tombstones();
+        if self.values.is_empty() {
+            std::mem::swap(&mut self.values, &mut other.values);
+            std::mem::swap(&mut self.generations, &mut other.generations);
+            std::mem::swap(&mut self.next_free, &mut other.next_free);
+            std::mem::swap(&mut self.pins, &mut other.pins);
+            std::mem::swap(&mut self.free_heap, &mut other.free_heap);
+            std::mem::swap(&mut self.stale, &mut other.stale);
+            std::mem::swap(&mut self.provisional, &mut other.provisional);
+            if let Some(tracker) = &mut self.lifetimes {
+                let now = tracker.clock.now();
+                let stale = &self.stale;
+                tracker.stamps = (self.next_free.iter().enumerate())
+                    .map(|(index, &next)| if next == OCCUPIED && !stale.contains(&(index as u32)) { now } else { UNSTAMPED })
+                    .collect();
+            }
+            self.free_head = other.free_head;
+            self.free_tail = other.free_tail;
+            self.len = other.len;
+            if other.policy != self.policy {
+                self.rebuild_free_list();
+            }
+            #[cfg(all(debug_assertions, feature = "key-provenance"))]
+            {
+                *self.map_id.get_mut() = other.map_id();
+            }
+            self.set_alive_tracking(self.alive.is_some());
+            self.touch(0, self.values.len());
+            return KeyRemap { mapping: Mapping::Identity };
+        }
+
+        let mut entries = vec![None; other.values.len()];
+        for (index, entry) in entries.iter_mut().enumerate() {
+            if other.is_occupied(index) {
+                let old_generation = other.generations[index];
+                let key = self.insert(other.values[index]);
+                if other.provisional.get(&(index as u32)) == Some(&old_generation) {
+                    self.provisional.insert(key.index, key.generation);
+                }
+                *entry = Some((old_generation, key));
+            }
+        }
+        KeyRemap { mapping: Mapping::Table(entries) }
+    }
+
+    // Keeps the entry from being removed until every guard for it is dropped.
+    // The guard does not borrow the map, so it can be handed to another thread.
+    pub fn pin(&mut self, key: Key) -> Option<PinGuard> {
+        self.check_provenance(key);
+        if !self.resolves(key) {
+            self.note_failed_lookup();
+            return None;
+        }
+        let count = self.pins.entry(key.index).or_default().clone();
+        count.add(1);
+        Some(PinGuard { key, count })
+    }
+
+    // Speculative insert, e.g. for client-side prediction: the key works like
+    // any other until `commit` keeps the entry or `abort` takes it back out
+    pub fn insert_provisional(&mut self, value: T) -> Key {
+        let key = self.insert(value);
+        self.provisional.insert(key.index, key.generation);
+        key
+    }
+
+    pub fn is_provisional(&self, key: Key) -> bool {
+        self.provisional.get(&key.index) == Some(&key.generation) && self.resolves(key)
+    }
+
+    // Makes a provisional entry permanent; false if it isn't one
+    pub fn commit(&mut self, key: Key) -> bool {
+        if !self.is_provisional(key) {
+            return false;
+        }
+        self.provisional.remove(&key.index);
+        true
+    }
+
+    // Removes a provisional entry. The key goes stale as after `remove`, but
+    // its short-lived generation is left out of the reuse report totals and
+    // counted under `aborted_inserts` instead. `None` if the key isn't
+    // provisional or the entry is pinned.
+    pub fn abort(&mut self, key: Key) -> Option<T> {
+        if !self.is_provisional(key) {
+            return None;
+        }
+        let value = self.remove(key)?;
+        #[cfg(feature = "reuse-report")]
+        {
+            self.reuse.stale_keys.fetch_sub(1, Ordering::Relaxed);
+            if self.generations[key.index as usize] == 0 {
+                self.reuse.generation_wraps.fetch_sub(1, Ordering::Relaxed);
+            }
+            self.reuse.aborted_inserts.fetch_add(1, Ordering::Relaxed);
+        }
+        Some(value)
+    }
+
+    pub fn is_pinned(&self, key: Key) -> bool {
+        !self.pins.is_empty() && self.resolves(key) && self.pins.get(&key.index).is_some_and(|count| count.get() > 0)
+    }
+
+    // Keeps a one-bit-per-slot occupancy map so `contains` and `retain_live`
+    // reject keys to vacant slots without touching the generations array
+    pub fn set_alive_tracking(&mut self, enabled: bool) {
+        self.alive = None;
+        if enabled {
+            let mut bits = vec![0u64; self.next_free.len().div_ceil(64)];
+            for index in (0..self.next_free.len()).filter(|&index| self.is_occupied(index)) {
+                bits[index / 64] |= 1 << (index % 64);
+            }
+            self.alive = Some(bits);
+        }
+    }
+
+    // Drops every key that does not resolve, keeping the order of the rest
+    pub fn retain_live(&self, keys: &mut Vec<Key>) {
+        keys.retain(|&key| self.contains(key));
+    }
+
+    // Sets bit `i % 64` of word `i / 64` of `out` when `keys[i]` resolves and
+    // returns how many do. Where the CPU has AVX2 the generations of eight
+    // keys are gathered and compared at once; elsewhere it is a plain loop.
+    pub fn validate_batch(&self, keys: &[Key], out: &mut Vec<u64>) -> u32 {
+        out.clear();
+        out.resize(keys.len().div_ceil(64), 0);
+        let done = self.validate_batch_simd(keys, out);
+        for (i, &key) in keys.iter().enumerate().skip(done) {
+            self.check_provenance(key);
+            let valid = self.generations.get(key.index as usize) == Some(&key.generation) && !self.stale.contains(&key.index);
+            out[i / 64] |= (valid as u64) << (i % 64);
+        }
+        out.iter().map(|word| word.count_ones()).sum()
+    }
+
+    // Validates whole groups of eight keys with AVX2, returning how many keys it covered
+    #[cfg(target_arch = "x86_64")]
+    fn validate_batch_simd(&self, keys: &[Key], out: &mut [u64]) -> usize {
+        // Voided ranges are rare; leave them and huge maps (gather offsets are i32) to the loop
+        if !self.stale.is_empty() || self.generations.len() > i32::MAX as usize || !is_x86_feature_detected!("avx2") {
+            return 0;
+        }
+        for (group, chunk) in keys.chunks_exact(8).enumerate() {
+            let (mut indices, mut generations) = ([0u32; 8], [0u32; 8]);
+            for (lane, &key) in chunk.iter().enumerate() {
+                self.check_provenance(key);
+                (indices[lane], generations[lane]) = (key.index, key.generation);
+            }
+            // AVX2 support was checked above
+            let mask = unsafe { validate_lanes_avx2(&self.generations, &indices, &generations) };
+            out[group / 8] |= (mask as u64) << (group % 8 * 8);
+        }
+        keys.len() / 8 * 8
+    }
+
+    #[cfg(not(target_arch = "x86_64"))]
+    fn validate_batch_simd(&self, _keys: &[Key], _out: &mut [u64]) -> usize {
+        0
+    }
+
+    // Values for `keys` in the given order (e.g. a draw list), skipping stale keys
+    pub fn iter_keys<'a>(&'a self, keys: &'a [Key]) -> impl Iterator<Item = (Key, &'a T)> + 'a {
+        keys.iter().filter_map(|&key| Some((key, self.lookup(key)?)))
+    }
+
+    // `iter_keys` that also appends each skipped key to `stale`, e.g. to prune the list afterwards
+    pub fn iter_keys_with_stale<'a>(&'a self, keys: &'a [Key], stale: &'a mut Vec<Key>) -> impl Iterator<Item = (Key, &'a T)> + 'a {
+        keys.iter().filter_map(move |&key| match self.lookup(key) {
+            Some(value) => Some((key, value)),
+            None => {
+                stale.push(key);
+                None
+            }
+        })
+    }
+
+    fn mark_alive(&mut self, start: usize, end: usize, alive: bool) {
+        let Some(bits) = &mut self.alive else { return };
+        if bits.len() * 64 < end {
+            bits.resize(end.div_ceil(64), 0);
+        }
+        for index in start..end {
+            match alive {
+                true => bits[index / 64] |= 1 << (index % 64),
+                false => bits[index / 64] &= !(1 << (index % 64)),
+            }
+        }
+    }
+
+    // Starts recording which slots are inserted, removed or mutably accessed
+    pub fn set_dirty_tracking(&mut self, enabled: bool) {
+        self.dirty = enabled.then(Vec::new);
+    }
+
+    // Coalesces the slots touched since the last call into sorted, disjoint
+    // index ranges (e.g. for partial GPU buffer uploads) and clears them
+    pub fn take_dirty_ranges(&mut self) -> Vec<Range<u32>> {
+        let mut ranges: Vec<Range<u32>> = Vec::new();
+        let Some(dirty) = &mut self.dirty else { return ranges };
+        for (word_index, word) in dirty.iter_mut().enumerate() {
+            let mut bits = std::mem::take(word);
+            while bits != 0 {
+                let start = bits.trailing_zeros();
+                let run = (bits >> start).trailing_ones();
+                let first = (word_index * 64) as u32 + start;
+                match ranges.last_mut() {
+                    Some(last) if last.end == first => last.end += run,
+                    _ => ranges.push(first..first + run),
+                }
+                bits &= u64::MAX.checked_shl(start + run).unwrap_or(0);
+            }
+        }
+        ranges
+    }
+
+    // Makes `get_mut` and other writes stamp the slot with a new write version,
+    // so caches can tell a value changed even though its key is still live
+    pub fn set_write_versioning(&mut self, enabled: bool) {
+        self.write_versions = enabled.then(Vec::new);
+    }
+
+    // Stamp of the last write to the entry; 0 if never written while versioning was on.
+    // Separate from the generation, which only changes when the slot is freed.
+    pub fn write_version(&self, key: Key) -> Option<u64> {
+        self.check(key).ok()?;
+        let stamps = self.write_versions.as_ref()?;
+        Some(stamps.get(key.index as usize).copied().unwrap_or(0))
+    }
+
+    // Incremental backup: every slot written after write version `since`,
+    // occupied or not, in a blob `apply_delta` replays onto an older copy.
+    // Start a chain with `since = 0` and continue from each blob's `version()`.
+    // `None` unless write versioning is on; slots last written before it was
+    // turned on are never included.
+    pub fn export_since(&self, since: u64, mut encode: impl FnMut(&T, &mut Vec<u8>)) -> Option<DeltaBlob> {
+        let stamps = self.write_versions.as_ref()?;
+        let mut out = Vec::new();
+        out.extend_from_slice(&since.to_le_bytes());
+        out.extend_from_slice(&self.write_clock.to_le_bytes());
+        out.extend_from_slice(&(self.values.len() as u32).to_le_bytes());
+        out.extend_from_slice(&0u32.to_le_bytes()); // Entry count, patched below
+        let mut count = 0u32;
+        let mut value_bytes = Vec::new();
+        for (index, _) in stamps.iter().enumerate().filter(|&(_, &stamp)| stamp > since) {
+            let mut generation = self.generations[index];
+            out.extend_from_slice(&(index as u32).to_le_bytes());
+            if self.is_occupied(index) {
+                value_bytes.clear();
+                encode(&self.values[index], &mut value_bytes);
+                out.extend_from_slice(&generation.to_le_bytes());
+                out.extend_from_slice(&(value_bytes.len() as u32).to_le_bytes());
+                out.extend_from_slice(&value_bytes);
+            } else {
+                // Voided slots get the generation they'll have once reclaimed
+                if self.stale.contains(&(index as u32)) {
+                    generation = generation.wrapping_add(1);
+                }
+                out.extend_from_slice(&generation.to_le_bytes());
+                out.extend_from_slice(&u32::MAX.to_le_bytes());
+            }
+            count += 1;
+        }
+        out[20..24].copy_from_slice(&count.to_le_bytes());
+        Some(DeltaBlob(out))
+    }
+
+    // Replays a delta from `export_since` onto a copy of the map taken at or
+    // after its `since` version, leaving every slot as it was at export. Keys
+    // issued by the exporting map resolve the same way afterwards. Entries
+    // the replay overwrites are not passed to the finalizer. Returns false,
+    // changing nothing, for malformed input or if a slot it changes holds a
+    // pinned entry.
+    pub fn apply_delta(&mut self, delta: &DeltaBlob, mut decode: impl FnMut(&[u8]) -> Option<T>) -> bool {
+        const MIN_ENTRY_LEN: usize = 12; // Index, generation and length of a vacant slot
+        let mut bytes = delta.0.as_slice();
+        let parsed = (|| {
+            take_bytes(&mut bytes, 16)?;
+            let slots = u32::from_le_bytes(take_bytes(&mut bytes, 4)?.try_into().ok()?);
+            let count = u32::from_le_bytes(take_bytes(&mut bytes, 4)?.try_into().ok()?);
+            // Every slot created since the copy was taken is in the delta, so
+            // a delta can't add more slots than it has entries
+            if count as usize > bytes.len() / MIN_ENTRY_LEN || slots as usize > self.values.len() + count as usize {
+                return None;
+            }
+            let mut entries = Vec::with_capacity(count as usize);
+            for _ in 0..count {
+                let index = u32::from_le_bytes(take_bytes(&mut bytes, 4)?.try_into().ok()?);
+                let generation = u32::from_le_bytes(take_bytes(&mut bytes, 4)?.try_into().ok()?);
+                let value = match u32::from_le_bytes(take_bytes(&mut bytes, 4)?.try_into().ok()?) {
+                    u32::MAX => None,
+                    len => Some(decode(take_bytes(&mut bytes, len as usize)?)?),
+                };
+                if index >= slots || index >= TOMBSTONE {
+                    return None;
+                }
+                entries.push((index, generation, value));
+            }
+            bytes.is_empty().then_some((slots, entries))
+        })();
+        let Some((slots, entries)) = parsed else { return false };
+        let pinned = |&(index, _, _): &(u32, u32, Option<T>)| {
+            (index as usize) < self.values.len() && self.is_pinned(Key::new(index, self.generations[index as usize]))
+        };
+        if entries.iter().any(pinned) {
+            return false;
+        }
+        self.reclaim_stale();
+        self.extend_slots(slots as usize);
+        // This is a restore, not a removal
+        let finalizer = self.finalizer.take();
+        for (index, generation, value) in entries {
+            if self.is_occupied(index as usize) {
+                self.vacate(Key::new(index, self.generations[index as usize]));
+            }
+            match value {
+                Some(value) => {
+                    self.insert_at_raw(index, generation, value).expect("slot was vacated");
+                }
+                None => {
+                    self.generations[index as usize] = generation;
+                    self.touch(index as usize, index as usize + 1);
+                }
+            }
+        }
+        self.finalizer = finalizer;
+        true
+    }
+
+    // Writes every slot so `load` can rebuild the map with the same keys:
+    //   [slot count (u32 LE), slot table, values...]
+    //   slot: [generation (u32 LE), value length (u32 LE, u32::MAX if vacant)]
+    // Values follow the table in slot order, so a loader can find each one
+    // without decoding the others.
+    pub fn save(&self, mut encode: impl FnMut(&T, &mut Vec<u8>)) -> Vec<u8> {
+        let slots = self.values.len();
+        let mut out = Vec::with_capacity(4 + slots * 8);
+        out.extend_from_slice(&(slots as u32).to_le_bytes());
+        let mut value_bytes = Vec::new();
+        for index in 0..slots {
+            let mut generation = self.generations[index];
+            let len = if self.is_occupied(index) {
+                let start = value_bytes.len();
+                encode(&self.values[index], &mut value_bytes);
+                (value_bytes.len() - start) as u32
+            } else {
+                // Voided slots get the generation they'll have once reclaimed
+                if self.stale.contains(&(index as u32)) {
+                    generation = generation.wrapping_add(1);
+                }
+                u32::MAX
+            };
+            out.extend_from_slice(&generation.to_le_bytes());
+            out.extend_from_slice(&len.to_le_bytes());
+        }
+        out.extend_from_slice(&value_bytes);
+        out
+    }
+
+    // Counts successful `get`/`get_mut` lookups per slot, for finding hot
+    // entries during performance triage. Costs one branch per lookup when off.
+    pub fn set_access_profiling(&mut self, enabled: bool) {
+        self.access_counts = enabled.then(|| (0..self.values.len()).map(|_| AtomicU32::new(0)).collect());
+    }
+
+    // Up to `n` live entries with the most lookups since profiling was turned
+    // on or last reset, most accessed first. Empty while profiling is off.
+    pub fn hottest(&self, n: usize) -> Vec<(Key, u32)> {
+        let Some(counts) = &self.access_counts else { return Vec::new() };
+        let mut hot: Vec<(Key, u32)> = counts
+            .iter()
+            .enumerate()
+            .map(|(index, count)| (index, count.load(Ordering::Relaxed)))
+            .filter(|&(index, count)| count > 0 && self.is_occupied(index))
+            .map(|(index, count)| (self.issue(Key::new(index as u32, self.generations[index])), count))
+            .collect();
+        hot.sort_unstable_by_key(|&(key, count)| (Reverse(count), key.index));
+        hot.truncate(n);
+        hot
+    }
+
+    // Times entries with `clock` (see `Clock`) for `lifetime_stats`: how long
+    // entries live from insert to removal, and how long slots sit vacant
+    // before reuse. Entries voided by `invalidate_all` are not recorded (their
+    // slots idle from the call), nor are slots that were already vacant when
+    // this was turned on.
+    pub fn set_lifetime_telemetry(&mut self, clock: impl Clock + Send + Sync + 'static) {
+        let now = clock.now();
+        let stamps = (0..self.next_free.len()).map(|index| if self.is_occupied(index) { now } else { UNSTAMPED }).collect();
+        self.lifetimes = Some(Box::new(LifetimeTracker { clock: Box::new(clock), stamps, stats: LifetimeStats::default() }));
+    }
+
+    pub fn disable_lifetime_telemetry(&mut self) {
+        self.lifetimes = None;
+    }
+
+    // Histograms recorded since telemetry was turned on; `None` while it is off
+    pub fn lifetime_stats(&self) -> Option<LifetimeStats> {
+        self.lifetimes.as_ref().map(|tracker| tracker.stats.clone())
+    }
+
+    // Stamps a slot as filled now, recording how long it was vacant
+    fn note_filled(&mut self, index: usize) {
+        let Some(tracker) = &mut self.lifetimes else { return };
+        let now = tracker.clock.now();
+        if let Some(vacated) = tracker.stamp(index, now) {
+            tracker.stats.idle.record(now.saturating_sub(vacated));
+        }
+    }
+
+    // Stamps a slot as vacated now, recording how long its entry lived
+    fn note_vacated(&mut self, index: usize) {
+        let Some(tracker) = &mut self.lifetimes else { return };
+        let now = tracker.clock.now();
+        if let Some(inserted) = tracker.stamp(index, now) {
+            tracker.stats.lifetimes.record(now.saturating_sub(inserted));
+        }
+    }
+
+    // Zeroes the lookup counts, e.g. at the start of each frame
+    pub fn reset_access_counts(&mut self) {
+        if let Some(counts) = &mut self.access_counts {
+            counts.iter_mut().for_each(|count| *count.get_mut() = 0);
+        }
+    }
+
+    fn note_access(&self, index: usize) {
+        if let Some(count) = self.access_counts.as_ref().and_then(|counts| counts.get(index)) {
+            count.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    // Records that slots `start..end` were written (dirty tracking and write versions)
+    fn touch(&mut self, start: usize, end: usize) {
+        if let Some(counts) = &mut self.access_counts {
+            if counts.len() < end {
+                counts.resize_with(end, || AtomicU32::new(0));
+            }
+        }
+        if let Some(dirty) = &mut self.dirty {
+            if dirty.len() * 64 < end {
+                dirty.resize(end.div_ceil(64), 0);
+            }
+            for index in start..end {
+                dirty[index / 64] |= 1 << (index % 64);
+            }
+        }
+        if let Some(stamps) = &mut self.write_versions {
+            self.write_clock += 1;
+            if stamps.len() < end {
+                stamps.resize(end, 0);
+            }
+            stamps[start..end].fill(self.write_clock);
+        }
+    }
+
+    // Tags a key handed out by this map with its id (provenance builds only)
+    fn issue(&self, key: Key) -> Key {
+        #[cfg(all(debug_assertions, feature = "key-provenance"))]
+        return Key { map_id: self.map_id(), ..key };
+        #[cfg(not(all(debug_assertions, feature = "key-provenance")))]
+        key
+    }
+
+    // For views and iterators that hand out keys without holding the map
+    fn issuer(&self) -> Issuer {
+        Issuer {
+            #[cfg(all(debug_assertions, feature = "key-provenance"))]
+            map_id: self.map_id(),
+        }
+    }
+
+    // Takes an id the first time one is needed, so `new` stays const
+    #[cfg(all(debug_assertions, feature = "key-provenance"))]
+    fn map_id(&self) -> u16 {
+        match self.map_id.load(Ordering::Relaxed) {
+            0 => {
+                let id = next_map_id();
+                match self.map_id.compare_exchange(0, id, Ordering::Relaxed, Ordering::Relaxed) {
+                    Ok(_) => id,
+                    Err(current) => current,
+                }
+            }
+            id => id,
+        }
+    }
+
+    // Advances a slot's generation so its current keys stop resolving
+    fn bump_generation(&mut self, index: usize) {
+        self.generations[index] = self.generations[index].wrapping_add(1);
+        #[cfg(feature = "reuse-report")]
+        if self.generations[index] == 0 {
+            self.reuse.generation_wraps.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn note_stale_keys(&self, _keys: u64) {
+        #[cfg(feature = "reuse-report")]
+        self.reuse.stale_keys.fetch_add(_keys, Ordering::Relaxed);
+    }
+
+    fn note_failed_lookup(&self) {
+        #[cfg(feature = "reuse-report")]
+        self.reuse.failed_lookups.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Totals since the map was created, for soak-test logs
+    #[cfg(feature = "reuse-report")]
+    pub fn reuse_report(&self) -> ReuseReport {
+        ReuseReport {
+            stale_keys: self.reuse.stale_keys.load(Ordering::Relaxed),
+            failed_lookups: self.reuse.failed_lookups.load(Ordering::Relaxed),
+            generation_wraps: self.reuse.generation_wraps.load(Ordering::Relaxed),
+            aborted_inserts: self.reuse.aborted_inserts.load(Ordering::Relaxed),
+        }
+    }
+
+    fn check_provenance(&self, _key: Key) {
+        #[cfg(all(debug_assertions, feature = "key-provenance"))]
+        assert!(_key.map_id == 0 || _key.map_id == self.map_id(), "key {:?} was issued by a different slot map", _key);
+    }
+
+    fn is_occupied(&self, index: usize) -> bool {
+        self.next_free[index] == OCCUPIED && !self.stale.contains(&(index as u32))
+    }
+
+    // Voids every outstanding key and empties the map without walking the
+    // slots; voided slots are reclaimed as inserts need them (or all at once
+    // before bulk operations). Returns false, changing nothing, while any
+    // entry is pinned.
+    pub fn invalidate_all(&mut self) -> bool {
+        if self.pins.values().any(|count| count.get() > 0) {
+            return false;
+        }
+        self.finalize_all();
+        self.provisional.clear();
+        if let Some(tracker) = &mut self.lifetimes {
+            // Voided slots start idling now; their entries' lifetimes aren't recorded
+            let now = tracker.clock.now();
+            for (index, &next) in self.next_free.iter().enumerate() {
+                if next == OCCUPIED && !self.stale.contains(&(index as u32)) {
+                    tracker.stamp(index, now);
+                }
+            }
+        }
+        // Vacant slots in the range are skipped when it is reclaimed
+        self.stale = 0..self.next_free.len() as u32;
+        self.note_stale_keys(self.len as u64);
+        self.len = 0;
+        self.version = self.version.wrapping_add(1);
+        self.touch(0, self.next_free.len());
+        true
+    }
+
+    // Next voided slot that still holds an entry, retired so it can be reused.
+    // Inserts take these before the free list, as free slots inside the range
+    // would read as voided.
+    fn take_stale(&mut self) -> Option<u32> {
+        for index in self.stale.by_ref() {
+            if self.next_free[index as usize] == OCCUPIED {
+                self.bump_generation(index as usize);
+                self.pins.remove(&index);
+                return Some(index);
+            }
+        }
+        None
+    }
+
+    // Returns every voided slot to the free list
+    fn reclaim_stale(&mut self) {
+        while let Some(index) = self.take_stale() {
+            self.push_free(index);
+        }
+    }
+
+    // Walks the free list in reuse order. Stops early if the list is corrupt.
+    pub fn free_slots(&self) -> impl Iterator<Item = u32> + '_ {
+        let first = (self.free_head as usize) < self.next_free.len();
+        let listed = std::iter::successors(first.then_some(self.free_head), |&index| {
+            let next = self.next_free[index as usize];
+            ((next as usize) < self.next_free.len()).then_some(next)
+        })
+        .take(self.next_free.len());
+        // Ascending `Reverse` order is descending index order
+        let heaped = self.free_heap.clone().into_sorted_vec();
+        listed.chain(heaped.into_iter().rev().map(|Reverse(index)| index))
+    }
+
+    // Switches how vacant slots are reused, relinking the existing ones
+    pub fn set_free_list_policy(&mut self, policy: FreeListPolicy) {
+        self.finish_compaction();
+        self.policy = policy;
+        self.rebuild_free_list();
+    }
+
+    pub fn free_list_policy(&self) -> FreeListPolicy {
+        self.policy
+    }
+
+    // Idle-time maintenance: relinks the free list in ascending slot order so
+    // the next inserts fill the lowest holes and iteration stays dense. Keys
+    // and entries don't move. `LowestFirst` is always in this order already.
+    pub fn defragment_free_list(&mut self) {
+        if self.policy == FreeListPolicy::LowestFirst {
+            return;
+        }
+        let mut vacant = std::mem::take(&mut self.sort_scratch);
+        vacant.clear();
+        let mut index = self.free_head;
+        while index != u32::MAX {
+            vacant.push(index);
+            index = self.next_free[index as usize];
+        }
+        vacant.sort_unstable();
+        self.free_head = u32::MAX;
+        self.free_tail = u32::MAX;
+        // A queue reuses in push order, a stack in reverse
+        if self.policy == FreeListPolicy::Fifo {
+            vacant.iter().for_each(|&index| self.push_free(index));
+        } else {
+            vacant.iter().rev().for_each(|&index| self.push_free(index));
+        }
+        self.sort_scratch = vacant;
+    }
+
+    // Makes a vacant slot available for reuse; its `next_free` entry is overwritten
+    fn push_free(&mut self, index: u32) {
+        self.mark_alive(index as usize, index as usize + 1, false);
+        match self.policy {
+            FreeListPolicy::Lifo => {
+                self.next_free[index as usize] = self.free_head;
+                self.free_head = index;
+            }
+            FreeListPolicy::LowestFirst => {
+                self.next_free[index as usize] = u32::MAX;
+                self.free_heap.push(Reverse(index));
+            }
+            FreeListPolicy::Fifo => {
+                self.next_free[index as usize] = u32::MAX;
+                match self.free_head {
+                    u32::MAX => self.free_head = index,
+                    _ => self.next_free[self.free_tail as usize] = index,
+                }
+                self.free_tail = index;
+            }
+        }
+    }
+
+    // Takes the next slot to reuse; the caller marks it occupied or holds it
+    fn pop_free(&mut self) -> Option<u32> {
+        match self.policy {
+            FreeListPolicy::Lifo | FreeListPolicy::Fifo => {
+                let index = self.free_head;
+                if index == u32::MAX {
+                    return None;
+                }
+                self.free_head = self.next_free[index as usize];
+                if self.free_head == u32::MAX {
+                    self.free_tail = u32::MAX;
+                }
+                Some(index)
+            }
+            FreeListPolicy::LowestFirst => self.free_heap.pop().map(|Reverse(index)| index),
+        }
+    }
+
+    // Removes one particular vacant slot from the free list
+    fn unlink_free(&mut self, index: u32) {
+        match self.policy {
+            FreeListPolicy::Lifo | FreeListPolicy::Fifo if self.free_head == index => {
+                self.free_head = self.next_free[index as usize];
+                if self.free_head == u32::MAX {
+                    self.free_tail = u32::MAX;
+                }
+            }
+            FreeListPolicy::Lifo | FreeListPolicy::Fifo => {
+                let previous = self.free_slots().find(|&free| self.next_free[free as usize] == index);
+                let previous = previous.expect("vacant slot missing from the free list");
+                self.next_free[previous as usize] = self.next_free[index as usize];
+                if self.free_tail == index {
+                    self.free_tail = previous;
+                }
+            }
+            FreeListPolicy::LowestFirst => self.free_heap.retain(|&Reverse(free)| free != index),
+        }
+    }
+
+    // Checks free-list and length invariants, reporting every violation found
+    pub fn debug_validate(&self) -> ValidationReport {
+        let slots = self.values.len();
+        let mut issues = Vec::new();
+        if self.generations.len() != slots || self.next_free.len() != slots {
+            issues.push(Corruption::ArrayLengthMismatch);
+            return ValidationReport { slots: slots as u32, live: 0, free: 0, issues };
+        }
+
+        let mut visited = vec![false; slots];
+        for &index in self.compaction.iter().flat_map(|pass| &pass.held).chain(self.tombstones.iter().flatten()) {
+            visited[index as usize] = true;
+        }
+        let mut free = 0;
+        let mut index = self.free_head;
+        while index != u32::MAX {
+            if index as usize >= slots {
+                issues.push(Corruption::FreeListOutOfBounds { index });
+                break;
+            }
+            if visited[index as usize] {
+                issues.push(Corruption::FreeListCycle { index });
+                break;
+            }
+            visited[index as usize] = true;
+            if self.is_occupied(index as usize) {
+                issues.push(Corruption::LiveSlotInFreeList { index });
+                break;
+            }
+            free += 1;
+            index = self.next_free[index as usize];
+        }
+        for &Reverse(index) in &self.free_heap {
+            if index as usize >= slots {
+                issues.push(Corruption::FreeListOutOfBounds { index });
+            } else if visited[index as usize] {
+                issues.push(Corruption::FreeListCycle { index });
+            } else if self.is_occupied(index as usize) {
+                issues.push(Corruption::LiveSlotInFreeList { index });
+            } else {
+                visited[index as usize] = true;
+                free += 1;
+            }
+        }
+
+        let live = (0..slots).filter(|&i| self.is_occupied(i)).count() as u32;
+        if live != self.len {
+            issues.push(Corruption::LenMismatch { recorded: self.len, counted: live });
+        }
+        for (index, &reached) in visited.iter().enumerate() {
+            if !reached && !self.is_occupied(index) && !self.stale.contains(&(index as u32)) {
+                issues.push(Corruption::UnreachableVacantSlot { index: index as u32 });
+            }
+        }
+        ValidationReport { slots: slots as u32, live, free, issues }
+    }
+
+    // Relinks every vacant slot so the lowest index is reused first
+    fn rebuild_free_list(&mut self) {
+        self.reclaim_stale();
+        self.free_head = u32::MAX;
+        self.free_tail = u32::MAX;
+        self.free_heap.clear();
+        // Tombstoned slots stay off the list until purged
+        let vacant: Vec<u32> = (0..self.values.len() as u32)
+            .filter(|&index| !self.is_occupied(index as usize) && self.next_free[index as usize] != TOMBSTONE)
+            .collect();
+        // A queue reuses in push order, a stack in reverse
+        if self.policy == FreeListPolicy::Fifo {
+            vacant.into_iter().for_each(|index| self.push_free(index));
+        } else {
+            vacant.into_iter().rev().for_each(|index| self.push_free(index));
+        }
+    }
+
+    // `slotmap`-style name for `contains`
+    #[cfg(feature = "slotmap")]
+    pub fn contains_key(&self, key: Key) -> bool {
+        self.contains(key)
+    }
+
+    // `slab`-style name for `remove`
+    #[cfg(feature = "slab")]
+    pub fn try_remove(&mut self, key: Key) -> Option<T> {
+        self.remove(key)
+    }
+
+    pub fn len(&self) -> u32 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Opaque token for detecting inserts/removes made after it was taken
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    pub fn has_changed_since(&self, token: u64) -> bool {
+        self.version != token
+    }
+}
+
+// With `CowStorage` this shares value pages instead of copying them. Slot
+// bookkeeping (8 bytes per slot) is still copied, and the clone starts with
+// no grow callback and no pins.
+impl<T: Default + Copy, S: Storage<T> + Clone> Clone for FastSlotMap<T, S> {
+    fn clone(&self) -> Self {
+        Self {
+            values: self.values.clone(),
+            generations: self.generations.clone(),
+            next_free: self.next_free.clone(),
+            free_head: self.free_head,
+            free_tail: self.free_tail,
+            policy: self.policy,
+            key_policy: self.key_policy,
+            free_heap: self.free_heap.clone(),
+            len: self.len,
+            version: self.version,
+            growth: self.growth.clone(),
+            on_grow: None,
+            watermarks: Vec::new(),
+            finalizer: None,
+            pins: BTreeMap::new(),
+            shuffle_seed: self.shuffle_seed,
+            provisional: self.provisional.clone(),
+            dirty: self.dirty.clone(),
+            alive: self.alive.clone(),
+            write_versions: self.write_versions.clone(),
+            write_clock: self.write_clock,
+            // Counting starts over in the clone
+            access_counts: self.access_counts.as_ref().map(|counts| counts.iter().map(|_| AtomicU32::new(0)).collect()),
+            lifetimes: None,
+            compaction: self.compaction.clone(),
+            sort_scratch: Vec::new(),
+            stale: self.stale.clone(),
+            tombstones: self.tombstones.clone(),
+            #[cfg(feature = "reuse-report")]
+            reuse: ReuseCounters::default(),
+            // Keys stay valid in the clone, so it shares the id
+            #[cfg(all(debug_assertions, feature = "key-provenance"))]
+            map_id: AtomicU16::new(self.map_id()),
+            _values: PhantomData,
+        }
+    }
+}
+
+// Span access needs the values of a span to be adjacent in memory
+impl<T: Default + Copy, S: Storage<T> + AsRef<[T]> + AsMut<[T]>> FastSlotMap<T, S> {
+    // Applies `f` to every live entry on the rayon pool. The slot array is cut
+    // into pieces holding about the same number of live entries, not the same
+    // number of slots, so sparse regions don't leave threads idle.
+    #[cfg(feature = "rayon")]
+    pub fn par_update(&mut self, f: impl Fn(Key, &mut T) + Sync + Send)
+    where
+        T: Send,
+    {
+        use rayon::prelude::*;
+        self.reclaim_stale();
+        self.touch(0, self.next_free.len());
+        let per_piece = (self.len as usize).div_ceil(rayon::current_num_threads() * 4).max(1);
+        let mut cuts = Vec::new(); // Slot starting each piece after the first
+        let mut live = 0;
+        for index in 0..self.next_free.len() {
+            if self.next_free[index] == OCCUPIED {
+                if live > 0 && live % per_piece == 0 {
+                    cuts.push(index);
+                }
+                live += 1;
+            }
+        }
+        cuts.push(self.next_free.len());
+        let (generations, next_free, issuer) = (&self.generations, &self.next_free, self.issuer());
+        let (mut rest, mut start) = (self.values.as_mut(), 0);
+        let mut pieces = Vec::with_capacity(cuts.len());
+        for cut in cuts {
+            let (piece, tail) = std::mem::take(&mut rest).split_at_mut(cut - start);
+            pieces.push((start, piece));
+            (rest, start) = (tail, cut);
+        }
+        pieces.into_par_iter().for_each(|(start, values)| {
+            for (index, value) in (start..).zip(values) {
+                if next_free[index] == OCCUPIED {
+                    f(issuer.key(index as u32, generations[index]), value);
+                }
+            }
+        });
+    }
+
+    // Key of the live entry `value` refers to, found from its address in the
+    // slot array, e.g. for a reference handed back by a C callback. `None` if
+    // it points outside the array, between values, or at a vacant slot; always
+    // `None` for zero-sized `T`.
+    pub fn key_of(&self, value: &T) -> Option<Key> {
+        self.key_of_ptr(value)
+    }
+
+    // `key_of` for a raw pointer; it is only compared, never dereferenced
+    pub fn key_of_ptr(&self, value: *const T) -> Option<Key> {
+        let size = std::mem::size_of::<T>();
+        let offset = (value as usize).checked_sub(self.values.as_ref().as_ptr() as usize)?;
+        if size == 0 || offset % size != 0 || offset / size >= self.values.len() {
+            return None;
+        }
+        let index = offset / size;
+        self.is_occupied(index).then(|| self.issue(Key::new(index as u32, self.generations[index])))
+    }
+
+    // Work-stealing iteration for job systems: share the result between
+    // worker threads, each of which pulls batches of `batch` slots from a
+    // common cursor until none are left. Batches never overlap, so each
+    // yields its live entries mutably.
+    pub fn steal_iter(&mut self, batch: u32) -> StealIter<'_, T> {
+        self.reclaim_stale();
+        self.touch(0, self.next_free.len());
+        StealIter {
+            issuer: self.issuer(),
+            values: self.values.as_mut().as_mut_ptr(),
+            generations: &self.generations,
+            next_free: &self.next_free,
+            batch: batch.max(1) as usize,
+            cursor: AtomicUsize::new(0),
+            _values: PhantomData,
+        }
+    }
+
+    pub fn get_span(&self, span: KeySpan) -> Option<&[T]> {
+        let start = span.base.index as usize;
+        self.contains_span(span).then(|| &self.values.as_ref()[start..start + span.len as usize])
+    }
+
+    pub fn get_span_mut(&mut self, span: KeySpan) -> Option<&mut [T]> {
+        if !self.contains_span(span) {
+            return None;
+        }
+        let (start, end) = (span.base.index as usize, (span.base.index + span.len) as usize);
+        self.touch(start, end);
+        Some(&mut self.values.as_mut()[start..end])
+    }
+
+    // Walks the slot array in blocks of N (at most 64) slots starting at indices
+    // that are multiples of N, so kernels can work on whole slices at a time
+    pub fn iter_chunks<const N: usize>(&self) -> impl Iterator<Item = Chunk<'_, T, N>> + '_ {
+        const { assert!(N > 0 && N <= 64, "chunk size must be between 1 and 64") };
+        let (values, issuer) = (self.values.as_ref(), self.issuer());
+        values.chunks(N).zip(self.generations.chunks(N)).zip(self.next_free.chunks(N)).enumerate().map(
+            move |(chunk, ((values, generations), links))| {
+                let occupancy = links.iter().enumerate().fold(0, |mask, (offset, &link)| {
+                    let live = link == OCCUPIED && !self.stale.contains(&((chunk * N + offset) as u32));
+                    mask | ((live as u64) << offset)
+                });
+                Chunk { base: (chunk * N) as u32, values, generations, occupancy, issuer }
+            },
+        )
+    }
+
+    // Immutable copy for maps that are done changing, with cheaper lookups (see `FrozenView`)
+    pub fn freeze(&self) -> FrozenView<T> {
+        let slots = self.values.len().max(2).next_power_of_two();
+        let mut entries: Vec<(u64, T)> = (0..slots).map(|index| (vacant_frozen_key(index), T::default())).collect();
+        for (key, value) in self.iter() {
+            entries[key.index as usize] = (key.as_ffi(), *value);
+        }
+        FrozenView { entries: entries.into_boxed_slice(), mask: slots - 1, len: self.len }
+    }
+
+    // Mutable access to one entry alongside read access to every other entry
+    pub fn split(&mut self, key: Key) -> Option<(&mut T, ReadOnlyView<'_, T>)> {
+        self.check(key).ok()?;
+        self.reclaim_stale();
+        self.touch(key.index as usize, key.index as usize + 1);
+        let issuer = self.issuer();
+        let (before, rest) = self.values.as_mut().split_at_mut(key.index as usize);
+        let (current, after) = rest.split_first_mut()?;
+        let view = ReadOnlyView { before, after, generations: &self.generations, excluded: key.index, issuer };
+        Some((current, view))
+    }
+
+    // Splits the existing slots into up to `shards` disjoint views that can be
+    // moved to scoped threads and mutated without locks. Each shard inserts
+    // only into its own vacant slots. When `f` returns, the free list is
+    // rebuilt (O(slots)) and the shards' inserts and removes are replayed
+    // through the map's usual bookkeeping.
+    pub fn scope<R>(&mut self, shards: usize, f: impl FnOnce(Vec<ShardMut<'_, T>>) -> R) -> R {
+        self.finish_compaction();
+        self.reclaim_stale();
+        self.version = self.version.wrapping_add(1);
+        self.touch(0, self.next_free.len());
+
+        let slots = self.next_free.len();
+        let size = slots.div_ceil(shards.max(1)).max(1);
+        let mut free: Vec<Vec<u32>> = vec![Vec::new(); slots.div_ceil(size)];
+        for index in self.free_slots() {
+            free[index as usize / size].push(index);
+        }
+        for list in &mut free {
+            // Pop lowest first, like a freshly rebuilt list
+            list.reverse();
+        }
+
+        let mut logs: Vec<(Vec<u32>, Vec<Key>)> = vec![(Vec::new(), Vec::new()); free.len()];
+        let issuer = self.issuer();
+        let views = self
+            .values
+            .as_mut()
+            .chunks_mut(size)
+            .zip(self.generations.chunks_mut(size))
+            .zip(self.next_free.chunks_mut(size))
+            .zip(free)
+            .zip(logs.iter_mut())
+            .enumerate()
+            .map(|(shard, ((((values, generations), next_free), free), (inserted, removed)))| ShardMut {
+                base: (shard * size) as u32,
+                values,
+                generations,
+                next_free,
+                free,
+                pins: &self.pins,
+                inserted,
+                removed,
+                issuer,
+            })
+            .collect();
+        let result = f(views);
+
+        // Removed entries are still in their slots; put them back so the
+        // removal below finalizes and records them like any other
+        for (_, removed) in &logs {
+            for key in removed {
+                self.next_free[key.index as usize] = OCCUPIED;
+            }
+        }
+        self.rebuild_free_list();
+        for (inserted, removed) in logs {
+            for index in inserted {
+                self.fill(index as usize, index as usize + 1);
+            }
+            for key in removed {
+                self.vacate(key);
+            }
+        }
+        result
+    }
+}
+
+// Disjoint range of slots from `FastSlotMap::scope`
+pub struct ShardMut<'a, T> {
+    base: u32, // Index of the first slot in the shard
+    values: &'a mut [T],
+    generations: &'a mut [u32],
+    next_free: &'a mut [u32],
+    free: Vec<u32>, // Vacant slots in this shard, next to reuse last
+    pins: &'a BTreeMap<u32, PinCount>,
+    inserted: &'a mut Vec<u32>, // Slots filled here, recorded in the map after `scope`
+    removed: &'a mut Vec<Key>,  // Entries removed here, freed in the map after `scope`
+    issuer: Issuer,
+}
+
+impl<T: Copy> ShardMut<'_, T> {
+    // Slots covered by this shard
+    pub fn range(&self) -> Range<u32> {
+        self.base..self.base + self.values.len() as u32
+    }
+
+    fn offset(&self, key: Key) -> Option<usize> {
+        let offset = key.index.checked_sub(self.base)? as usize;
+        (self.generations.get(offset) == Some(&key.generation) && self.next_free[offset] == OCCUPIED).then_some(offset)
+    }
+
+    // `None` for keys outside this shard as well as stale keys
+    pub fn get(&self, key: Key) -> Option<&T> {
+        self.offset(key).map(|offset| &self.values[offset])
+    }
+
+    pub fn get_mut(&mut self, key: Key) -> Option<&mut T> {
+        self.offset(key).map(|offset| &mut self.values[offset])
+    }
+
+    pub fn contains(&self, key: Key) -> bool {
+        self.offset(key).is_some()
+    }
+
+    // `None` once the shard has no vacant slots left
+    pub fn insert(&mut self, value: T) -> Option<Key> {
+        let index = self.free.pop()?;
+        let offset = (index - self.base) as usize;
+        self.values[offset] = value;
+        self.next_free[offset] = OCCUPIED;
+        self.inserted.push(index);
+        Some(self.issuer.key(index, self.generations[offset]))
+    }
+
+    // Pinned entries are not removed. The slot isn't reused until the scope
+    // ends, when the map frees it (running its finalizer and other hooks).
+    pub fn remove(&mut self, key: Key) -> Option<T> {
+        let offset = self.offset(key)?;
+        if self.pins.get(&key.index).is_some_and(|count| count.get() > 0) {
+            return None;
+        }
+        self.next_free[offset] = u32::MAX;
+        self.removed.push(key);
+        Some(self.values[offset])
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (Key, &mut T)> + '_ {
+        let (base, issuer) = (self.base, self.issuer);
+        let generations = &*self.generations;
+        let next_free = &*self.next_free;
+        self.values
+            .iter_mut()
+            .enumerate()
+            .filter(move |&(offset, _)| next_free[offset] == OCCUPIED)
+            .map(move |(offset, value)| (issuer.key(base + offset as u32, generations[offset]), value))
+    }
+
+    // Vacant slots left for `insert`
+    pub fn vacant(&self) -> usize {
+        self.free.len()
+    }
+}
+
+// Shared batch source from `FastSlotMap::steal_iter`
+pub struct StealIter<'a, T> {
+    values: *mut T, // Start of the map's values, exclusively borrowed for 'a
+    generations: &'a [u32],
+    next_free: &'a [u32],
+    batch: usize,
+    cursor: AtomicUsize, // First slot of the next batch to hand out
+    issuer: Issuer,
+    _values: PhantomData<&'a mut [T]>,
+}
+
+unsafe impl<T: Send> Send for StealIter<'_, T> {}
+unsafe impl<T: Send> Sync for StealIter<'_, T> {}
+
+impl<T> StealIter<'_, T> {
+    // Claims the next batch; `None` once every slot has been handed out
+    pub fn next_batch(&self) -> Option<impl Iterator<Item = (Key, &mut T)> + '_> {
+        let len = self.next_free.len();
+        // The cursor stops at `len`, so it can't wrap around and hand out slots twice
+        let claim = |start: usize| (start < len).then(|| start.saturating_add(self.batch).min(len));
+        let start = self.cursor.fetch_update(Ordering::Relaxed, Ordering::Relaxed, claim).ok()?;
+        let end = start.saturating_add(self.batch).min(len);
+        // The cursor hands out each slot range once, so no other batch aliases it
+        let values = unsafe { std::slice::from_raw_parts_mut(self.values.add(start), end - start) };
+        Some(values.iter_mut().zip(start..end).filter_map(|(value, index)| {
+            (self.next_free[index] == OCCUPIED).then(|| (self.issuer.key(index as u32, self.generations[index]), value))
+        }))
+    }
+}
+
+// Fixed-size block of slots from `FastSlotMap::iter_chunks`, vacant slots included
+pub struct Chunk<'a, T, const N: usize> {
+    base: u32,                 // Index of the first slot in the block
+    values: &'a [T],           // N values (fewer in the final block)
+    generations: &'a [u32],
+    occupancy: u64,            // Bit i is set when slot `base + i` is live
+    issuer: Issuer,
+}
+
+impl<'a, T, const N: usize> Chunk<'a, T, N> {
+    pub fn values(&self) -> &'a [T] {
+        self.values
+    }
+
+    pub fn occupancy(&self) -> u64 {
+        self.occupancy
+    }
+
+    pub fn key(&self, offset: usize) -> Option<Key> {
+        (offset < self.values.len() && self.occupancy & (1 << offset) != 0)
+            .then(|| self.issuer.key(self.base + offset as u32, self.generations[offset]))
+    }
+
+    // Live entries of the block
+    pub fn iter(&self) -> impl Iterator<Item = (Key, &'a T)> + '_ {
+        (0..self.values.len()).filter_map(move |offset| Some((self.key(offset)?, &self.values[offset])))
+    }
+}
+
+// Every entry of a map except the one mutably borrowed by `FastSlotMap::split`
+pub struct ReadOnlyView<'a, T> {
+    before: &'a [T],
+    after: &'a [T],
+    generations: &'a [u32],
+    excluded: u32,
+    issuer: Issuer,
+}
+
+impl<T> ReadOnlyView<'_, T> {
+    // Returns `None` for the excluded key as well as for stale keys
+    pub fn get(&self, key: Key) -> Option<&T> {
+        if key.index == self.excluded || self.generations.get(key.index as usize) != Some(&key.generation) {
+            return None;
+        }
+        if key.index < self.excluded {
+            self.before.get(key.index as usize)
+        } else {
+            self.after.get((key.index - self.excluded - 1) as usize)
+        }
+    }
+
+    pub fn contains(&self, key: Key) -> bool {
+        self.get(key).is_some()
+    }
+
+    pub fn excluded(&self) -> Key {
+        self.issuer.key(self.excluded, self.generations[self.excluded as usize])
+    }
+}
+
+// Snapshot made by `FastSlotMap::freeze`. Each slot stores its live key next
+// to the value, and the table is a power of two long, so `get` is a masked
+// index plus one 64-bit compare: no bounds or separate generation checks.
+pub struct FrozenView<T> {
+    entries: Box<[(u64, T)]>, // (`as_ffi` of the live key, value) per slot
+    mask: usize,              // entries.len() - 1
+    len: u32,
+}
+
+// Stored in vacant slot `index`. Its index half is `!index`, which masks to a
+// different slot (the table has at least two), so no key can ever match it.
+fn vacant_frozen_key(index: usize) -> u64 {
+    !(index as u32) as u64
+}
+
+impl<T> FrozenView<T> {
+    pub fn get(&self, key: Key) -> Option<&T> {
+        let (stored, value) = &self.entries[key.index as usize & self.mask];
+        (*stored == key.as_ffi()).then_some(value)
+    }
+
+    pub fn contains(&self, key: Key) -> bool {
+        self.get(key).is_some()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (Key, &T)> + '_ {
+        self.entries
+            .iter()
+            .enumerate()
+            .filter(|&(index, (stored, _))| *stored != vacant_frozen_key(index))
+            .map(|(_, (stored, value))| (Key::from_ffi(*stored), value))
+    }
+
+    pub fn len(&self) -> u32 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+// Resumable position in slot order, for walking a map a batch at a time while
+// it changes in between. Removed entries are skipped; entries inserted ahead
+// of the position are visited, those behind it are not. Compaction moves
+// entries backwards, so a pass that straddles it can miss some.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct Cursor {
+    next: u32,        // Next slot to look at
+    end: Option<u32>, // Stop before this slot; `None` follows the map as it grows
+}
+
+impl Cursor {
+    // Runs until the end of the map, including slots created after this call
+    pub fn new() -> Self {
+        Self { next: 0, end: None }
+    }
+
+    // Stops at the map's current slot count, so entries appended later are
+    // skipped (ones that reuse a vacant slot ahead of the cursor still show up)
+    pub fn existing<T: Default + Copy, S: Storage<T>>(map: &FastSlotMap<T, S>) -> Self {
+        Self { next: 0, end: Some(map.next_free.len() as u32) }
+    }
+
+    // Keys of up to `max` live entries from the current position, advancing past them
+    pub fn next_batch<T: Default + Copy, S: Storage<T>>(&mut self, map: &FastSlotMap<T, S>, max: usize) -> Vec<Key> {
+        let end = self.end_in(map);
+        let mut keys = Vec::with_capacity(max.min((end - self.next.min(end)) as usize));
+        while keys.len() < max && self.next < end {
+            let index = self.next as usize;
+            if map.is_occupied(index) {
+                keys.push(map.issue(Key::new(self.next, map.generations[index])));
+            }
+            self.next += 1;
+        }
+        keys
+    }
+
+    pub fn is_done<T: Default + Copy, S: Storage<T>>(&self, map: &FastSlotMap<T, S>) -> bool {
+        self.next >= self.end_in(map)
+    }
+
+    // Slot the next batch starts from
+    pub fn position(&self) -> u32 {
+        self.next
+    }
+
+    pub fn reset(&mut self) {
+        self.next = 0;
+    }
+
+    fn end_in<T: Default + Copy, S: Storage<T>>(&self, map: &FastSlotMap<T, S>) -> u32 {
+        let slots = map.next_free.len() as u32;
+        self.end.map_or(slots, |end| end.min(slots))
+    }
+}
+
+pub struct Iter<'a, T, S = VecStorage<T>> {
+    values: &'a S,
+    generations: &'a [u32],
+    next_free: &'a [u32],
+    stale: Range<usize>, // Slots voided by `invalidate_all`, skipped
+    front: usize, // Next position from the front
+    back: usize,  // One past the next position from the back
+    remaining: u32,
+    shuffle: Option<Shuffle>, // Maps positions to slots; identity when `None`
+    issuer: Issuer,
+    _values: PhantomData<&'a T>,
+}
+
+// Scrambles slot order for `FastSlotMap::set_iteration_shuffle`: position `p`
+// visits slot `(p * step + offset) % slots`, a permutation as `step` and
+// `slots` are coprime
+#[derive(Copy, Clone)]
+struct Shuffle {
+    step: u64,
+    offset: u64,
+    slots: u64,
+}
+
+impl Shuffle {
+    fn new(seed: u64, slots: usize) -> Self {
+        // splitmix64 finalizer, so nearby seeds give unrelated orders
+        let mix = |mut x: u64| {
+            x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+            x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+            x ^ (x >> 31)
+        };
+        let gcd = |mut a: u64, mut b: u64| {
+            while b != 0 {
+                (a, b) = (b, a % b);
+            }
+            a
+        };
+        let slots = slots.max(1) as u64;
+        let mut step = mix(seed) % slots;
+        while gcd(step, slots) != 1 {
+            step += 1;
+        }
+        Shuffle { step, offset: mix(seed ^ 0x9e37_79b9_7f4a_7c15) % slots, slots }
+    }
+
+    fn slot(shuffle: Option<Shuffle>, position: usize) -> usize {
+        match shuffle {
+            Some(shuffle) => ((position as u64 * shuffle.step + shuffle.offset) % shuffle.slots) as usize,
+            None => position,
+        }
+    }
+}
+
+impl<'a, T: 'a, S: Storage<T>> Iterator for Iter<'a, T, S> {
+    type Item = (Key, &'a T);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.front < self.back {
+            let index = Shuffle::slot(self.shuffle, self.front);
+            self.front += 1;
+            if self.next_free[index] == OCCUPIED && !self.stale.contains(&index) {
+                self.remaining -= 1;
+                return Some((self.issuer.key(index as u32, self.generations[index]), &self.values[index]));
+            }
+        }
+        None
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining as usize, Some(self.remaining as usize))
+    }
+}
+
+impl<'a, T: 'a, S: Storage<T>> DoubleEndedIterator for Iter<'a, T, S> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        while self.front < self.back {
+            self.back -= 1;
+            let index = Shuffle::slot(self.shuffle, self.back);
+            if self.next_free[index] == OCCUPIED && !self.stale.contains(&index) {
+                self.remaining -= 1;
+                return Some((self.issuer.key(index as u32, self.generations[index]), &self.values[index]));
+            }
+        }
+        None
+    }
+}
+
+impl<'a, T: 'a, S: Storage<T>> ExactSizeIterator for Iter<'a, T, S> {}
+impl<'a, T: 'a, S: Storage<T>> FusedIterator for Iter<'a, T, S> {}
+
+pub struct IterMut<'a, T, S = VecStorage<T>> {
+    values: &'a mut S,
+    generations: &'a [u32],
+    next_free: &'a [u32],
+    front: usize,
+    back: usize,
+    remaining: u32,
+    shuffle: Option<Shuffle>,
+    issuer: Issuer,
+    _values: PhantomData<&'a mut T>,
+}
+
+impl<'a, T: 'a, S: Storage<T>> IterMut<'a, T, S> {
+    fn entry(&mut self, index: usize) -> (Key, &'a mut T) {
+        self.remaining -= 1;
+        // Each slot is yielded at most once, so the borrows never overlap
+        let value = unsafe { &mut *self.values.slot_ptr(index) };
+        (self.issuer.key(index as u32, self.generations[index]), value)
+    }
+}
+
+impl<'a, T: 'a, S: Storage<T>> Iterator for IterMut<'a, T, S> {
+    type Item = (Key, &'a mut T);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.front < self.back {
+            let index = Shuffle::slot(self.shuffle, self.front);
+            self.front += 1;
+            if self.next_free[index] == OCCUPIED {
+                return Some(self.entry(index));
+            }
+        }
+        None
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining as usize, Some(self.remaining as usize))
+    }
+}
+
+impl<'a, T: 'a, S: Storage<T>> DoubleEndedIterator for IterMut<'a, T, S> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        while self.front < self.back {
+            self.back -= 1;
+            let index = Shuffle::slot(self.shuffle, self.back);
+            if self.next_free[index] == OCCUPIED {
+                return Some(self.entry(index));
+            }
+        }
+        None
+    }
+}
+
+impl<'a, T: 'a, S: Storage<T>> ExactSizeIterator for IterMut<'a, T, S> {}
+impl<'a, T: 'a, S: Storage<T>> FusedIterator for IterMut<'a, T, S> {}
+
+// Bit `lane` is set when `indices[lane]` is in bounds and its slot's generation
+// equals `generations[lane]`. `slots` must have at most `i32::MAX` entries.
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx2")]
+unsafe fn validate_lanes_avx2(slots: &[u32], indices: &[u32; 8], generations: &[u32; 8]) -> u8 {
+    use std::arch::x86_64::*;
+    let index = _mm256_loadu_si256(indices.as_ptr() as *const __m256i);
+    let generation = _mm256_loadu_si256(generations.as_ptr() as *const __m256i);
+    // Unsigned `index < len` as a signed compare with the sign bits flipped
+    let bias = _mm256_set1_epi32(i32::MIN);
+    let len = _mm256_set1_epi32((slots.len() as u32 ^ 0x8000_0000) as i32);
+    let in_bounds = _mm256_cmpgt_epi32(len, _mm256_xor_si256(index, bias));
+    // Out-of-bounds lanes are masked off and never read
+    let current = _mm256_mask_i32gather_epi32::<4>(_mm256_setzero_si256(), slots.as_ptr() as *const i32, index, in_bounds);
+    let valid = _mm256_and_si256(in_bounds, _mm256_cmpeq_epi32(current, generation));
+    _mm256_movemask_ps(_mm256_castsi256_ps(valid)) as u8
+}
+
+// Merge-joins two slot-ordered entry streams on equal keys
+fn zip_live<A, B>(
+    mut a: impl Iterator<Item = (Key, A)>,
+    mut b: impl Iterator<Item = (Key, B)>,
+) -> impl Iterator<Item = (Key, A, B)> {
+    let (mut next_a, mut next_b) = (a.next(), b.next());
+    std::iter::from_fn(move || loop {
+        let (index_a, index_b) = (next_a.as_ref()?.0.index, next_b.as_ref()?.0.index);
+        match index_a.cmp(&index_b) {
+            std::cmp::Ordering::Less => next_a = a.next(),
+            std::cmp::Ordering::Greater => next_b = b.next(),
+            std::cmp::Ordering::Equal => {
+                let ((key, value_a), (other, value_b)) = (next_a.take()?, next_b.take()?);
+                (next_a, next_b) = (a.next(), b.next());
+                if key.generation == other.generation {
+                    return Some((key, value_a, value_b));
+                }
+            }
+        }
+    })
+}
+
+// Removal queue handed out by `FastSlotMap::for_each_with_remover`
+pub struct Remover {
+    keys: RefCell<Vec<Key>>,
+}
+
+impl Remover {
+    const fn new() -> Self {
+        Self { keys: RefCell::new(Vec::new()) }
+    }
+
+    // Stale keys and keys queued twice are ignored when the queue is applied
+    pub fn remove(&self, key: Key) {
+        self.keys.borrow_mut().push(key);
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.borrow().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+// Handed to the closure of `FastSlotMap::transaction`. Reads go through to
+// the map; only inserts and removes made here are rolled back.
+pub struct Transaction<'a, T: Default + Copy, S: Storage<T> = VecStorage<T>> {
+    map: &'a mut FastSlotMap<T, S>,
+    inserted: Vec<Key>, // Taken out again on rollback
+    removed: Vec<Key>,  // Still in the map; removed on commit
+}
+
+impl<T: Default + Copy, S: Storage<T>> Transaction<'_, T, S> {
+    pub fn insert(&mut self, value: T) -> Key {
+        let key = self.map.insert(value);
+        self.inserted.push(key);
+        key
+    }
+
+    // The entry is only marked here and leaves the map on commit, so its
+    // slot can't be reused by a later insert. Until then `get`, `contains`
+    // and `len` below leave it out, but other reads still see it.
+    // `None` if the key is stale, pinned or already removed here.
+    pub fn remove(&mut self, key: Key) -> Option<T> {
+        if self.removed.contains(&key) || self.map.is_pinned(key) {
+            return None;
+        }
+        let value = *self.map.get(key)?;
+        self.removed.push(key);
+        Some(value)
+    }
+
+    pub fn get(&self, key: Key) -> Option<&T> {
+        self.map.get(key).filter(|_| !self.removed.contains(&key))
+    }
+
+    pub fn contains(&self, key: Key) -> bool {
+        self.get(key).is_some()
+    }
+
+    pub fn len(&self) -> u32 {
+        self.map.len() - self.removed.len() as u32
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn commit(&mut self) {
+        self.inserted.clear();
+        for key in std::mem::take(&mut self.removed) {
+            self.map.vacate(key);
+        }
+    }
+}
+
+impl<T: Default + Copy, S: Storage<T>> Deref for Transaction<'_, T, S> {
+    type Target = FastSlotMap<T, S>;
+
+    fn deref(&self) -> &Self::Target {
+        self.map
+    }
+}
+
+// Rolls back whatever wasn't committed. Removed slots were never freed, so
+// no generation has to be wound back.
+impl<T: Default + Copy, S: Storage<T>> Drop for Transaction<'_, T, S> {
+    fn drop(&mut self) {
+        let finalizer = self.map.finalizer.take();
+        for &key in self.inserted.iter().rev() {
+            self.map.vacate(key);
+        }
+        self.map.finalizer = finalizer;
+    }
+}
+
+// Returned by `FastSlotMap::scoped`. Entries already removed by hand are
+// skipped on drop, and pinned ones are left in place.
+pub struct ScopedInserts<'a, T: Default + Copy, S: Storage<T> = VecStorage<T>> {
+    map: &'a mut FastSlotMap<T, S>,
+    keys: Vec<Key>, // Inserted through this guard
+}
+
+impl<T: Default + Copy, S: Storage<T>> ScopedInserts<'_, T, S> {
+    pub fn insert(&mut self, value: T) -> Key {
+        let key = self.map.insert(value);
+        self.keys.push(key);
+        key
+    }
+
+    pub fn keys(&self) -> &[Key] {
+        &self.keys
+    }
+}
+
+impl<T: Default + Copy, S: Storage<T>> Deref for ScopedInserts<'_, T, S> {
+    type Target = FastSlotMap<T, S>;
+
+    fn deref(&self) -> &Self::Target {
+        self.map
+    }
+}
+
+impl<T: Default + Copy, S: Storage<T>> DerefMut for ScopedInserts<'_, T, S> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.map
+    }
+}
+
+impl<T: Default + Copy, S: Storage<T>> Drop for ScopedInserts<'_, T, S> {
+    fn drop(&mut self) {
+        for &key in &self.keys {
+            self.map.vacate(key);
+        }
+    }
+}
+
+impl<'a, T: Default + Copy, S: Storage<T>> IntoIterator for &'a FastSlotMap<T, S> {
+    type Item = (Key, &'a T);
+    type IntoIter = Iter<'a, T, S>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl<'a, T: Default + Copy, S: Storage<T>> IntoIterator for &'a mut FastSlotMap<T, S> {
+    type Item = (Key, &'a mut T);
+    type IntoIter = IterMut<'a, T, S>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter_mut()
+    }
+}
+
+impl<T, S> Drop for FastSlotMap<T, S> {
+    fn drop(&mut self) {
+        if let Some(finalizer) = &self.finalizer {
+            (finalizer.drain)(self);
+        }
+    }
+}
+
+impl<T: Default + Copy> Default for FastSlotMap<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Panics on an invalid key regardless of the invalid-key policy
+impl<T: Default + Copy, S: Storage<T>> Index<Key> for FastSlotMap<T, S> {
+    type Output = T;
+
+    fn index(&self, key: Key) -> &T {
+        match self.lookup(key) {
+            Some(value) => value,
+            None => panic!("invalid slot map key {key:?}"),
+        }
+    }
+}
+
+impl<T: Default + Copy, S: Storage<T>> IndexMut<Key> for FastSlotMap<T, S> {
+    fn index_mut(&mut self, key: Key) -> &mut T {
+        match self.try_get_mut(key) {
+            Ok(value) => value,
+            Err(_) => panic!("invalid slot map key {key:?}"),
+        }
+    }
+}
+
+// Slab entries keep their index. Slab keys carry no generation, so live
+// entries get generation 0 and vacant slots start at 1 to reject stale indices.
+#[cfg(feature = "slab")]
+impl<T: Default + Copy> From<slab::Slab<T>> for FastSlotMap<T> {
+    fn from(slab: slab::Slab<T>) -> Self {
+        Self::from_slots(slab.into_iter().map(|(index, value)| (index as u32, 0, value)), 1)
+    }
+}
+
+#[cfg(feature = "slab")]
+impl Key {
+    pub fn from_slab_index(index: usize) -> Self {
+        Key::new(index as u32, 0)
+    }
+}
+
+// Entries keep both their index and version, so existing `slotmap` keys convert
+// with `Key::from(key.data())` and stay valid. Versions of vacant slots are not
+// observable through `slotmap`'s API, so stale keys to them are not guaranteed
+// to stay invalid after later reuse.
+#[cfg(feature = "slotmap")]
+impl<K: slotmap::Key, T: Default + Copy> From<slotmap::SlotMap<K, T>> for FastSlotMap<T> {
+    fn from(map: slotmap::SlotMap<K, T>) -> Self {
+        let slots = map.into_iter().map(|(key, value)| {
+            let key = Key::from(key.data());
+            (key.index, key.generation, value)
+        });
+        Self::from_slots(slots, 0)
+    }
+}
+
+#[cfg(feature = "slotmap")]
+impl From<slotmap::KeyData> for Key {
+    fn from(data: slotmap::KeyData) -> Self {
+        let ffi = data.as_ffi();
+        Key::new(ffi as u32, (ffi >> 32) as u32)
+    }
+}
+
+// `slotmap` versions are always odd, so only keys that came from a `slotmap`
+// round-trip exactly
+#[cfg(feature = "slotmap")]
+impl From<Key> for slotmap::KeyData {
+    fn from(key: Key) -> Self {
+        slotmap::KeyData::from_ffi(((key.generation as u64) << 32) | key.index as u64)
+    }
+}
+
+// `hecs` and `bevy_ecs` entity bits use the `as_ffi` layout (generation high,
+// index low), so an entity converts to a key with the same index and generation.
+// Use it with `insert_at_raw` to mirror entities into slots of the same number.
+#[cfg(feature = "hecs")]
+impl From<hecs::Entity> for Key {
+    fn from(entity: hecs::Entity) -> Self {
+        Key::from_ffi(entity.to_bits().get())
+    }
+}
+
+#[cfg(feature = "hecs")]
+impl Key {
+    // `None` for keys no entity can have (generation 0)
+    pub fn to_hecs_entity(self) -> Option<hecs::Entity> {
+        hecs::Entity::from_bits(self.as_ffi())
+    }
+}
+
+#[cfg(feature = "bevy_ecs")]
+impl From<bevy_ecs::entity::Entity> for Key {
+    fn from(entity: bevy_ecs::entity::Entity) -> Self {
+        Key::from_ffi(entity.to_bits())
+    }
+}
+
+#[cfg(feature = "bevy_ecs")]
+impl Key {
+    // `None` for keys no entity can have (generation 0)
+    pub fn to_bevy_entity(self) -> Option<bevy_ecs::entity::Entity> {
+        bevy_ecs::entity::Entity::try_from_bits(self.as_ffi()).ok()
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct NodeKey(Key);
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct EdgeKey(Key);
+
+#[derive(Copy, Clone)]
+struct Edge<E> {
+    from: NodeKey,
+    to: NodeKey,
+    weight: E,
+}
+
+impl<E: Default> Default for Edge<E> {
+    fn default() -> Self {
+        let null = NodeKey(Key::null());
+        Self { from: null, to: null, weight: E::default() }
+    }
+}
+
+pub struct SlotGraph<N, E> {
+    nodes: FastSlotMap<N>,
+    edges: FastSlotMap<Edge<E>>,
+    adjacency: Vec<Vec<EdgeKey>>, // Incident edges (in and out), indexed by node slot
+}
+
+impl<N: Default + Copy, E: Default + Copy> SlotGraph<N, E> {
+    pub fn new() -> Self {
+        Self {
+            nodes: FastSlotMap::new(),
+            edges: FastSlotMap::new(),
+            adjacency: Vec::new(),
+        }
+    }
+
+    pub fn add_node(&mut self, value: N) -> NodeKey {
+        let key = self.nodes.insert(value);
+        if key.index as usize == self.adjacency.len() {
+            self.adjacency.push(Vec::new());
+        }
+        NodeKey(key)
+    }
+
+    pub fn add_edge(&mut self, from: NodeKey, to: NodeKey, value: E) -> Option<EdgeKey> {
+        if !self.contains_node(from) || !self.contains_node(to) {
+            return None;
+        }
+        let key = EdgeKey(self.edges.insert(Edge { from, to, weight: value }));
+        self.adjacency[from.0.index as usize].push(key);
+        if from != to {
+            self.adjacency[to.0.index as usize].push(key);
+        }
+        Some(key)
+    }
+
+    pub fn node(&self, key: NodeKey) -> Option<&N> {
+        self.nodes.get(key.0)
+    }
+
+    pub fn node_mut(&mut self, key: NodeKey) -> Option<&mut N> {
+        self.nodes.get_mut(key.0)
+    }
+
+    pub fn edge(&self, key: EdgeKey) -> Option<&E> {
+        self.edges.get(key.0).map(|edge| &edge.weight)
+    }
+
+    pub fn edge_mut(&mut self, key: EdgeKey) -> Option<&mut E> {
+        self.edges.get_mut(key.0).map(|edge| &mut edge.weight)
+    }
+
+    pub fn endpoints(&self, key: EdgeKey) -> Option<(NodeKey, NodeKey)> {
+        self.edges.get(key.0).map(|edge| (edge.from, edge.to))
+    }
+
+    pub fn contains_node(&self, key: NodeKey) -> bool {
+        self.nodes.contains(key.0)
+    }
+
+    pub fn contains_edge(&self, key: EdgeKey) -> bool {
+        self.edges.contains(key.0)
+    }
+
+    // All edges touching `key`, regardless of direction
+    pub fn edges(&self, key: NodeKey) -> impl Iterator<Item = EdgeKey> + '_ {
+        let incident = if self.contains_node(key) { &self.adjacency[key.0.index as usize][..] } else { &[] };
+        incident.iter().copied()
+    }
+
+    // Targets of the outgoing edges of `key`
+    pub fn neighbors(&self, key: NodeKey) -> impl Iterator<Item = NodeKey> + '_ {
+        self.edges(key).filter_map(move |edge_key| {
+            let edge = self.edges.get(edge_key.0)?;
+            (edge.from == key).then_some(edge.to)
+        })
+    }
+
+    pub fn remove_edge(&mut self, key: EdgeKey) -> Option<E> {
+        let edge = self.edges.remove(key.0)?;
+        self.detach(edge.from, key);
+        if edge.from != edge.to {
+            self.detach(edge.to, key);
+        }
+        Some(edge.weight)
+    }
+
+    // Removes the node and cascades to every incident edge
+    pub fn remove_node(&mut self, key: NodeKey) -> Option<N> {
+        let value = self.nodes.remove(key.0)?;
+        let incident = std::mem::take(&mut self.adjacency[key.0.index as usize]);
+        for edge_key in incident {
+            if let Some(edge) = self.edges.remove(edge_key.0) {
+                let other = if edge.from == key { edge.to } else { edge.from };
+                if other != key {
+                    self.detach(other, edge_key);
+                }
+            }
+        }
+        Some(value)
+    }
+
+    pub fn node_count(&self) -> u32 {
+        self.nodes.len()
+    }
+
+    pub fn edge_count(&self) -> u32 {
+        self.edges.len()
+    }
+
+    fn detach(&mut self, node: NodeKey, edge: EdgeKey) {
+        let incident = &mut self.adjacency[node.0.index as usize];
+        if let Some(position) = incident.iter().position(|&e| e == edge) {
+            incident.swap_remove(position);
+        }
+    }
+}
+
+impl<N: Default + Copy, E: Default + Copy> Default for SlotGraph<N, E> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Map usable from many threads at once. Every slot carries a seqlock so
+// `Copy` values can be read without ever observing a torn write. Inserts never
+// allocate; capacity is added ahead of time with `prepare_grow`.
+// Use `ConcurrentSlotMap<CachePadded<T>>` to stop writers to neighbouring
+// slots from contending for the same cache line.
+pub struct ConcurrentSlotMap<T> {
+    values: Segmented<UnsafeCell<T>>, // Slot values, guarded by `seqs`
+    seqs: Segmented<AtomicU32>,        // Per-slot seqlock (odd while a writer holds the slot)
+    generations: Segmented<AtomicU32>, // Tracks slot validity
+    readers: Segmented<AtomicU32>,     // Live `ReadGuard`s per slot, plus RETIRED once removed under them
+    refs: Segmented<AtomicU64>,        // Generation the handles belong to (high half), handle count plus EVICTING (low half)
+    free: AtomicFreeList,              // Vacant slots
+    len: AtomicU32,                    // Number of active elements
+    grow_lock: Mutex<()>,              // Serializes `prepare_grow` calls
+}
+
+// Set in a slot's reader count by `remove`; the last guard out frees the slot
+const RETIRED: u32 = 1 << 31;
+
+// Held in a slot's handle count while remove_if_unreferenced evicts it
+const EVICTING: u64 = 1 << 31;
+const HANDLES: u64 = EVICTING - 1;
+
+// Handle word for `count` handles on the entry of generation `generation`
+fn handles(generation: u32, count: u64) -> u64 {
+    (generation as u64) << 32 | count
+}
+
+unsafe impl<T: Copy + Send> Sync for ConcurrentSlotMap<T> {}
+
+impl<T: Default + Copy> Default for ConcurrentSlotMap<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// First segment allocated by the prepare_grow calls of a map from `new`
+const CONCURRENT_BASE: u32 = 64;
+
+impl<T: Default + Copy> ConcurrentSlotMap<T> {
+    // No slots until prepare_grow adds some, so this can initialise a `static`:
+    // `static MAP: ConcurrentSlotMap<u64> = ConcurrentSlotMap::new();`
+    pub const fn new() -> Self {
+        Self {
+            free: AtomicFreeList::empty(CONCURRENT_BASE),
+            values: Segmented::empty(CONCURRENT_BASE),
+            seqs: Segmented::empty(CONCURRENT_BASE),
+            generations: Segmented::empty(CONCURRENT_BASE),
+            readers: Segmented::empty(CONCURRENT_BASE),
+            refs: Segmented::empty(CONCURRENT_BASE),
+            len: AtomicU32::new(0),
+            grow_lock: Mutex::new(()),
+        }
+    }
+
+    pub fn with_capacity(capacity: u32) -> Self {
+        Self {
+            free: AtomicFreeList::with_all_free(capacity),
+            values: Segmented::new(capacity, |_| UnsafeCell::new(T::default())),
+            seqs: Segmented::new(capacity, |_| AtomicU32::new(0)),
+            generations: Segmented::new(capacity, |_| AtomicU32::new(0)),
+            readers: Segmented::new(capacity, |_| AtomicU32::new(0)),
+            refs: Segmented::new(capacity, |_| AtomicU64::new(0)),
+            len: AtomicU32::new(0),
+            grow_lock: Mutex::new(()),
+        }
+    }
+
+    // Adds at least `additional` vacant slots, allocating them here so that
+    // concurrent inserts never do. Meant for a maintenance thread; other
+    // operations carry on while it runs. Existing slots never move. Returns
+    // the new capacity, which stops growing near `u32::MAX` slots.
+    pub fn prepare_grow(&self, additional: u32) -> u32 {
+        let _serialized = self.grow_lock.lock().unwrap();
+        let target = self.capacity().saturating_add(additional);
+        while self.capacity() < target && !injected_growth_failure() {
+            let Some((_, slots)) = self.values.next_segment() else { break };
+            self.readers.grow(|_| AtomicU32::new(0));
+            self.refs.grow(|_| AtomicU64::new(0));
+            self.seqs.grow(|_| AtomicU32::new(0));
+            self.generations.grow(|_| AtomicU32::new(0));
+            self.free.next.grow(|_| AtomicU32::new(u32::MAX));
+            // Lookups bounds-check against `values`, so it is published last
+            self.values.grow(|_| UnsafeCell::new(T::default()));
+            for index in slots.rev() {
+                self.free.push(index);
+            }
+        }
+        self.capacity()
+    }
+
+    // Returns `None` when every slot is taken
+    pub fn insert(&self, value: T) -> Option<Key> {
+        let index = self.free.pop()?;
+        let seq = self.lock(index);
+        unsafe { ptr::write_volatile(self.values[index as usize].get(), value) };
+        let generation = self.generations[index as usize].load(Ordering::Relaxed);
+        self.unlock(index, seq);
+        self.len.fetch_add(1, Ordering::Relaxed);
+        Some(Key::new(index, generation))
+    }
+
+    // Consistent snapshot of the value; retries while a writer holds the slot
+    pub fn read(&self, key: Key) -> Option<T> {
+        let (current, value) = self.read_slot(key.index)?;
+        (current == key).then_some(value)
+    }
+
+    // Key and value of whatever a slot holds, vacant slots included
+    fn read_slot(&self, index: u32) -> Option<(Key, T)> {
+        let slot = index as usize;
+        if slot >= self.values.len() {
+            return None;
+        }
+        loop {
+            let before = self.seqs[slot].load(Ordering::Acquire);
+            if before & 1 == 1 {
+                hint::spin_loop();
+                continue;
+            }
+            let generation = self.generations[slot].load(Ordering::Relaxed);
+            // Seqlock read: a racing write may tear this copy, in which case the
+            // sequence check below fails and the copy is discarded.
+            let value = unsafe { ptr::read_volatile(self.values[slot].get()) };
+            fence(Ordering::Acquire);
+            if self.seqs[slot].load(Ordering::Relaxed) == before {
+                return Some((Key::new(index, generation), value));
+            }
+        }
+    }
+
+    // Borrows the value in place. While the guard lives, `write` to this slot
+    // waits, and a `remove` keeps the slot out of reuse until the guard drops.
+    pub fn read_guard(&self, key: Key) -> Option<ReadGuard<'_, T>> {
+        let slot = key.index as usize;
+        if slot >= self.values.len() {
+            return None;
+        }
+        loop {
+            if self.readers[slot].fetch_add(1, Ordering::SeqCst) & RETIRED != 0 {
+                self.release_reader(key.index);
+                return None;
+            }
+            // Pairs with the fence in `write`: either it sees our count or we see its lock
+            if self.seqs[slot].load(Ordering::SeqCst) & 1 == 1 {
+                self.release_reader(key.index);
+                hint::spin_loop();
+                continue;
+            }
+            if self.generations[slot].load(Ordering::Acquire) != key.generation {
+                self.release_reader(key.index);
+                return None;
+            }
+            return Some(ReadGuard { map: self, index: key.index });
+        }
+    }
+
+    // Returns false if the key is stale. Waits for read guards on the slot to drop.
+    pub fn write(&self, key: Key, value: T) -> bool {
+        if key.index as usize >= self.values.len() {
+            return false;
+        }
+        let seq = self.lock(key.index);
+        fence(Ordering::SeqCst);
+        while self.readers[key.index as usize].load(Ordering::SeqCst) & !RETIRED != 0 {
+            hint::spin_loop();
+        }
+        let live = self.generations[key.index as usize].load(Ordering::Relaxed) == key.generation;
+        if live {
+            unsafe { ptr::write_volatile(self.values[key.index as usize].get(), value) };
+        }
+        self.unlock(key.index, seq);
+        live
+    }
+
+    pub fn remove(&self, key: Key) -> Option<T> {
+        let slot = key.index as usize;
+        if slot >= self.values.len() {
+            return None;
+        }
+        let seq = self.lock(key.index);
+        if self.generations[slot].load(Ordering::Relaxed) != key.generation {
+            self.unlock(key.index, seq);
+            return None;
+        }
+        let value = unsafe { ptr::read_volatile(self.values[slot].get()) };
+        self.generations[slot].store(key.generation.wrapping_add(1), Ordering::Relaxed);
+        self.unlock(key.index, seq);
+        self.len.fetch_sub(1, Ordering::Relaxed);
+        // Slots still borrowed by a guard are freed when the last one drops
+        if self.readers[slot].fetch_or(RETIRED, Ordering::SeqCst) == 0 {
+            self.free_retired(key.index);
+        }
+        Some(value)
+    }
+
+    pub fn contains(&self, key: Key) -> bool {
+        self.generations.get(key.index as usize).is_some_and(|g| g.load(Ordering::Acquire) == key.generation)
+    }
+
+    // Takes a shared handle on the entry, which keeps remove_if_unreferenced
+    // from evicting it until `release`. False if the key is stale. Plain
+    // `remove` ignores handles. Handles count against one generation of the
+    // slot, so ones left over from a removed entry never hold up the next.
+    pub fn acquire(&self, key: Key) -> bool {
+        let Some(refs) = self.refs.get(key.index as usize) else { return false };
+        let mut word = refs.load(Ordering::Relaxed);
+        let mut retries = 0u32;
+        loop {
+            let next = match word {
+                // Wait out the eviction, then find the key stale (or not)
+                _ if word & EVICTING != 0 => {
+                    hint::spin_loop();
+                    word = refs.load(Ordering::Relaxed);
+                    retries += 1;
+                    continue;
+                }
+                // Handles of an older entry; start this one's count over
+                _ if word >> 32 != key.generation as u64 => {
+                    if !self.contains(key) {
+                        return false;
+                    }
+                    handles(key.generation, 1)
+                }
+                _ => word + 1,
+            };
+            match weak_cas(word, || refs.compare_exchange_weak(word, next, Ordering::SeqCst, Ordering::Relaxed)) {
+                Ok(_) => break,
+                Err(current) => word = current,
+            }
+            retries += 1;
+        }
+        report_contention("slot acquire", retries);
+        // Counted before the check, so an eviction can't slip in between
+        if !self.contains(key) {
+            self.release(key);
+            return false;
+        }
+        true
+    }
+
+    // Gives back a handle taken with `acquire`. False, changing nothing, if
+    // no handle on this generation of the slot is held.
+    pub fn release(&self, key: Key) -> bool {
+        let Some(refs) = self.refs.get(key.index as usize) else { return false };
+        let taken = refs.fetch_update(Ordering::Release, Ordering::Relaxed, |word| {
+            (word >> 32 == key.generation as u64 && word & HANDLES > 0).then(|| word - 1)
+        });
+        taken.is_ok()
+    }
+
+    // Handles currently held on the entry, 0 if the key is stale
+    pub fn ref_count(&self, key: Key) -> u32 {
+        let Some(refs) = self.refs.get(key.index as usize) else { return 0 };
+        let word = refs.load(Ordering::Acquire);
+        match self.contains(key) && word >> 32 == key.generation as u64 {
+            true => (word & HANDLES) as u32,
+            false => 0,
+        }
+    }
+
+    // Removes the entry only if nobody holds a handle to it; `None` if the key
+    // is stale or the entry is referenced. A key that went stale while being
+    // acquired may count briefly, so a `None` here can be retried later.
+    pub fn remove_if_unreferenced(&self, key: Key) -> Option<T> {
+        let refs = self.refs.get(key.index as usize)?;
+        let word = refs.load(Ordering::Relaxed);
+        let held = word >> 32 == key.generation as u64 && word & HANDLES != 0;
+        if held || word & EVICTING != 0 || !self.contains(key) {
+            return None;
+        }
+        refs.compare_exchange(word, handles(key.generation, EVICTING), Ordering::SeqCst, Ordering::Relaxed).ok()?;
+        let value = self.remove(key);
+        refs.store(handles(key.generation, 0), Ordering::Release);
+        value
+    }
+
+    pub fn len(&self) -> u32 {
+        self.len.load(Ordering::Relaxed)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn capacity(&self) -> u32 {
+        self.values.len() as u32
+    }
+
+    // Every slot's value, vacant slots included
+    fn slot_values(&self) -> impl Iterator<Item = T> + '_ {
+        (0..self.capacity()).filter_map(|index| Some(self.read_slot(index)?.1))
+    }
+
+    // Spins until the slot's seqlock is taken, returning the (odd) locked sequence
+    fn lock(&self, index: u32) -> u32 {
+        let seq = &self.seqs[index as usize];
+        let mut retries = 0u32;
+        loop {
+            let current = seq.load(Ordering::Relaxed);
+            if current & 1 == 0 && weak_cas(current, || seq.compare_exchange_weak(current, current + 1, Ordering::Acquire, Ordering::Relaxed)).is_ok() {
+                report_contention("slot lock", retries);
+                return current + 1;
+            }
+            retries += 1;
+            hint::spin_loop();
+        }
+    }
+
+    fn unlock(&self, index: u32, seq: u32) {
+        self.seqs[index as usize].store(seq.wrapping_add(1), Ordering::Release);
+    }
+}
+
+impl<T> ConcurrentSlotMap<T> {
+    // Drops one reader; whoever sees the count of a removed slot reach zero frees it
+    fn release_reader(&self, index: u32) {
+        if self.readers[index as usize].fetch_sub(1, Ordering::SeqCst) == RETIRED | 1 {
+            self.free_retired(index);
+        }
+    }
+
+    fn free_retired(&self, index: u32) {
+        let readers = &self.readers[index as usize];
+        if readers.compare_exchange(RETIRED, 0, Ordering::SeqCst, Ordering::Relaxed).is_ok() {
+            self.free.push(index);
+        }
+    }
+}
+
+// Shared borrow of a `ConcurrentSlotMap` value, from `read_guard`
+pub struct ReadGuard<'a, T> {
+    map: &'a ConcurrentSlotMap<T>,
+    index: u32,
+}
+
+impl<T> std::ops::Deref for ReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // Writers wait for this guard and the slot can't be reused under it
+        unsafe { &*self.map.values[self.index as usize].get() }
+    }
+}
+
+impl<T> Drop for ReadGuard<'_, T> {
+    fn drop(&mut self) {
+        self.map.release_reader(self.index);
+    }
+}
+
+// Emits a trace event when a concurrent operation had to retry its CAS
+#[cfg(feature = "tracing")]
+fn report_contention(op: &'static str, retries: u32) {
+    if retries > 0 {
+        tracing::trace!(op, retries, "slot map CAS contended");
+    }
+}
+
+#[cfg(not(feature = "tracing"))]
+#[inline(always)]
+fn report_contention(_op: &'static str, _retries: u32) {}
+
+// Deterministic failures for testing recovery code (feature "fault-injection").
+// Counts are per thread, so tests running in parallel don't see each other's.
+#[cfg(feature = "fault-injection")]
+thread_local! {
+    static GROWTH_FAULTS: std::cell::Cell<u32> = const { std::cell::Cell::new(0) };
+    static CAS_FAULTS: std::cell::Cell<u32> = const { std::cell::Cell::new(0) };
+}
+
+// Makes the next `count` slot-array growths on this thread fail: fallible
+// calls (try_insert, try_reserve, ConcurrentSlotMap::prepare_grow) report it,
+// infallible ones panic as on a real allocation failure
+#[cfg(feature = "fault-injection")]
+pub fn inject_growth_failures(count: u32) {
+    GROWTH_FAULTS.with(|faults| faults.set(count));
+}
+
+// Makes the next `count` compare-and-swap attempts in the lock-free maps on
+// this thread fail as if another thread got there first, forcing retries
+#[cfg(feature = "fault-injection")]
+pub fn inject_cas_failures(count: u32) {
+    CAS_FAULTS.with(|faults| faults.set(count));
+}
+
+#[cfg(feature = "fault-injection")]
+fn take_fault(faults: &'static std::thread::LocalKey<std::cell::Cell<u32>>) -> bool {
+    faults.with(|faults| {
+        let left = faults.get();
+        faults.set(left.saturating_sub(1));
+        left > 0
+    })
+}
+
+#[inline(always)]
+fn injected_growth_failure() -> bool {
+    #[cfg(feature = "fault-injection")]
+    return take_fault(&GROWTH_FAULTS);
+    #[cfg(not(feature = "fault-injection"))]
+    false
+}
+
+// Runs a weak compare-and-swap, or fails it spuriously when one is injected
+#[inline(always)]
+fn weak_cas<V>(expected: V, cas: impl FnOnce() -> Result<V, V>) -> Result<V, V> {
+    #[cfg(feature = "fault-injection")]
+    if take_fault(&CAS_FAULTS) {
+        return Err(expected);
+    }
+    #[cfg(not(feature = "fault-injection"))]
+    let _ = expected;
+    cas()
+}
+
+// Array that grows by appending segments, so elements never move and indexing
+// takes no lock. Segment 0 holds `base` elements and segment k > 0 holds
+// elements base·2^(k-1) .. base·2^k. Only one thread may grow it at a time.
+struct Segmented<X> {
+    base: u32,
+    segments: [AtomicPtr<X>; 33], // First element of each allocated segment, null after the last
+    len: AtomicUsize,             // Elements in the allocated segments
+    _owns: PhantomData<Box<[X]>>,
+}
+
+impl<X> Segmented<X> {
+    fn new(len: u32, init: impl FnMut(u32) -> X) -> Self {
+        let array = Self::empty(len.max(1));
+        if len > 0 {
+            array.grow(init);
+        }
+        array
+    }
+
+    // No segments allocated; the first `grow` allocates `base` elements
+    const fn empty(base: u32) -> Self {
+        Self {
+            base,
+            segments: [const { AtomicPtr::new(ptr::null_mut()) }; 33],
+            len: AtomicUsize::new(0),
+            _owns: PhantomData,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.len.load(Ordering::Acquire)
+    }
+
+    fn get(&self, index: usize) -> Option<&X> {
+        (index < self.len()).then(|| &self[index])
+    }
+
+    // Segment the next `grow` would allocate and its index range, if that
+    // stays below `u32::MAX`
+    fn next_segment(&self) -> Option<(usize, Range<u32>)> {
+        let segment = self.segments.iter().position(|first| first.load(Ordering::Relaxed).is_null())?;
+        let range = self.segment_range(segment);
+        (range.end < u32::MAX as u64).then_some((segment, range.start as u32..range.end as u32))
+    }
+
+    // Allocates the next segment and publishes it
+    fn grow(&self, mut init: impl FnMut(u32) -> X) -> Option<Range<u32>> {
+        let (segment, range) = self.next_segment()?;
+        let elements: Box<[X]> = range.clone().map(&mut init).collect();
+        self.segments[segment].store(Box::into_raw(elements) as *mut X, Ordering::Release);
+        self.len.store(range.end as usize, Ordering::Release);
+        Some(range)
+    }
+
+    fn segment_range(&self, segment: usize) -> Range<u64> {
+        match segment {
+            0 => 0..self.base as u64,
+            _ => (self.base as u64) << (segment - 1)..(self.base as u64) << segment,
+        }
+    }
+
+    fn locate(&self, index: usize) -> (usize, usize) {
+        let quotient = index / self.base as usize;
+        let segment = (usize::BITS - quotient.leading_zeros()) as usize;
+        (segment, index - self.segment_range(segment).start as usize)
+    }
+}
+
+impl<X> Index<usize> for Segmented<X> {
+    type Output = X;
+
+    fn index(&self, index: usize) -> &X {
+        assert!(index < self.len(), "index {index} out of bounds for {} elements", self.len());
+        let (segment, offset) = self.locate(index);
+        // Segments are published before `len` covers them and freed only on drop
+        unsafe { &*self.segments[segment].load(Ordering::Acquire).add(offset) }
+    }
+}
+
+impl<X> Drop for Segmented<X> {
+    fn drop(&mut self) {
+        for (segment, first) in self.segments.iter().enumerate() {
+            let first = first.load(Ordering::Relaxed);
+            if !first.is_null() {
+                let range = self.segment_range(segment);
+                let len = (range.end - range.start) as usize;
+                drop(unsafe { Box::from_raw(ptr::slice_from_raw_parts_mut(first, len)) });
+            }
+        }
+    }
+}
+
+// Lock-free LIFO list of free slot indices. The head packs the first index
+// with a tag bumped on every update, so a pop racing with pop+push (ABA) fails.
+struct AtomicFreeList {
+    next: Segmented<AtomicU32>, // Link to the next free index, u32::MAX at the end
+    head: AtomicU64,        // First free index in the low half, ABA tag in the high half
+}
+
+impl AtomicFreeList {
+    const fn empty(base: u32) -> Self {
+        Self { next: Segmented::empty(base), head: AtomicU64::new(u32::MAX as u64) }
+    }
+
+    // Every slot starts free, lowest index first
+    fn with_all_free(capacity: u32) -> Self {
+        assert!(capacity < u32::MAX, "capacity must leave room for the free-list sentinel");
+        Self {
+            next: Segmented::new(capacity, |i| AtomicU32::new(if i + 1 < capacity { i + 1 } else { u32::MAX })),
+            head: AtomicU64::new(if capacity > 0 { 0 } else { u32::MAX as u64 }),
+        }
+    }
+
+    fn pop(&self) -> Option<u32> {
+        let mut head = self.head.load(Ordering::Acquire);
+        let mut retries = 0u32;
+        loop {
+            let index = head as u32;
+            if index == u32::MAX {
+                return None;
+            }
+            let next = self.next[index as usize].load(Ordering::Relaxed);
+            let tag = (head >> 32).wrapping_add(1);
+            match weak_cas(head, || self.head.compare_exchange_weak(head, (tag << 32) | next as u64, Ordering::Acquire, Ordering::Acquire)) {
+                Ok(_) => {
+                    report_contention("free-list pop", retries);
+                    return Some(index);
+                }
+                Err(current) => head = current,
+            }
+            retries += 1;
+        }
+    }
+
+    fn push(&self, index: u32) {
+        let mut head = self.head.load(Ordering::Relaxed);
+        let mut retries = 0u32;
+        loop {
+            self.next[index as usize].store(head as u32, Ordering::Relaxed);
+            let tag = (head >> 32).wrapping_add(1);
+            match weak_cas(head, || self.head.compare_exchange_weak(head, (tag << 32) | index as u64, Ordering::Release, Ordering::Relaxed)) {
+                Ok(_) => {
+                    report_contention("free-list push", retries);
+                    return;
+                }
+                Err(current) => head = current,
+            }
+            retries += 1;
+        }
+    }
+}
+
+// Concurrent map with multi-version reads: a `snapshot` sees every entry as
+// it was when the snapshot was taken, however long it lives, while writers
+// carry on. The newest version of each entry lives in a `ConcurrentSlotMap`
+// slot, so plain reads are seqlock reads; a write that replaces a version
+// some open snapshot can see moves the old one to a side table, from which
+// it is dropped once no snapshot can see it. Opening a snapshot takes no
+// lock. Writes are serialized with each other.
+pub struct MvccSlotMap<T> {
+    current: ConcurrentSlotMap<MvccCell<T>>, // Newest version of every entry
+    history: RwLock<BTreeMap<u32, Vec<MvccVersion<T>>>>, // Replaced versions open snapshots read, by slot
+    snapshots: ConcurrentSlotMap<u64>,       // Versions of open snapshots plus one; 0 in vacant slots
+    stamp: AtomicU64,                        // Twice the version of the last write, plus one during a write
+    writer: Mutex<()>,                       // Serializes writes
+}
+
+#[derive(Clone, Copy)]
+struct MvccCell<T> {
+    written: u64,     // Version that wrote it
+    value: Option<T>, // `None` once removed (and in slots never used)
+}
+
+impl<T> Default for MvccCell<T> {
+    fn default() -> Self {
+        MvccCell { written: 0, value: None }
+    }
+}
+
+// Value of an entry between the writes at `written` and `replaced`
+struct MvccVersion<T> {
+    generation: u32,
+    written: u64,
+    replaced: u64,
+    value: T,
+}
+
+impl<T: Copy + Send> MvccSlotMap<T> {
+    pub fn new() -> Self {
+        Self {
+            current: ConcurrentSlotMap::new(),
+            history: RwLock::new(BTreeMap::new()),
+            snapshots: ConcurrentSlotMap::new(),
+            stamp: AtomicU64::new(0),
+            writer: Mutex::new(()),
+        }
+    }
+
+    pub fn insert(&self, value: T) -> Key {
+        self.write_version(|version| insert_growing(&self.current, MvccCell { written: version, value: Some(value) }))
+    }
+
+    // Replaces the value for readers from now on; false if the key is stale
+    pub fn write(&self, key: Key, value: T) -> bool {
+        self.write_version(|version| self.replace(key, version, Some(value)).is_some())
+    }
+
+    // Snapshots taken earlier still see the entry
+    pub fn remove(&self, key: Key) -> Option<T> {
+        self.write_version(|version| {
+            let value = self.replace(key, version, None)?;
+            self.current.remove(key);
+            Some(value)
+        })
+    }
+
+    // Current value
+    pub fn get(&self, key: Key) -> Option<T> {
+        self.current.read(key)?.value
+    }
+
+    pub fn contains(&self, key: Key) -> bool {
+        self.get(key).is_some()
+    }
+
+    // Read view pinned to the current version. Waits out a write in progress,
+    // but never a lock.
+    pub fn snapshot(&self) -> MvccSnapshot<'_, T> {
+        loop {
+            let stamp = self.stamp.load(Ordering::SeqCst);
+            if stamp & 1 == 1 {
+                hint::spin_loop();
+                continue;
+            }
+            let (version, len) = (stamp / 2, self.current.len());
+            let registration = insert_growing(&self.snapshots, version + 1);
+            // Either the next write sees this registration before it drops a
+            // version, or this sees the write and takes a newer version
+            fence(Ordering::SeqCst);
+            if self.stamp.load(Ordering::SeqCst) == stamp {
+                return MvccSnapshot { map: self, version, len, registration };
+            }
+            self.unregister(registration);
+        }
+    }
+
+    pub fn len(&self) -> u32 {
+        self.current.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Older versions kept alive by snapshots, for watching reclamation
+    pub fn retained_versions(&self) -> usize {
+        self.history.read().unwrap().values().map(Vec::len).sum()
+    }
+
+    // Runs one write as the next version; the stamp is odd while it runs
+    fn write_version<R>(&self, write: impl FnOnce(u64) -> R) -> R {
+        let _serialized = self.writer.lock().unwrap();
+        let version = self.stamp.fetch_add(1, Ordering::SeqCst) / 2 + 1;
+        let result = write(version);
+        self.stamp.store(version * 2, Ordering::SeqCst);
+        result
+    }
+
+    // Overwrites a live entry at `version`, first keeping the old value for
+    // any open snapshot that can see it. Returns the old value.
+    fn replace(&self, key: Key, version: u64, value: Option<T>) -> Option<T> {
+        let old = self.current.read(key)?;
+        let previous = old.value?;
+        let mut history = self.history.write().unwrap();
+        fence(Ordering::SeqCst);
+        if self.open_versions().any(|open| open >= old.written) {
+            let replaced = MvccVersion { generation: key.generation, written: old.written, replaced: version, value: previous };
+            history.entry(key.index).or_default().push(replaced);
+        }
+        self.current.write(key, MvccCell { written: version, value });
+        Some(previous)
+    }
+
+    fn open_versions(&self) -> impl Iterator<Item = u64> + '_ {
+        self.snapshots.slot_values().filter(|&open| open != 0).map(|open| open - 1)
+    }
+
+    fn unregister(&self, registration: Key) {
+        self.snapshots.write(registration, 0);
+        self.snapshots.remove(registration);
+    }
+}
+
+impl<T: Copy + Send> Default for MvccSlotMap<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Inserts into a map that grows when full, for maps no other thread grows
+fn insert_growing<T: Default + Copy>(map: &ConcurrentSlotMap<T>, value: T) -> Key {
+    loop {
+        match map.insert(value) {
+            Some(key) => return key,
+            None => map.prepare_grow(map.capacity().max(1)),
+        };
+    }
+}
+
+// Read view of an `MvccSlotMap` from `snapshot`
+pub struct MvccSnapshot<'a, T: Copy + Send> {
+    map: &'a MvccSlotMap<T>,
+    version: u64,
+    len: u32,
+    registration: Key, // Entry in the map's snapshot registry
+}
+
+impl<T: Copy + Send> MvccSnapshot<'_, T> {
+    pub fn get(&self, key: Key) -> Option<T> {
+        if let Some(cell) = self.map.current.read(key).filter(|cell| cell.written <= self.version) {
+            return cell.value;
+        }
+        let history = self.map.history.read().unwrap();
+        self.visible(history.get(&key.index)?).filter(|version| version.generation == key.generation).map(|version| version.value)
+    }
+
+    pub fn contains(&self, key: Key) -> bool {
+        self.get(key).is_some()
+    }
+
+    // Entries as of the snapshot, in slot order. Older versions are looked up
+    // one batch of slots at a time, so writers can run between batches.
+    pub fn iter(&self) -> impl Iterator<Item = (Key, T)> + '_ {
+        const BATCH: u32 = 256;
+        let (mut next, mut batch) = (0, Vec::new());
+        std::iter::from_fn(move || loop {
+            if let Some(entry) = batch.pop() {
+                return Some(entry);
+            }
+            let slots = self.map.current.capacity();
+            if next >= slots {
+                return None;
+            }
+            let end = (next + BATCH).min(slots);
+            let history = self.map.history.read().unwrap();
+            for index in (next..end).rev() {
+                match self.map.current.read_slot(index) {
+                    Some((key, cell)) if cell.written <= self.version => batch.extend(cell.value.map(|value| (key, value))),
+                    _ => {
+                        let version = history.get(&index).and_then(|versions| self.visible(versions));
+                        batch.extend(version.map(|version| (Key::new(index, version.generation), version.value)));
+                    }
+                }
+            }
+            next = end;
+        })
+    }
+
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    pub fn len(&self) -> u32 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // The replaced version of a slot this snapshot reads, if any
+    fn visible<'v>(&self, versions: &'v [MvccVersion<T>]) -> Option<&'v MvccVersion<T>> {
+        versions.iter().find(|version| (version.written..version.replaced).contains(&self.version))
+    }
+}
+
+impl<T: Copy + Send> Drop for MvccSnapshot<'_, T> {
+    fn drop(&mut self) {
+        self.map.unregister(self.registration);
+        // Versions only this snapshot could see go now
+        let mut history = self.map.history.write().unwrap();
+        fence(Ordering::SeqCst);
+        let open: Vec<u64> = self.map.open_versions().collect();
+        history.retain(|_, versions| {
+            versions.retain(|version| open.iter().any(|open| (version.written..version.replaced).contains(open)));
+            !versions.is_empty()
+        });
+    }
+}
+
+// Lock-free multi-producer queue of values waiting to be inserted into a map
+// owned by one thread: workers `push`, the owner `flush`es them all in one
+// pass. Values go in in push order per producer.
+pub struct InsertQueue<T> {
+    head: AtomicPtr<QueuedValue<T>>, // Most recently pushed value, null when empty
+}
+
+struct QueuedValue<T> {
+    value: T,
+    next: *mut QueuedValue<T>, // Pushed just before this one
+}
+
+unsafe impl<T: Send> Send for InsertQueue<T> {}
+unsafe impl<T: Send> Sync for InsertQueue<T> {}
+
+impl<T> InsertQueue<T> {
+    pub const fn new() -> Self {
+        Self { head: AtomicPtr::new(ptr::null_mut()) }
+    }
+
+    pub fn push(&self, value: T) {
+        let node = Box::into_raw(Box::new(QueuedValue { value, next: ptr::null_mut() }));
+        let mut head = self.head.load(Ordering::Relaxed);
+        let mut retries = 0u32;
+        loop {
+            unsafe { (*node).next = head };
+            match weak_cas(head, || self.head.compare_exchange_weak(head, node, Ordering::Release, Ordering::Relaxed)) {
+                Ok(_) => break,
+                Err(current) => head = current,
+            }
+            retries += 1;
+        }
+        report_contention("insert queue push", retries);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.head.load(Ordering::Relaxed).is_null()
+    }
+
+    // Detaches everything pushed so far, oldest first. Taking the whole list
+    // at once (rather than popping) leaves no room for ABA.
+    fn take_all(&self) -> impl Iterator<Item = T> {
+        let mut newest = self.head.swap(ptr::null_mut(), Ordering::Acquire);
+        let mut oldest = ptr::null_mut();
+        while !newest.is_null() {
+            let next = unsafe { (*newest).next };
+            unsafe { (*newest).next = oldest };
+            oldest = newest;
+            newest = next;
+        }
+        std::iter::from_fn(move || {
+            if oldest.is_null() {
+                return None;
+            }
+            let node = unsafe { Box::from_raw(oldest) };
+            oldest = node.next;
+            Some(node.value)
+        })
+    }
+}
+
+impl<T: Default + Copy> InsertQueue<T> {
+    // Inserts every pending value, returning their keys in insertion order
+    pub fn flush<S: Storage<T>>(&self, map: &mut FastSlotMap<T, S>) -> Vec<Key> {
+        self.take_all().map(|value| map.insert(value)).collect()
+    }
+}
+
+impl<T> Default for InsertQueue<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Drop for InsertQueue<T> {
+    fn drop(&mut self) {
+        self.take_all().for_each(drop);
+    }
+}
+
+// Fixed-capacity concurrent map for values that own heap memory. Removed values
+// are retired to crossbeam's epoch collector and dropped once no thread that
+// could still be reading them is pinned.
+#[cfg(feature = "crossbeam-epoch")]
+pub struct EpochSlotMap<T> {
+    values: Box<[crossbeam_epoch::Atomic<T>]>, // Null while vacant
+    generations: Box<[AtomicU32]>,           // Tracks slot validity
+    free: AtomicFreeList,                    // Vacant slots
+    len: AtomicU32,                          // Number of active elements
+}
+
+#[cfg(feature = "crossbeam-epoch")]
+impl<T> EpochSlotMap<T> {
+    pub fn with_capacity(capacity: u32) -> Self {
+        Self {
+            free: AtomicFreeList::with_all_free(capacity),
+            values: (0..capacity).map(|_| crossbeam_epoch::Atomic::null()).collect(),
+            generations: (0..capacity).map(|_| AtomicU32::new(0)).collect(),
+            len: AtomicU32::new(0),
+        }
+    }
+
+    // Returns `None` when every slot is taken
+    pub fn insert(&self, value: T) -> Option<Key> {
+        let index = self.free.pop()?;
+        self.values[index as usize].store(crossbeam_epoch::Owned::new(value), Ordering::Release);
+        self.len.fetch_add(1, Ordering::Relaxed);
+        Some(Key::new(index, self.generations[index as usize].load(Ordering::Relaxed)))
+    }
+
+    // The reference stays valid for as long as `guard` is pinned, even if the
+    // entry is removed or replaced meanwhile
+    pub fn get<'g>(&self, key: Key, guard: &'g crossbeam_epoch::Guard) -> Option<&'g T> {
+        let generation = self.generations.get(key.index as usize)?;
+        if generation.load(Ordering::Acquire) != key.generation {
+            return None;
+        }
+        let value = self.values[key.index as usize].load(Ordering::Acquire, guard);
+        // Re-check: the slot may have been removed and refilled since
+        if generation.load(Ordering::Acquire) != key.generation {
+            return None;
+        }
+        unsafe { value.as_ref() }
+    }
+
+    // Pins the current thread for the duration of `f`
+    pub fn read<R>(&self, key: Key, f: impl FnOnce(&T) -> R) -> Option<R> {
+        let guard = crossbeam_epoch::pin();
+        self.get(key, &guard).map(f)
+    }
+
+    // Replaces the value, retiring the old one. Returns false if the key is stale.
+    pub fn write(&self, key: Key, value: T) -> bool {
+        let guard = crossbeam_epoch::pin();
+        let mut new = crossbeam_epoch::Owned::new(value);
+        loop {
+            let current = match self.get(key, &guard) {
+                Some(_) => self.values[key.index as usize].load(Ordering::Acquire, &guard),
+                None => return false,
+            };
+            let slot = &self.values[key.index as usize];
+            match slot.compare_exchange(current, new, Ordering::AcqRel, Ordering::Acquire, &guard) {
+                Ok(_) => {
+                    unsafe { guard.defer_destroy(current) };
+                    return true;
+                }
+                Err(err) => new = err.new,
+            }
+        }
+    }
+
+    // Unlinks the entry and retires its value; it is dropped once no pinned
+    // thread can still see it
+    pub fn remove(&self, key: Key) -> bool {
+        let Some(generation) = self.generations.get(key.index as usize) else {
+            return false;
+        };
+        if generation.compare_exchange(key.generation, key.generation.wrapping_add(1), Ordering::AcqRel, Ordering::Relaxed).is_err() {
+            return false;
+        }
+        let guard = crossbeam_epoch::pin();
+        let value = self.values[key.index as usize].swap(crossbeam_epoch::Shared::null(), Ordering::AcqRel, &guard);
+        unsafe { guard.defer_destroy(value) };
+        self.len.fetch_sub(1, Ordering::Relaxed);
+        self.free.push(key.index);
+        true
+    }
+
+    pub fn contains(&self, key: Key) -> bool {
+        self.generations.get(key.index as usize).is_some_and(|g| g.load(Ordering::Acquire) == key.generation)
+            && !self.values[key.index as usize].load(Ordering::Acquire, &crossbeam_epoch::pin()).is_null()
+    }
+
+    pub fn len(&self) -> u32 {
+        self.len.load(Ordering::Relaxed)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn capacity(&self) -> u32 {
+        self.values.len() as u32
+    }
+}
+
+#[cfg(feature = "crossbeam-epoch")]
+impl<T> Drop for EpochSlotMap<T> {
+    fn drop(&mut self) {
+        // `&mut self`: no other thread can hold a reference
+        let guard = unsafe { crossbeam_epoch::unprotected() };
+        for slot in self.values.iter() {
+            let value = slot.load(Ordering::Relaxed, guard);
+            if !value.is_null() {
+                drop(unsafe { value.into_owned() });
+            }
+        }
+    }
+}
+
+// Values that round-trip through 32 bits, so a slot's generation and value
+// fit in a single atomic word
+pub trait AtomicValue: Copy {
+    fn to_bits(self) -> u32;
+    fn from_bits(bits: u32) -> Self;
+}
+
+macro_rules! impl_atomic_value {
+    ($($ty:ty),*) => {
+        $(impl AtomicValue for $ty {
+            fn to_bits(self) -> u32 {
+                self as u32
+            }
+
+            fn from_bits(bits: u32) -> Self {
+                bits as $ty
+            }
+        })*
+    };
+}
+
+impl_atomic_value!(u8, u16, u32, i8, i16, i32);
+
+impl AtomicValue for f32 {
+    fn to_bits(self) -> u32 {
+        f32::to_bits(self)
+    }
+
+    fn from_bits(bits: u32) -> Self {
+        f32::from_bits(bits)
+    }
+}
+
+impl AtomicValue for bool {
+    fn to_bits(self) -> u32 {
+        self as u32
+    }
+
+    fn from_bits(bits: u32) -> Self {
+        bits != 0
+    }
+}
+
+// Fixed-capacity map where every operation is lock-free and nothing allocates
+// after construction, so it is safe to use from signal or interrupt handlers.
+// Each slot is one atomic word holding (generation, value); odd generations
+// mark live slots. Larger `Copy` values need `ConcurrentSlotMap` instead.
+pub struct AtomicSlotMap<T> {
+    slots: Box<[AtomicU64]>, // Generation in the high half, value bits in the low half
+    free: AtomicFreeList,    // Vacant slots
+    len: AtomicU32,          // Number of active elements
+    _values: PhantomData<T>,
+}
+
+impl<T: AtomicValue> AtomicSlotMap<T> {
+    pub fn with_capacity(capacity: u32) -> Self {
+        Self {
+            free: AtomicFreeList::with_all_free(capacity),
+            slots: (0..capacity).map(|_| AtomicU64::new(0)).collect(),
+            len: AtomicU32::new(0),
+            _values: PhantomData,
+        }
+    }
+
+    // Returns `None` when every slot is taken
+    pub fn insert(&self, value: T) -> Option<Key> {
+        let index = self.free.pop()?;
+        let slot = &self.slots[index as usize];
+        // The slot is ours alone until the key is handed out
+        let generation = ((slot.load(Ordering::Relaxed) >> 32) as u32).wrapping_add(1);
+        slot.store(Self::pack(generation, value), Ordering::Release);
+        self.len.fetch_add(1, Ordering::Relaxed);
+        Some(Key::new(index, generation))
+    }
+
+    pub fn get(&self, key: Key) -> Option<T> {
+        let word = self.slots.get(key.index as usize)?.load(Ordering::Acquire);
+        Self::is_live(word, key).then(|| T::from_bits(word as u32))
+    }
+
+    // Returns false if the key is stale
+    pub fn write(&self, key: Key, value: T) -> bool {
+        let Some(slot) = self.slots.get(key.index as usize) else { return false };
+        let mut word = slot.load(Ordering::Relaxed);
+        loop {
+            if !Self::is_live(word, key) {
+                return false;
+            }
+            match weak_cas(word, || slot.compare_exchange_weak(word, Self::pack(key.generation, value), Ordering::Release, Ordering::Relaxed)) {
+                Ok(_) => return true,
+                Err(current) => word = current,
+            }
+        }
+    }
+
+    pub fn remove(&self, key: Key) -> Option<T> {
+        let slot = self.slots.get(key.index as usize)?;
+        let mut word = slot.load(Ordering::Acquire);
+        loop {
+            if !Self::is_live(word, key) {
+                return None;
+            }
+            let vacant = (key.generation.wrapping_add(1) as u64) << 32;
+            match weak_cas(word, || slot.compare_exchange_weak(word, vacant, Ordering::AcqRel, Ordering::Acquire)) {
+                Ok(_) => break,
+                Err(current) => word = current,
+            }
+        }
+        self.len.fetch_sub(1, Ordering::Relaxed);
+        self.free.push(key.index);
+        Some(T::from_bits(word as u32))
+    }
+
+    pub fn contains(&self, key: Key) -> bool {
+        self.get(key).is_some()
+    }
+
+    pub fn len(&self) -> u32 {
+        self.len.load(Ordering::Relaxed)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn capacity(&self) -> u32 {
+        self.slots.len() as u32
+    }
+
+    fn is_live(word: u64, key: Key) -> bool {
+        (word >> 32) as u32 == key.generation && key.generation & 1 == 1
+    }
+
+    fn pack(generation: u32, value: T) -> u64 {
+        ((generation as u64) << 32) | value.to_bits() as u64
+    }
+}
+
+// Time source for `IdleSlotMap`. Units are up to the caller (ticks, ms, ...);
+// any `Fn() -> u64` works, so no particular clock is assumed.
+pub trait Clock {
+    fn now(&self) -> u64;
+}
+
+impl<F: Fn() -> u64> Clock for F {
+    fn now(&self) -> u64 {
+        self()
+    }
+}
+
+// Milliseconds since the clock was created
+pub struct StdClock(std::time::Instant);
+
+impl StdClock {
+    pub fn new() -> Self {
+        StdClock(std::time::Instant::now())
+    }
+}
+
+impl Default for StdClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for StdClock {
+    fn now(&self) -> u64 {
+        self.0.elapsed().as_millis() as u64
+    }
+}
+
+// Slot map that records when each entry was last accessed, for expiring idle
+// entries. `get` takes `&self`; stamps are relaxed atomics so the map stays `Sync`.
+pub struct IdleSlotMap<T, C> {
+    map: FastSlotMap<T>,
+    clock: C,
+    last_access: Vec<AtomicU64>, // Per-slot time of the last insert or access
+}
+
+impl<T: Default + Copy, C: Clock> IdleSlotMap<T, C> {
+    pub fn new(clock: C) -> Self {
+        Self { map: FastSlotMap::new(), clock, last_access: Vec::new() }
+    }
+
+    pub fn insert(&mut self, value: T) -> Key {
+        let key = self.map.insert(value);
+        let slot = key.index as usize;
+        if slot >= self.last_access.len() {
+            self.last_access.resize_with(slot + 1, || AtomicU64::new(0));
+        }
+        self.last_access[slot].store(self.clock.now(), Ordering::Relaxed);
+        key
+    }
+
+    // Counts as an access
+    pub fn get(&self, key: Key) -> Option<&T> {
+        let value = self.map.get(key)?;
+        self.stamp(key);
+        Some(value)
+    }
+
+    pub fn get_mut(&mut self, key: Key) -> Option<&mut T> {
+        let value = self.map.get_mut(key)?;
+        if let Some(stamp) = self.last_access.get(key.index as usize) {
+            stamp.store(self.clock.now(), Ordering::Relaxed);
+        }
+        Some(value)
+    }
+
+    // Reads without counting as an access
+    pub fn peek(&self, key: Key) -> Option<&T> {
+        self.map.get(key)
+    }
+
+    // Marks the entry as accessed now; false if the key is stale
+    pub fn touch(&self, key: Key) -> bool {
+        if !self.map.contains(key) {
+            return false;
+        }
+        self.stamp(key);
+        true
+    }
+
+    pub fn last_access(&self, key: Key) -> Option<u64> {
+        self.map.contains(key).then(|| self.last_access[key.index as usize].load(Ordering::Relaxed))
+    }
+
+    pub fn remove(&mut self, key: Key) -> Option<T> {
+        self.map.remove(key)
+    }
+
+    // Removes every entry not accessed for more than `older_than` clock units
+    pub fn remove_idle(&mut self, older_than: u64) -> Vec<(Key, T)> {
+        let now = self.clock.now();
+        let idle: Vec<Key> = self
+            .map
+            .iter()
+            .map(|(key, _)| key)
+            .filter(|key| now.saturating_sub(self.last_access[key.index as usize].load(Ordering::Relaxed)) > older_than)
+            .collect();
+        idle.into_iter().filter_map(|key| Some((key, self.map.remove(key)?))).collect()
+    }
+
+    pub fn contains(&self, key: Key) -> bool {
+        self.map.contains(key)
+    }
+
+    pub fn len(&self) -> u32 {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    pub fn as_map(&self) -> &FastSlotMap<T> {
+        &self.map
+    }
+
+    fn stamp(&self, key: Key) {
+        if let Some(stamp) = self.last_access.get(key.index as usize) {
+            stamp.store(self.clock.now(), Ordering::Relaxed);
+        }
+    }
+}
+
+// FNV-1a with integers fed little-endian, so hashes match across platforms
+struct StableHasher(u64);
+
+impl std::hash::Hasher for StableHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 = (self.0 ^ byte as u64).wrapping_mul(0x100_0000_01b3);
+        }
+    }
+
+    fn write_u16(&mut self, i: u16) {
+        self.write(&i.to_le_bytes())
+    }
+
+    fn write_u32(&mut self, i: u32) {
+        self.write(&i.to_le_bytes())
+    }
+
+    fn write_u64(&mut self, i: u64) {
+        self.write(&i.to_le_bytes())
+    }
+
+    fn write_u128(&mut self, i: u128) {
+        self.write(&i.to_le_bytes())
+    }
+
+    fn write_usize(&mut self, i: usize) {
+        self.write_u64(i as u64)
+    }
+}
+
+fn entry_hash<T: Hash>(key: Key, value: &T) -> u64 {
+    use std::hash::Hasher;
+    let mut hasher = StableHasher(0xcbf2_9ce4_8422_2325);
+    hasher.write_u32(key.index);
+    hasher.write_u32(key.generation);
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+// Slot map that maintains `content_hash` incrementally, so reading it is O(1)
+pub struct HashedSlotMap<T> {
+    map: FastSlotMap<T>,
+    hash: u64, // Wrapping sum of every live entry's hash
+}
+
+impl<T: Default + Copy + Hash> HashedSlotMap<T> {
+    pub fn new() -> Self {
+        Self { map: FastSlotMap::new(), hash: 0 }
+    }
+
+    pub fn insert(&mut self, value: T) -> Key {
+        let key = self.map.insert(value);
+        self.hash = self.hash.wrapping_add(entry_hash(key, &value));
+        key
+    }
+
+    pub fn get(&self, key: Key) -> Option<&T> {
+        self.map.get(key)
+    }
+
+    // Mutates the entry in place and rehashes it
+    pub fn update<R>(&mut self, key: Key, f: impl FnOnce(&mut T) -> R) -> Option<R> {
+        let value = self.map.get_mut(key)?;
+        let old = entry_hash(key, value);
+        let result = f(value);
+        self.hash = self.hash.wrapping_sub(old).wrapping_add(entry_hash(key, value));
+        Some(result)
+    }
+
+    pub fn remove(&mut self, key: Key) -> Option<T> {
+        let value = self.map.remove(key)?;
+        self.hash = self.hash.wrapping_sub(entry_hash(key, &value));
+        Some(value)
+    }
+
+    // Same value `FastSlotMap::content_hash` would compute
+    pub fn content_hash(&self) -> u64 {
+        self.hash
+    }
+
+    pub fn contains(&self, key: Key) -> bool {
+        self.map.contains(key)
+    }
+
+    pub fn len(&self) -> u32 {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    pub fn as_map(&self) -> &FastSlotMap<T> {
+        &self.map
+    }
+}
+
+impl<T: Default + Copy + Hash> Default for HashedSlotMap<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Slot map that numbers entries in insertion order, so saves list them the
+// same way however slots were reused
+pub struct SequencedSlotMap<T> {
+    map: FastSlotMap<T>,
+    sequences: Vec<u64>, // Sequence id of the entry in each slot
+    next_sequence: u64,
+}
+
+impl<T: Default + Copy> SequencedSlotMap<T> {
+    pub fn new() -> Self {
+        Self { map: FastSlotMap::new(), sequences: Vec::new(), next_sequence: 0 }
+    }
+
+    pub fn insert(&mut self, value: T) -> Key {
+        let key = self.map.insert(value);
+        self.stamp(key, self.next_sequence);
+        self.next_sequence += 1;
+        key
+    }
+
+    pub fn get(&self, key: Key) -> Option<&T> {
+        self.map.get(key)
+    }
+
+    pub fn get_mut(&mut self, key: Key) -> Option<&mut T> {
+        self.map.get_mut(key)
+    }
+
+    pub fn remove(&mut self, key: Key) -> Option<T> {
+        self.map.remove(key)
+    }
+
+    pub fn contains(&self, key: Key) -> bool {
+        self.map.contains(key)
+    }
+
+    // Position of the entry in insertion order; never reused
+    pub fn sequence(&self, key: Key) -> Option<u64> {
+        self.map.contains(key).then(|| self.sequences[key.index as usize])
+    }
+
+    // Live entries oldest first
+    pub fn iter_in_sequence(&self) -> impl Iterator<Item = (u64, Key, &T)> + '_ {
+        let mut entries: Vec<(u64, Key, &T)> =
+            self.map.iter().map(|(key, value)| (self.sequences[key.index as usize], key, value)).collect();
+        entries.sort_unstable_by_key(|&(sequence, _, _)| sequence);
+        entries.into_iter()
+    }
+
+    // Writes the generation of every slot, then the entries in sequence
+    // order, each with its exact key:
+    //   [next sequence (u64 LE), slot count (u32 LE), generations (u32 LE each),
+    //    entry count (u32 LE), entries...]
+    //   entry: [sequence (u64 LE), key (stable bytes), value length (u32 LE), value]
+    pub fn save(&self, mut encode: impl FnMut(&T, &mut Vec<u8>)) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(&self.next_sequence.to_le_bytes());
+        out.extend_from_slice(&(self.map.generations.len() as u32).to_le_bytes());
+        for generation in &self.map.generations {
+            out.extend_from_slice(&generation.to_le_bytes());
+        }
+        out.extend_from_slice(&self.map.len().to_le_bytes());
+        let mut value_bytes = Vec::new();
+        for (sequence, key, value) in self.iter_in_sequence() {
+            value_bytes.clear();
+            encode(value, &mut value_bytes);
+            out.extend_from_slice(&sequence.to_le_bytes());
+            out.extend_from_slice(&key.to_stable_bytes());
+            out.extend_from_slice(&(value_bytes.len() as u32).to_le_bytes());
+            out.extend_from_slice(&value_bytes);
+        }
+        out
+    }
+
+    // Rebuilds a map written by `save`; keys saved with it resolve again and
+    // keys to vacant slots stay stale. Returns `None` for truncated or
+    // inconsistent input, which is checked in full before anything is
+    // allocated for the slots.
+    pub fn load(bytes: &[u8], mut decode: impl FnMut(&[u8]) -> Option<T>) -> Option<Self> {
+        let mut bytes = bytes;
+        let next_sequence = u64::from_le_bytes(take_bytes(&mut bytes, 8)?.try_into().ok()?);
+        let slot_count = u32::from_le_bytes(take_bytes(&mut bytes, 4)?.try_into().ok()?) as usize;
+        if slot_count >= TOMBSTONE as usize {
+            return None;
+        }
+        // The table is 4 bytes per slot, so the slot count is bounded by the input
+        let table = take_bytes(&mut bytes, slot_count.checked_mul(4)?)?;
+        let mut slots: Vec<SavedSlot> =
+            table.chunks_exact(4).map(|generation| (u32::from_le_bytes(generation.try_into().unwrap()), None)).collect();
+        let count = u32::from_le_bytes(take_bytes(&mut bytes, 4)?.try_into().ok()?) as usize;
+        if count > slot_count {
+            return None;
+        }
+        let mut sequences = vec![0; slot_count];
+        for _ in 0..count {
+            let sequence = u64::from_le_bytes(take_bytes(&mut bytes, 8)?.try_into().ok()?);
+            let key = Key::from_stable_bytes(take_bytes(&mut bytes, STABLE_KEY_LEN)?)?;
+            let len = u32::from_le_bytes(take_bytes(&mut bytes, 4)?.try_into().ok()?);
+            let value = take_bytes(&mut bytes, len as usize)?;
+            let slot = slots.get_mut(key.index as usize)?;
+            if sequence >= next_sequence || slot.0 != key.generation || slot.1.is_some() {
+                return None;
+            }
+            slot.1 = Some(value);
+            sequences[key.index as usize] = sequence;
+        }
+        if !bytes.is_empty() {
+            return None;
+        }
+        let map = FastSlotMap::from_saved(&slots, |values, slots| decode_slots(values, slots, &mut decode))?;
+        Some(Self { map, sequences, next_sequence })
+    }
+
+    pub fn len(&self) -> u32 {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    pub fn as_map(&self) -> &FastSlotMap<T> {
+        &self.map
+    }
+
+    fn stamp(&mut self, key: Key, sequence: u64) {
+        let index = key.index as usize;
+        if self.sequences.len() <= index {
+            self.sequences.resize(index + 1, 0);
+        }
+        self.sequences[index] = sequence;
+    }
+}
+
+impl<T: Default + Copy> Default for SequencedSlotMap<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct QuotaId(u32);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QuotaUsage {
+    pub name: String,
+    pub used: u32,
+    pub limit: u32,
+}
+
+// Slot map shared by several subsystems, each limited to a number of live
+// entries so one can't starve the others. Entries inserted without a quota
+// are not counted against any.
+pub struct QuotaSlotMap<T> {
+    map: FastSlotMap<T>,
+    quotas: Vec<QuotaUsage>,
+    owners: Vec<u32>, // Quota charged for the entry in each slot, u32::MAX for none
+}
+
+impl<T: Default + Copy> QuotaSlotMap<T> {
+    pub fn new() -> Self {
+        Self { map: FastSlotMap::new(), quotas: Vec::new(), owners: Vec::new() }
+    }
+
+    pub fn add_quota(&mut self, name: impl Into<String>, limit: u32) -> QuotaId {
+        self.quotas.push(QuotaUsage { name: name.into(), used: 0, limit });
+        QuotaId(self.quotas.len() as u32 - 1)
+    }
+
+    // Lowering a limit below current usage only blocks further inserts
+    pub fn set_quota_limit(&mut self, quota: QuotaId, limit: u32) {
+        self.quotas[quota.0 as usize].limit = limit;
+    }
+
+    pub fn insert(&mut self, value: T) -> Key {
+        let key = self.map.insert(value);
+        self.set_owner(key, u32::MAX);
+        key
+    }
+
+    // `None` when the quota is exhausted
+    pub fn insert_for(&mut self, quota: QuotaId, value: T) -> Option<Key> {
+        let usage = &mut self.quotas[quota.0 as usize];
+        if usage.used >= usage.limit {
+            return None;
+        }
+        usage.used += 1;
+        let key = self.map.insert(value);
+        self.set_owner(key, quota.0);
+        Some(key)
+    }
+
+    pub fn get(&self, key: Key) -> Option<&T> {
+        self.map.get(key)
+    }
+
+    pub fn get_mut(&mut self, key: Key) -> Option<&mut T> {
+        self.map.get_mut(key)
+    }
+
+    pub fn remove(&mut self, key: Key) -> Option<T> {
+        let value = self.map.remove(key)?;
+        if let Some(usage) = self.quotas.get_mut(self.owners[key.index as usize] as usize) {
+            usage.used -= 1;
+        }
+        Some(value)
+    }
+
+    pub fn contains(&self, key: Key) -> bool {
+        self.map.contains(key)
+    }
+
+    // Quota the entry is counted against
+    pub fn quota_of(&self, key: Key) -> Option<QuotaId> {
+        let owner = *self.owners.get(key.index as usize)?;
+        (self.map.contains(key) && owner != u32::MAX).then_some(QuotaId(owner))
+    }
+
+    pub fn quota_usage(&self, quota: QuotaId) -> &QuotaUsage {
+        &self.quotas[quota.0 as usize]
+    }
+
+    // Every quota in registration order
+    pub fn quota_stats(&self) -> impl Iterator<Item = (QuotaId, &QuotaUsage)> + '_ {
+        self.quotas.iter().enumerate().map(|(id, usage)| (QuotaId(id as u32), usage))
+    }
+
+    pub fn len(&self) -> u32 {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    pub fn as_map(&self) -> &FastSlotMap<T> {
+        &self.map
+    }
+
+    fn set_owner(&mut self, key: Key, owner: u32) {
+        let index = key.index as usize;
+        if self.owners.len() <= index {
+            self.owners.resize(index + 1, u32::MAX);
+        }
+        self.owners[index] = owner;
+    }
+}
+
+impl<T: Default + Copy> Default for QuotaSlotMap<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Slot map mirroring data owned by external ids (e.g. ECS entities), keeping
+// the id <-> key mapping in both directions. Each id has at most one entry.
+pub struct SyncedSlotMap<T, E> {
+    map: FastSlotMap<T>,
+    keys: HashMap<E, Key>,
+    ids: SecondaryMap<E>,
+}
+
+impl<T: Default + Copy, E: Copy + Eq + Hash> SyncedSlotMap<T, E> {
+    pub fn new() -> Self {
+        Self { map: FastSlotMap::new(), keys: HashMap::new(), ids: SecondaryMap::new() }
+    }
+
+    // Replaces the id's previous entry, if any
+    pub fn insert(&mut self, id: E, value: T) -> Key {
+        self.remove(id);
+        let key = self.map.insert(value);
+        self.keys.insert(id, key);
+        self.ids.insert(key, id);
+        key
+    }
+
+    pub fn get(&self, id: E) -> Option<&T> {
+        self.map.get(*self.keys.get(&id)?)
+    }
+
+    pub fn get_mut(&mut self, id: E) -> Option<&mut T> {
+        self.map.get_mut(*self.keys.get(&id)?)
+    }
+
+    pub fn key_of(&self, id: E) -> Option<Key> {
+        self.keys.get(&id).copied()
+    }
+
+    pub fn id_of(&self, key: Key) -> Option<E> {
+        self.ids.get(key).copied()
+    }
+
+    pub fn get_by_key(&self, key: Key) -> Option<&T> {
+        self.map.get(key)
+    }
+
+    // Call when the id goes away (e.g. the entity despawned)
+    pub fn remove(&mut self, id: E) -> Option<T> {
+        let key = self.keys.remove(&id)?;
+        self.ids.remove(key);
+        self.map.remove(key)
+    }
+
+    pub fn remove_by_key(&mut self, key: Key) -> Option<T> {
+        let id = self.ids.remove(key)?;
+        self.keys.remove(&id);
+        self.map.remove(key)
+    }
+
+    // Removes the entries of every id for which `exists` is false (e.g. entities
+    // no longer in the world), returning how many were removed
+    pub fn retain_ids(&mut self, mut exists: impl FnMut(E) -> bool) -> u32 {
+        let gone: Vec<E> = self.keys.keys().copied().filter(|&id| !exists(id)).collect();
+        for &id in &gone {
+            self.remove(id);
+        }
+        gone.len() as u32
+    }
+
+    pub fn contains(&self, id: E) -> bool {
+        self.keys.contains_key(&id)
+    }
+
+    pub fn len(&self) -> u32 {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    pub fn as_map(&self) -> &FastSlotMap<T> {
+        &self.map
+    }
+}
+
+impl<T: Default + Copy, E: Copy + Eq + Hash> Default for SyncedSlotMap<T, E> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Slot map for `(Hot, Cold)` entries that keeps the two halves in parallel
+// arrays, so passes over the hot half don't pull cold bytes into cache. One
+// key addresses both halves.
+pub struct HotColdSlotMap<H, C> {
+    hot: FastSlotMap<H>,
+    cold: Vec<C>, // Indexed by slot, like the hot map's values
+}
+
+impl<H: Default + Copy, C: Default> HotColdSlotMap<H, C> {
+    pub fn new() -> Self {
+        Self { hot: FastSlotMap::new(), cold: Vec::new() }
+    }
+
+    pub fn insert(&mut self, (hot, cold): (H, C)) -> Key {
+        let key = self.hot.insert(hot);
+        let index = key.index as usize;
+        if self.cold.len() <= index {
+            self.cold.resize_with(index + 1, C::default);
+        }
+        self.cold[index] = cold;
+        key
+    }
+
+    pub fn get(&self, key: Key) -> Option<(&H, &C)> {
+        let hot = self.hot.get(key)?;
+        Some((hot, &self.cold[key.index as usize]))
+    }
+
+    pub fn get_mut(&mut self, key: Key) -> Option<(&mut H, &mut C)> {
+        let hot = self.hot.get_mut(key)?;
+        Some((hot, &mut self.cold[key.index as usize]))
+    }
+
+    pub fn hot(&self, key: Key) -> Option<&H> {
+        self.hot.get(key)
+    }
+
+    pub fn hot_mut(&mut self, key: Key) -> Option<&mut H> {
+        self.hot.get_mut(key)
+    }
+
+    pub fn cold(&self, key: Key) -> Option<&C> {
+        self.get(key).map(|(_, cold)| cold)
+    }
+
+    pub fn cold_mut(&mut self, key: Key) -> Option<&mut C> {
+        self.get_mut(key).map(|(_, cold)| cold)
+    }
+
+    // The cold half is taken out of its slot, so nothing it owns outlives the entry
+    pub fn remove(&mut self, key: Key) -> Option<(H, C)> {
+        let hot = self.hot.remove(key)?;
+        Some((hot, std::mem::take(&mut self.cold[key.index as usize])))
+    }
+
+    pub fn contains(&self, key: Key) -> bool {
+        self.hot.contains(key)
+    }
+
+    // Touches only the hot array
+    pub fn iter_hot(&self) -> Iter<'_, H> {
+        self.hot.iter()
+    }
+
+    pub fn iter_hot_mut(&mut self) -> IterMut<'_, H> {
+        self.hot.iter_mut()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (Key, &H, &C)> + '_ {
+        self.hot.iter().map(|(key, hot)| (key, hot, &self.cold[key.index as usize]))
+    }
+
+    pub fn len(&self) -> u32 {
+        self.hot.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hot.is_empty()
+    }
+
+    // The hot half on its own
+    pub fn as_map(&self) -> &FastSlotMap<H> {
+        &self.hot
+    }
+}
+
+impl<H: Default + Copy, C: Default> Default for HotColdSlotMap<H, C> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Value of a `SplitSlotMap`: the common small case or a rarer large one.
+// An enum value type converts to and from this, e.g. with `From` impls.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Split<S, L> {
+    Small(S),
+    Large(L),
+}
+
+// Slot of a `SplitSlotMap`: the small value itself or where its large one is pooled
+#[derive(Copy, Clone)]
+enum Packed<S> {
+    Small(S),
+    Large(u32),
+}
+
+impl<S: Default> Default for Packed<S> {
+    fn default() -> Self {
+        Packed::Small(S::default())
+    }
+}
+
+// Slot map for enum-like values where a small variant dominates. Small values
+// sit in the slot array, which is sized to them; large ones live in a side
+// pool the slot points into. One key space covers both.
+pub struct SplitSlotMap<S, L> {
+    slots: FastSlotMap<Packed<S>>,
+    large: Vec<Option<L>>,
+    large_free: Vec<u32>, // Vacant positions in `large`
+}
+
+impl<S: Default + Copy, L> SplitSlotMap<S, L> {
+    pub fn new() -> Self {
+        Self { slots: FastSlotMap::new(), large: Vec::new(), large_free: Vec::new() }
+    }
+
+    pub fn insert(&mut self, value: Split<S, L>) -> Key {
+        let packed = self.pack(value);
+        self.slots.insert(packed)
+    }
+
+    pub fn get(&self, key: Key) -> Option<Split<&S, &L>> {
+        match self.slots.get(key)? {
+            Packed::Small(small) => Some(Split::Small(small)),
+            &Packed::Large(at) => self.large[at as usize].as_ref().map(Split::Large),
+        }
+    }
+
+    pub fn get_mut(&mut self, key: Key) -> Option<Split<&mut S, &mut L>> {
+        match self.slots.get_mut(key)? {
+            Packed::Small(small) => Some(Split::Small(small)),
+            &mut Packed::Large(at) => self.large[at as usize].as_mut().map(Split::Large),
+        }
+    }
+
+    // Swaps in a value of either variant under the same key
+    pub fn replace(&mut self, key: Key, value: Split<S, L>) -> Option<Split<S, L>> {
+        let old = *self.slots.get(key)?;
+        let packed = self.pack(value);
+        *self.slots.get_mut(key)? = packed;
+        Some(self.unpack(old))
+    }
+
+    pub fn remove(&mut self, key: Key) -> Option<Split<S, L>> {
+        let old = self.slots.remove(key)?;
+        Some(self.unpack(old))
+    }
+
+    pub fn contains(&self, key: Key) -> bool {
+        self.slots.contains(key)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (Key, Split<&S, &L>)> + '_ {
+        self.slots.iter().filter_map(|(key, packed)| match packed {
+            Packed::Small(small) => Some((key, Split::Small(small))),
+            &Packed::Large(at) => Some((key, Split::Large(self.large[at as usize].as_ref()?))),
+        })
+    }
+
+    pub fn len(&self) -> u32 {
+        self.slots.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.slots.is_empty()
+    }
+
+    // Entries holding the large variant
+    pub fn large_len(&self) -> u32 {
+        (self.large.len() - self.large_free.len()) as u32
+    }
+
+    fn pack(&mut self, value: Split<S, L>) -> Packed<S> {
+        match value {
+            Split::Small(small) => Packed::Small(small),
+            Split::Large(large) => match self.large_free.pop() {
+                Some(at) => {
+                    self.large[at as usize] = Some(large);
+                    Packed::Large(at)
+                }
+                None => {
+                    self.large.push(Some(large));
+                    Packed::Large(self.large.len() as u32 - 1)
+                }
+            },
+        }
+    }
+
+    fn unpack(&mut self, packed: Packed<S>) -> Split<S, L> {
+        match packed {
+            Packed::Small(small) => Split::Small(small),
+            Packed::Large(at) => {
+                self.large_free.push(at);
+                Split::Large(self.large[at as usize].take().expect("pooled value of a live entry"))
+            }
+        }
+    }
+}
+
+impl<S: Default + Copy, L> Default for SplitSlotMap<S, L> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Brings a pooled object back to its freshly created state
+pub trait Reset {
+    fn reset(&mut self);
+}
+
+// Object pool on top of a slot map. Released objects are kept, not dropped,
+// and `acquire` hands them out again after calling `reset`, so once the pool
+// has grown to its peak size nothing is allocated or freed.
+pub struct Pool<T> {
+    slots: FastSlotMap<u32>, // Key -> index into `objects`
+    objects: Vec<T>,
+    idle: Vec<u32>,          // Objects released and waiting for reuse
+}
+
+impl<T: Reset + Default> Pool<T> {
+    pub fn new() -> Self {
+        Self { slots: FastSlotMap::new(), objects: Vec::new(), idle: Vec::new() }
+    }
+
+    pub fn acquire(&mut self) -> (Key, &mut T) {
+        let object = match self.idle.pop() {
+            Some(object) => {
+                self.objects[object as usize].reset();
+                object
+            }
+            None => {
+                self.objects.push(T::default());
+                self.objects.len() as u32 - 1
+            }
+        };
+        let key = self.slots.insert(object);
+        (key, &mut self.objects[object as usize])
+    }
+
+    // Returns the object to the pool as is; false if the key is stale
+    pub fn release(&mut self, key: Key) -> bool {
+        let Some(object) = self.slots.remove(key) else { return false };
+        self.idle.push(object);
+        true
+    }
+
+    pub fn get(&self, key: Key) -> Option<&T> {
+        self.slots.get(key).map(|&object| &self.objects[object as usize])
+    }
+
+    pub fn get_mut(&mut self, key: Key) -> Option<&mut T> {
+        let object = *self.slots.get(key)?;
+        Some(&mut self.objects[object as usize])
+    }
+
+    pub fn contains(&self, key: Key) -> bool {
+        self.slots.contains(key)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (Key, &T)> + '_ {
+        self.slots.iter().map(|(key, &object)| (key, &self.objects[object as usize]))
+    }
+
+    // Objects in use
+    pub fn len(&self) -> u32 {
+        self.slots.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.slots.is_empty()
+    }
+
+    // Released objects waiting to be reused
+    pub fn idle(&self) -> u32 {
+        self.idle.len() as u32
+    }
+}
+
+impl<T: Reset + Default> Default for Pool<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Byte blobs packed into one buffer, addressed by slot map keys. Removed
+// ranges are merged with free neighbours and reused first-fit by later inserts.
+pub struct SlotBuffer {
+    ranges: FastSlotMap<(u32, u32)>, // Key -> (offset, len) in `bytes`
+    bytes: Vec<u8>,
+    free: BTreeMap<u32, u32>,        // Free range offset -> len, never adjacent to each other or the end
+}
+
+impl SlotBuffer {
+    pub fn new() -> Self {
+        Self { ranges: FastSlotMap::new(), bytes: Vec::new(), free: BTreeMap::new() }
+    }
+
+    pub fn with_capacity(bytes: usize) -> Self {
+        Self { bytes: Vec::with_capacity(bytes), ..Self::new() }
+    }
+
+    pub fn insert(&mut self, data: &[u8]) -> Key {
+        let len = data.len() as u32;
+        // Empty blobs take no space, so truncation never strands them
+        if len == 0 {
+            return self.ranges.insert((0, 0));
+        }
+        let fit = self.free.iter().find(|&(_, &free)| free >= len).map(|(&offset, &free)| (offset, free));
+        let offset = match fit {
+            Some((offset, free)) => {
+                self.free.remove(&offset);
+                if free > len {
+                    self.free.insert(offset + len, free - len);
+                }
+                self.bytes[offset as usize..(offset + len) as usize].copy_from_slice(data);
+                offset
+            }
+            None => {
+                self.bytes.extend_from_slice(data);
+                self.bytes.len() as u32 - len
+            }
+        };
+        self.ranges.insert((offset, len))
+    }
+
+    pub fn get(&self, key: Key) -> Option<&[u8]> {
+        let &(offset, len) = self.ranges.get(key)?;
+        Some(&self.bytes[offset as usize..(offset + len) as usize])
+    }
+
+    pub fn get_mut(&mut self, key: Key) -> Option<&mut [u8]> {
+        let &(offset, len) = self.ranges.get(key)?;
+        Some(&mut self.bytes[offset as usize..(offset + len) as usize])
+    }
+
+    // Frees the blob's range; false if the key is stale
+    pub fn remove(&mut self, key: Key) -> bool {
+        let Some((mut offset, mut len)) = self.ranges.remove(key) else { return false };
+        if len == 0 {
+            return true;
+        }
+        if let Some((&before, &before_len)) = self.free.range(..offset).next_back() {
+            if before + before_len == offset {
+                self.free.remove(&before);
+                offset = before;
+                len += before_len;
+            }
+        }
+        if let Some(after_len) = self.free.remove(&(offset + len)) {
+            len += after_len;
+        }
+        // A range reaching the end shrinks the buffer instead
+        if (offset + len) as usize == self.bytes.len() {
+            self.bytes.truncate(offset as usize);
+        } else {
+            self.free.insert(offset, len);
+        }
+        true
+    }
+
+    pub fn contains(&self, key: Key) -> bool {
+        self.ranges.contains(key)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (Key, &[u8])> + '_ {
+        self.ranges.iter().map(|(key, &(offset, len))| (key, &self.bytes[offset as usize..(offset + len) as usize]))
+    }
+
+    pub fn len(&self) -> u32 {
+        self.ranges.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    // Size of the backing buffer, free ranges included
+    pub fn buffer_len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    // Bytes in free ranges waiting to be reused
+    pub fn free_bytes(&self) -> usize {
+        self.free.values().map(|&len| len as usize).sum()
+    }
+}
+
+impl Default for SlotBuffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Slot map for values that must not move, e.g. futures or intrusive list
+// nodes. Values live in fixed-size pages that are never reallocated, so they
+// can be handed out pinned. Removing an entry drops it in place.
+pub struct PinnedSlotMap<T, const PAGE: usize = 64> {
+    slots: FastSlotMap<()>, // Occupancy and generations; a key's index is its cell
+    pages: Vec<Box<[MaybeUninit<T>]>>,
+}
+
+impl<T, const PAGE: usize> PinnedSlotMap<T, PAGE> {
+    pub fn new() -> Self {
+        assert!(PAGE > 0, "pages must hold at least one value");
+        Self { slots: FastSlotMap::new(), pages: Vec::new() }
+    }
+
+    pub fn insert(&mut self, value: T) -> Key {
+        self.insert_pinned(value).0
+    }
+
+    pub fn insert_pinned(&mut self, value: T) -> (Key, Pin<&mut T>) {
+        let key = self.slots.insert(());
+        let index = key.index as usize;
+        while self.pages.len() <= index / PAGE {
+            self.pages.push((0..PAGE).map(|_| MaybeUninit::uninit()).collect());
+        }
+        let value = self.pages[index / PAGE][index % PAGE].write(value);
+        // The page is never reallocated and the value is only dropped in place
+        (key, unsafe { Pin::new_unchecked(value) })
+    }
+
+    pub fn get(&self, key: Key) -> Option<&T> {
+        let index = self.cell(key)?;
+        Some(unsafe { self.pages[index / PAGE][index % PAGE].assume_init_ref() })
+    }
+
+    pub fn get_pin(&mut self, key: Key) -> Option<Pin<&mut T>> {
+        let index = self.cell(key)?;
+        Some(unsafe { Pin::new_unchecked(self.pages[index / PAGE][index % PAGE].assume_init_mut()) })
+    }
+
+    pub fn get_mut(&mut self, key: Key) -> Option<&mut T>
+    where
+        T: Unpin,
+    {
+        self.get_pin(key).map(Pin::into_inner)
+    }
+
+    // Drops the value where it lies; false if the key is stale
+    pub fn remove(&mut self, key: Key) -> bool {
+        let Some(index) = self.cell(key) else { return false };
+        self.slots.remove(key);
+        unsafe { self.pages[index / PAGE][index % PAGE].assume_init_drop() };
+        true
+    }
+
+    pub fn contains(&self, key: Key) -> bool {
+        self.cell(key).is_some()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (Key, &T)> + '_ {
+        self.slots.iter().map(|(key, _)| {
+            let index = key.index as usize;
+            (key, unsafe { self.pages[index / PAGE][index % PAGE].assume_init_ref() })
+        })
+    }
+
+    pub fn len(&self) -> u32 {
+        self.slots.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.slots.is_empty()
+    }
+
+    // Cell of a live entry. Checks occupancy as well, since a forged key can
+    // match the generation of a vacant slot.
+    fn cell(&self, key: Key) -> Option<usize> {
+        let index = key.index as usize;
+        (self.slots.contains(key) && self.slots.is_occupied(index)).then_some(index)
+    }
+}
+
+impl<T, const PAGE: usize> Default for PinnedSlotMap<T, PAGE> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, const PAGE: usize> Drop for PinnedSlotMap<T, PAGE> {
+    fn drop(&mut self) {
+        for (key, _) in self.slots.iter() {
+            let index = key.index as usize;
+            unsafe { self.pages[index / PAGE][index % PAGE].assume_init_drop() };
+        }
+    }
+}
+
+// Key whose top `BITS` index bits carry a user tag (e.g. an object kind), from a `TaggedSlotMap`
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct TaggedKey<const BITS: u32>(Key);
+
+impl<const BITS: u32> TaggedKey<BITS> {
+    // Tags a key of an untagged map; panics if the tag or the key's index doesn't fit
+    pub fn new(tag: u32, key: Key) -> Self {
+        assert!(tag < 1 << BITS, "tag {tag} does not fit in {BITS} bits");
+        assert!(key.index < u32::MAX >> BITS, "slot index {} leaves no room for a {BITS}-bit tag", key.index);
+        TaggedKey(Key::new(key.index | tag << (32 - BITS), key.generation))
+    }
+
+    pub fn tag(&self) -> u32 {
+        self.0.index >> (32 - BITS)
+    }
+
+    // Same key with the tag bits cleared, as used by the underlying map
+    pub fn untagged(&self) -> Key {
+        Key::new(self.0.index & (u32::MAX >> BITS), self.0.generation)
+    }
+
+    // The key with its tag bits in place, e.g. for `as_ffi` or persisting
+    pub fn key(&self) -> Key {
+        self.0
+    }
+
+    pub fn from_key(key: Key) -> Self {
+        TaggedKey(key)
+    }
+}
+
+// Slot map that stores a `BITS`-wide tag with each entry and hands it out in
+// the top bits of the key index, so one map can hold several kinds of object
+// without a side enum. Holds at most 2^(32 - BITS) - 1 slots.
+pub struct TaggedSlotMap<T, const BITS: u32> {
+    map: FastSlotMap<T>,
+    tags: Vec<u32>, // Tag of the entry in each slot
+}
+
+impl<T: Default + Copy, const BITS: u32> TaggedSlotMap<T, BITS> {
+    pub fn new() -> Self {
+        const { assert!(BITS > 0 && BITS < 32, "tag must take 1 to 31 bits") };
+        Self { map: FastSlotMap::new(), tags: Vec::new() }
+    }
+
+    // `None`, changing nothing, once every slot the tag leaves room for is in use
+    pub fn insert(&mut self, tag: u32, value: T) -> Option<TaggedKey<BITS>> {
+        assert!(tag < 1 << BITS, "tag {tag} does not fit in {BITS} bits");
+        if !self.map.has_vacant() && self.map.values.len() >= (u32::MAX >> BITS) as usize {
+            return None;
+        }
+        let key = self.map.insert(value);
+        let index = key.index as usize;
+        if self.tags.len() <= index {
+            self.tags.resize(index + 1, 0);
+        }
+        self.tags[index] = tag;
+        Some(TaggedKey(Key::new(key.index | tag << (32 - BITS), key.generation)))
+    }
+
+    pub fn get(&self, key: TaggedKey<BITS>) -> Option<&T> {
+        self.resolve(key).and_then(|key| self.map.get(key))
+    }
+
+    pub fn get_mut(&mut self, key: TaggedKey<BITS>) -> Option<&mut T> {
+        self.resolve(key).and_then(|key| self.map.get_mut(key))
+    }
+
+    pub fn remove(&mut self, key: TaggedKey<BITS>) -> Option<T> {
+        self.resolve(key).and_then(|key| self.map.remove(key))
+    }
+
+    pub fn contains(&self, key: TaggedKey<BITS>) -> bool {
+        self.get(key).is_some()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (TaggedKey<BITS>, &T)> + '_ {
+        self.map.iter().map(|(key, value)| {
+            let tag = self.tags[key.index as usize];
+            (TaggedKey(Key::new(key.index | tag << (32 - BITS), key.generation)), value)
+        })
+    }
+
+    // Entries of one kind
+    pub fn iter_tag(&self, tag: u32) -> impl Iterator<Item = (TaggedKey<BITS>, &T)> + '_ {
+        self.iter().filter(move |(key, _)| key.tag() == tag)
+    }
+
+    pub fn len(&self) -> u32 {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    // Keys of the underlying map are untagged
+    pub fn as_map(&self) -> &FastSlotMap<T> {
+        &self.map
+    }
+
+    // Strips the tag, rejecting keys whose tag isn't the one stored for the slot
+    fn resolve(&self, key: TaggedKey<BITS>) -> Option<Key> {
+        let untagged = key.untagged();
+        (self.tags.get(untagged.index as usize) == Some(&key.tag())).then_some(untagged)
+    }
+}
+
+impl<T: Default + Copy, const BITS: u32> Default for TaggedSlotMap<T, BITS> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Slot map with a secondary index on a field of `T`, kept in sync on every mutation
+pub struct IndexedSlotMap<T, K> {
+    map: FastSlotMap<T>,
+    extract: fn(&T) -> K,
+    index: HashMap<K, Vec<Key>>, // Field value -> keys holding it
+}
+
+impl<T: Default + Copy, K: Hash + Eq> IndexedSlotMap<T, K> {
+    pub fn new(extract: fn(&T) -> K) -> Self {
+        Self { map: FastSlotMap::new(), extract, index: HashMap::new() }
+    }
+
+    pub fn insert(&mut self, value: T) -> Key {
+        let key = self.map.insert(value);
+        self.index.entry((self.extract)(&value)).or_default().push(key);
+        key
+    }
+
+    // Refuses to insert if another entry already has the same indexed field,
+    // handing back that entry's key along with the rejected value
+    pub fn insert_unique(&mut self, value: T) -> Result<Key, (Key, T)> {
+        match self.find_by(&(self.extract)(&value)) {
+            Some(existing) => Err((existing, value)),
+            None => Ok(self.insert(value)),
+        }
+    }
+
+    pub fn get(&self, key: Key) -> Option<&T> {
+        self.map.get(key)
+    }
+
+    // Mutates the entry in place and re-indexes it if the indexed field changed
+    pub fn update<R>(&mut self, key: Key, f: impl FnOnce(&mut T) -> R) -> Option<R> {
+        let value = self.map.get_mut(key)?;
+        let old = (self.extract)(value);
+        let result = f(value);
+        let new = (self.extract)(value);
+        if old != new {
+            self.unindex(&old, key);
+            self.index.entry(new).or_default().push(key);
+        }
+        Some(result)
+    }
+
+    pub fn remove(&mut self, key: Key) -> Option<T> {
+        let value = self.map.remove(key)?;
+        self.unindex(&(self.extract)(&value), key);
+        Some(value)
+    }
+
+    pub fn find_by(&self, field: &K) -> Option<Key> {
+        self.index.get(field).and_then(|keys| keys.first().copied())
+    }
+
+    pub fn find_all_by(&self, field: &K) -> impl Iterator<Item = Key> + '_ {
+        self.index.get(field).into_iter().flatten().copied()
+    }
+
+    pub fn contains(&self, key: Key) -> bool {
+        self.map.contains(key)
+    }
+
+    pub fn len(&self) -> u32 {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    pub fn as_map(&self) -> &FastSlotMap<T> {
+        &self.map
+    }
+
+    fn unindex(&mut self, field: &K, key: Key) {
+        if let Some(keys) = self.index.get_mut(field) {
+            if let Some(position) = keys.iter().position(|&k| k == key) {
+                keys.swap_remove(position);
+            }
+            if keys.is_empty() {
+                self.index.remove(field);
+            }
+        }
+    }
+}
+
+// Extra per-entry data stored outside a primary map, addressed by the primary's keys
+pub struct SecondaryMap<V> {
+    slots: Vec<Option<(u32, V)>>, // Indexed by slot: (generation, value)
+    len: u32,
+    synced_version: Option<u64>, // Primary's version at the last sync_with
+}
+
+impl<V> SecondaryMap<V> {
+    pub fn new() -> Self {
+        Self { slots: Vec::new(), len: 0, synced_version: None }
+    }
+
+    // Returns the previous value stored for this exact key
+    pub fn insert(&mut self, key: Key, value: V) -> Option<V> {
+        assert!(!key.is_null(), "cannot insert the null key");
+        let index = key.index as usize;
+        if index >= self.slots.len() {
+            self.slots.resize_with(index + 1, || None);
+        }
+        match self.slots[index].replace((key.generation, value)) {
+            Some((generation, old)) if generation == key.generation => Some(old),
+            Some(_) => None,
+            None => {
+                self.len += 1;
+                None
+            }
+        }
+    }
+
+    pub fn get(&self, key: Key) -> Option<&V> {
+        match self.slots.get(key.index as usize) {
+            Some(Some((generation, value))) if *generation == key.generation => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn get_mut(&mut self, key: Key) -> Option<&mut V> {
+        match self.slots.get_mut(key.index as usize) {
+            Some(Some((generation, value))) if *generation == key.generation => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn remove(&mut self, key: Key) -> Option<V> {
+        let slot = self.slots.get_mut(key.index as usize)?;
+        if slot.as_ref().is_some_and(|(generation, _)| *generation == key.generation) {
+            self.len -= 1;
+            return slot.take().map(|(_, value)| value);
+        }
+        None
+    }
+
+    pub fn contains_key(&self, key: Key) -> bool {
+        self.get(key).is_some()
+    }
+
+    // Entries in slot order
+    pub fn iter(&self) -> impl Iterator<Item = (Key, &V)> + '_ {
+        self.slots
+            .iter()
+            .enumerate()
+            .filter_map(|(index, slot)| slot.as_ref().map(|(generation, value)| (Key::new(index as u32, *generation), value)))
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = Key> + '_ {
+        self.iter().map(|(key, _)| key)
+    }
+
+    // Keys with an entry here that also resolve in `primary`, in slot order
+    pub fn intersect_keys<'a, T: Default + Copy, S: Storage<T>>(&'a self, primary: &'a FastSlotMap<T, S>) -> impl Iterator<Item = Key> + 'a {
+        zip_live(self.iter(), primary.slot_iter()).map(|(key, _, _)| key)
+    }
+
+    // Drops every entry whose key no longer resolves in `primary`, returning how
+    // many were dropped. Call it for each secondary map after removing from the
+    // primary; it returns at once if the primary hasn't changed since last time.
+    pub fn sync_with<T: Default + Copy, S: Storage<T>>(&mut self, primary: &FastSlotMap<T, S>) -> u32 {
+        if self.synced_version == Some(primary.version()) {
+            return 0;
+        }
+        self.synced_version = Some(primary.version());
+        self.retain_with_primary(primary)
+    }
+
+    // `slotmap`-style GC: `sync_with` without the unchanged-primary shortcut
+    pub fn retain_with_primary<T: Default + Copy, S: Storage<T>>(&mut self, primary: &FastSlotMap<T, S>) -> u32 {
+        let before = self.len;
+        for (index, slot) in self.slots.iter_mut().enumerate() {
+            if slot.as_ref().is_some_and(|&(generation, _)| !primary.contains(Key::new(index as u32, generation))) {
+                *slot = None;
+                self.len -= 1;
+            }
+        }
+        before - self.len
+    }
+
+    pub fn len(&self) -> u32 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // Moves entries to their new keys after the primary map was merged or compacted;
+    // entries whose keys have no mapping are dropped
+    pub fn remap_keys(&mut self, remap: &KeyRemap) {
+        match &remap.mapping {
+            Mapping::Identity => {}
+            Mapping::Table(_) => {
+                let old = std::mem::take(&mut self.slots);
+                self.len = 0;
+                for (index, slot) in old.into_iter().enumerate() {
+                    if let Some((generation, value)) = slot {
+                        if let Some(new_key) = remap.remap(Key::new(index as u32, generation)) {
+                            self.insert(new_key, value);
+                        }
+                    }
+                }
+            }
+            Mapping::Moves(moves) => {
+                // Take every moved entry out first, since a target may be another move's source
+                let mut moving = Vec::new();
+                for (&index, &(generation, new_key)) in moves {
+                    if let Some(value) = self.remove(Key::new(index, generation)) {
+                        moving.push((new_key, value));
+                    }
+                }
+                for (new_key, value) in moving {
+                    self.insert(new_key, value);
+                }
+            }
+        }
+    }
+}
+
+impl<V> Default for SecondaryMap<V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Secondary map for data attached to entries of either of two primary maps,
+// e.g. static and dynamic objects. Keys name their primary in one namespace
+// bit: `TaggedKey::new(space, key)` with space 0 for the first primary and 1
+// for the second. Primaries must stay under 2^31 - 1 slots.
+pub struct UnionSecondaryMap<V> {
+    spaces: [SecondaryMap<V>; 2], // Entries keyed by each primary's plain keys
+}
+
+impl<V> UnionSecondaryMap<V> {
+    pub fn new() -> Self {
+        Self { spaces: [SecondaryMap::new(), SecondaryMap::new()] }
+    }
+
+    pub fn insert(&mut self, key: TaggedKey<1>, value: V) -> Option<V> {
+        self.spaces[key.tag() as usize].insert(key.untagged(), value)
+    }
+
+    pub fn get(&self, key: TaggedKey<1>) -> Option<&V> {
+        self.spaces[key.tag() as usize].get(key.untagged())
+    }
+
+    pub fn get_mut(&mut self, key: TaggedKey<1>) -> Option<&mut V> {
+        self.spaces[key.tag() as usize].get_mut(key.untagged())
+    }
+
+    pub fn remove(&mut self, key: TaggedKey<1>) -> Option<V> {
+        self.spaces[key.tag() as usize].remove(key.untagged())
+    }
+
+    pub fn contains_key(&self, key: TaggedKey<1>) -> bool {
+        self.get(key).is_some()
+    }
+
+    // `get` that also checks the key still resolves in the primary of its space
+    pub fn get_live<A: Default + Copy, SA: Storage<A>, B: Default + Copy, SB: Storage<B>>(
+        &self,
+        key: TaggedKey<1>,
+        first: &FastSlotMap<A, SA>,
+        second: &FastSlotMap<B, SB>,
+    ) -> Option<&V> {
+        let live = match key.tag() {
+            0 => first.contains(key.untagged()),
+            _ => second.contains(key.untagged()),
+        };
+        self.get(key).filter(|_| live)
+    }
+
+    // Entries of the first space, then the second, each in slot order
+    pub fn iter(&self) -> impl Iterator<Item = (TaggedKey<1>, &V)> + '_ {
+        (0..2).flat_map(move |space| self.spaces[space as usize].iter().map(move |(key, value)| (TaggedKey::new(space, key), value)))
+    }
+
+    // One space's entries, keyed by that primary's plain keys
+    pub fn space(&self, space: u32) -> &SecondaryMap<V> {
+        &self.spaces[space as usize]
+    }
+
+    // `SecondaryMap::sync_with` for each space against its own primary
+    pub fn sync_with<A: Default + Copy, SA: Storage<A>, B: Default + Copy, SB: Storage<B>>(
+        &mut self,
+        first: &FastSlotMap<A, SA>,
+        second: &FastSlotMap<B, SB>,
+    ) -> u32 {
+        self.spaces[0].sync_with(first) + self.spaces[1].sync_with(second)
+    }
+
+    pub fn len(&self) -> u32 {
+        self.spaces[0].len() + self.spaces[1].len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<V> Default for UnionSecondaryMap<V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Uniform 2D grid over the entries of a primary map, for "what's near here"
+// queries. Keyed by the primary's keys, like `SecondaryMap`; call `sync_with`
+// after removing from the primary to drop the removed entries from the grid.
+pub struct SlotGrid {
+    cell_size: f32,
+    cells: HashMap<(i32, i32), Vec<Key>>, // Keys positioned in each non-empty cell
+    positions: SecondaryMap<[f32; 2]>,
+    synced_version: Option<u64>, // Primary's version at the last sync_with
+}
+
+impl SlotGrid {
+    pub fn new(cell_size: f32) -> Self {
+        assert!(cell_size > 0.0, "cell size must be positive");
+        Self { cell_size, cells: HashMap::new(), positions: SecondaryMap::new(), synced_version: None }
+    }
+
+    // Places the entry at `pos`, moving it between cells if needed. An entry
+    // left in the slot by an older generation is dropped from its cell.
+    pub fn update_position(&mut self, key: Key, pos: [f32; 2]) {
+        let cell = self.cell_of(pos);
+        let previous = match self.positions.slots.get(key.index as usize) {
+            Some(Some((generation, old))) => Some((Key::new(key.index, *generation), self.cell_of(*old))),
+            _ => None,
+        };
+        self.positions.insert(key, pos);
+        match previous {
+            Some((old_key, old_cell)) if old_key == key && old_cell == cell => {}
+            Some((old_key, old_cell)) => {
+                self.unlink(old_key, old_cell);
+                self.cells.entry(cell).or_default().push(key);
+            }
+            None => self.cells.entry(cell).or_default().push(key),
+        }
+    }
+
+    pub fn position(&self, key: Key) -> Option<[f32; 2]> {
+        self.positions.get(key).copied()
+    }
+
+    pub fn remove(&mut self, key: Key) -> Option<[f32; 2]> {
+        let pos = self.positions.remove(key)?;
+        self.unlink(key, self.cell_of(pos));
+        Some(pos)
+    }
+
+    // Keys positioned inside the box, bounds included, in no particular order
+    pub fn query_aabb(&self, min: [f32; 2], max: [f32; 2]) -> Vec<Key> {
+        let (low, high) = (self.cell_of(min), self.cell_of(max));
+        let inside = |key: &&Key| {
+            self.positions.get(**key).is_some_and(|p| (min[0]..=max[0]).contains(&p[0]) && (min[1]..=max[1]).contains(&p[1]))
+        };
+        let spanned = (high.0 as i64 - low.0 as i64 + 1).max(0) * (high.1 as i64 - low.1 as i64 + 1).max(0);
+        // Scan whichever is smaller: the cells the box covers or the occupied ones
+        if spanned as usize <= self.cells.len() {
+            (low.0..=high.0)
+                .flat_map(|x| (low.1..=high.1).map(move |y| (x, y)))
+                .filter_map(|cell| self.cells.get(&cell))
+                .flat_map(|keys| keys.iter().filter(inside))
+                .copied()
+                .collect()
+        } else {
+            self.cells
+                .iter()
+                .filter(|(&(x, y), _)| (low.0..=high.0).contains(&x) && (low.1..=high.1).contains(&y))
+                .flat_map(|(_, keys)| keys.iter().filter(inside))
+                .copied()
+                .collect()
+        }
+    }
+
+    // Drops every entry whose key no longer resolves in `primary`, returning how
+    // many were dropped; returns at once if the primary hasn't changed since last time
+    pub fn sync_with<T: Default + Copy, S: Storage<T>>(&mut self, primary: &FastSlotMap<T, S>) -> u32 {
+        if self.synced_version == Some(primary.version()) {
+            return 0;
+        }
+        self.synced_version = Some(primary.version());
+        self.cells.retain(|_, keys| {
+            keys.retain(|&key| primary.contains(key));
+            !keys.is_empty()
+        });
+        self.positions.retain_with_primary(primary)
+    }
+
+    pub fn len(&self) -> u32 {
+        self.positions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.positions.is_empty()
+    }
+
+    fn cell_of(&self, pos: [f32; 2]) -> (i32, i32) {
+        ((pos[0] / self.cell_size).floor() as i32, (pos[1] / self.cell_size).floor() as i32)
+    }
+
+    fn unlink(&mut self, key: Key, cell: (i32, i32)) {
+        let Some(keys) = self.cells.get_mut(&cell) else { return };
+        if let Some(position) = keys.iter().position(|&k| k == key) {
+            keys.swap_remove(position);
+        }
+        if keys.is_empty() {
+            self.cells.remove(&cell);
+        }
+    }
+}
+
+// Per-entry scratch data for one frame, keyed by a primary map's keys.
+// `reset` forgets every entry in O(1) by advancing an epoch instead of
+// clearing, and slots are allocated up front for the primary's capacity.
+pub struct ScratchMap<U> {
+    slots: Vec<(u32, u32, U)>, // (epoch, generation, value) per slot; valid if the epoch is current
+    epoch: u32,
+    len: u32,
+}
+
+impl<U: Default> ScratchMap<U> {
+    pub fn new() -> Self {
+        Self::with_capacity(0)
+    }
+
+    pub fn with_capacity(slots: usize) -> Self {
+        let mut scratch = Self { slots: Vec::new(), epoch: 1, len: 0 };
+        scratch.slots.resize_with(slots, || (0, 0, U::default()));
+        scratch
+    }
+
+    // Sized for every slot `primary` can hold without growing
+    pub fn for_map<T: Default + Copy, S: Storage<T>>(primary: &FastSlotMap<T, S>) -> Self {
+        Self::with_capacity(primary.capacity())
+    }
+
+    // Forgets every entry. Values stay in place until overwritten.
+    pub fn reset(&mut self) {
+        self.len = 0;
+        self.epoch = self.epoch.wrapping_add(1);
+        if self.epoch == 0 {
+            // Stamps from 2^32 resets ago would look current again
+            self.slots.iter_mut().for_each(|slot| slot.0 = 0);
+            self.epoch = 1;
+        }
+    }
+
+    pub fn insert(&mut self, key: Key, value: U) {
+        *self.get_or_default(key) = value;
+    }
+
+    // The entry for `key`, starting from `U::default()` if it has none this epoch
+    pub fn get_or_default(&mut self, key: Key) -> &mut U {
+        assert!(!key.is_null(), "cannot insert the null key");
+        let index = key.index as usize;
+        if index >= self.slots.len() {
+            self.slots.resize_with(index + 1, || (0, 0, U::default()));
+        }
+        let slot = &mut self.slots[index];
+        if slot.0 != self.epoch || slot.1 != key.generation {
+            if slot.0 != self.epoch {
+                self.len += 1;
+            }
+            *slot = (self.epoch, key.generation, U::default());
+        }
+        &mut slot.2
+    }
+
+    pub fn get(&self, key: Key) -> Option<&U> {
+        match self.slots.get(key.index as usize) {
+            Some((epoch, generation, value)) if *epoch == self.epoch && *generation == key.generation => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn get_mut(&mut self, key: Key) -> Option<&mut U> {
+        match self.slots.get_mut(key.index as usize) {
+            Some((epoch, generation, value)) if *epoch == self.epoch && *generation == key.generation => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn contains_key(&self, key: Key) -> bool {
+        self.get(key).is_some()
+    }
+
+    // Entries set since the last reset
+    pub fn len(&self) -> u32 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl<U: Default> Default for ScratchMap<U> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Values packed with no holes, for fast iteration. Removal swaps the last value
+// into the hole, so a key's dense position can change; keys themselves stay valid.
+pub struct DenseSlotMap<T> {
+    values: Vec<T>,                  // Live values, densely packed
+    keys: Vec<Key>,                  // Key of the value at each dense position
+    positions: FastSlotMap<u32>,     // Key -> dense position
+    on_relocate: Option<RelocateCallback>, // Called with (key, old, new) position
+}
+
+type RelocateCallback = Box<dyn FnMut(Key, u32, u32) + Send + Sync>;
+
+impl<T> DenseSlotMap<T> {
+    pub fn new() -> Self {
+        Self { values: Vec::new(), keys: Vec::new(), positions: FastSlotMap::new(), on_relocate: None }
+    }
+
+    pub fn insert(&mut self, value: T) -> Key {
+        let key = self.positions.insert(self.values.len() as u32);
+        self.values.push(value);
+        self.keys.push(key);
+        key
+    }
+
+    pub fn get(&self, key: Key) -> Option<&T> {
+        self.positions.get(key).map(|&position| &self.values[position as usize])
+    }
+
+    pub fn get_mut(&mut self, key: Key) -> Option<&mut T> {
+        self.positions.get(key).map(|&position| &mut self.values[position as usize])
+    }
+
+    pub fn remove(&mut self, key: Key) -> Option<T> {
+        let position = self.positions.remove(key)?;
+        let value = self.values.swap_remove(position as usize);
+        self.keys.swap_remove(position as usize);
+
+        // The last value now fills the hole
+        if let Some(&moved) = self.keys.get(position as usize) {
+            *self.positions.get_mut(moved).expect("dense keys are always live") = position;
+            if let Some(callback) = &mut self.on_relocate {
+                callback(moved, self.values.len() as u32, position);
+            }
+        }
+        Some(value)
+    }
+
+    // Current index of the key's value in `values()`
+    pub fn position_of(&self, key: Key) -> Option<u32> {
+        self.positions.get(key).copied()
+    }
+
+    // Called whenever a removal moves a value to a new dense position
+    pub fn set_relocation_callback(&mut self, callback: impl FnMut(Key, u32, u32) + Send + Sync + 'static) {
+        self.on_relocate = Some(Box::new(callback));
+    }
+
+    pub fn values(&self) -> &[T] {
+        &self.values
+    }
+
+    pub fn values_mut(&mut self) -> &mut [T] {
+        &mut self.values
+    }
+
+    pub fn keys(&self) -> &[Key] {
+        &self.keys
+    }
+
+    pub fn contains(&self, key: Key) -> bool {
+        self.positions.contains(key)
+    }
+
+    pub fn len(&self) -> u32 {
+        self.values.len() as u32
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+}
+
+impl<T> Default for DenseSlotMap<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Upcast to `Any` through a trait object: declare `trait Plugin: AsAny` and a
+// `DenseSlotMap<Box<dyn Plugin>>` can downcast its entries with `get_dyn`
+pub trait AsAny: Any {
+    fn as_any(&self) -> &dyn Any;
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+}
+
+impl<T: Any> AsAny for T {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+// Helpers for maps of boxed trait objects
+impl<D: ?Sized> DenseSlotMap<Box<D>> {
+    // Takes the box so the unsizing happens at the call site: `insert_boxed(Box::new(value))`
+    pub fn insert_boxed(&mut self, value: Box<D>) -> Key {
+        self.insert(value)
+    }
+
+    pub fn iter_dyn(&self) -> impl Iterator<Item = (Key, &D)> + '_ {
+        self.keys.iter().copied().zip(self.values.iter().map(|value| &**value))
+    }
+
+    pub fn iter_dyn_mut(&mut self) -> impl Iterator<Item = (Key, &mut D)> + '_ {
+        self.keys.iter().copied().zip(self.values.iter_mut().map(|value| &mut **value))
+    }
+}
+
+impl<D: ?Sized + AsAny> DenseSlotMap<Box<D>> {
+    // The entry as its concrete type; `None` if the key is stale or the type differs
+    pub fn get_dyn<U: Any>(&self, key: Key) -> Option<&U> {
+        let value: &D = self.get(key)?;
+        value.as_any().downcast_ref()
+    }
+
+    pub fn get_dyn_mut<U: Any>(&mut self, key: Key) -> Option<&mut U> {
+        let value: &mut D = self.get_mut(key)?;
+        value.as_any_mut().downcast_mut()
+    }
+}
+
+// Owns one `FastSlotMap<T>` per value type, looked up by `TypeId`
+pub struct SlotMapRegistry {
+    maps: HashMap<TypeId, Box<dyn ErasedMap>>,
+}
+
+// Object-safe view of a `FastSlotMap<T>` of any `T`
+trait ErasedMap: AsAny {
+    fn remove_key(&mut self, key: Key) -> bool;
+    fn into_any(self: Box<Self>) -> Box<dyn Any>;
+}
+
+impl<T: Default + Copy + 'static> ErasedMap for FastSlotMap<T> {
+    // The maps share one key space by design, so provenance is dropped
+    fn remove_key(&mut self, key: Key) -> bool {
+        self.remove(Key::new(key.index, key.generation)).is_some()
+    }
+
+    fn into_any(self: Box<Self>) -> Box<dyn Any> {
+        self
+    }
+}
+
+impl SlotMapRegistry {
+    pub fn new() -> Self {
+        Self { maps: HashMap::new() }
+    }
+
+    pub fn get_map<T: Default + Copy + 'static>(&self) -> Option<&FastSlotMap<T>> {
+        self.maps.get(&TypeId::of::<T>()).and_then(|map| (**map).as_any().downcast_ref())
+    }
+
+    pub fn get_map_mut<T: Default + Copy + 'static>(&mut self) -> Option<&mut FastSlotMap<T>> {
+        self.maps.get_mut(&TypeId::of::<T>()).and_then(|map| (**map).as_any_mut().downcast_mut())
+    }
+
+    // Returns the map for `T`, creating an empty one on first use
+    pub fn map_mut<T: Default + Copy + 'static>(&mut self) -> &mut FastSlotMap<T> {
+        self.maps
+            .entry(TypeId::of::<T>())
+            .or_insert_with(|| Box::new(FastSlotMap::<T>::new()))
+            .as_mut()
+            .as_any_mut()
+            .downcast_mut()
+            .expect("registry maps are keyed by their value type")
+    }
+
+    // Replaces the map for `T`, returning the previous one
+    pub fn insert_map<T: Default + Copy + 'static>(&mut self, map: FastSlotMap<T>) -> Option<FastSlotMap<T>> {
+        let old = self.maps.insert(TypeId::of::<T>(), Box::new(map))?;
+        old.into_any().downcast().ok().map(|map| *map)
+    }
+
+    pub fn remove_map<T: Default + Copy + 'static>(&mut self) -> Option<FastSlotMap<T>> {
+        let map = self.maps.remove(&TypeId::of::<T>())?;
+        map.into_any().downcast().ok().map(|map| *map)
+    }
+
+    pub fn contains_map<T: 'static>(&self) -> bool {
+        self.maps.contains_key(&TypeId::of::<T>())
+    }
+
+    // Removes `key` from every map it is live in, returning how many maps held it.
+    // Only meaningful when the maps share one key space (e.g. per-entity maps
+    // that are always inserted into and removed from together).
+    pub fn remove_everywhere(&mut self, key: Key) -> usize {
+        self.maps.values_mut().map(|map| map.remove_key(key) as usize).sum()
+    }
+
+    // Number of registered maps
+    pub fn len(&self) -> usize {
+        self.maps.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.maps.is_empty()
+    }
+}
+
+impl Default for SlotMapRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Left-right map for read-heavy workloads. The single writer mutates a private
+// copy and logs what it did; `publish` swaps the copies and replays the log
+// onto the old one. Readers never wait on the writer.
+pub struct DoubleBufferedSlotMap<T> {
+    shared: Arc<LeftRight<T>>,
+    pending: Vec<BufferedOp<T>>,
+}
+
+// Cloneable read handle for a `DoubleBufferedSlotMap`
+pub struct DoubleBufferedReader<T> {
+    shared: Arc<LeftRight<T>>,
+}
+
+struct LeftRight<T> {
+    maps: [UnsafeCell<FastSlotMap<T>>; 2],
+    // Copy readers use; the other one belongs to the writer
+    active: AtomicUsize,
+    readers: [AtomicUsize; 2],
+}
+
+// Readers only touch the active copy, the writer only the other one
+unsafe impl<T: Send + Sync> Sync for LeftRight<T> {}
+
+enum BufferedOp<T> {
+    Insert(T),
+    Remove(Key),
+    // Value was changed in place; copy it over on replay
+    Write(Key),
+}
+
+// Keeps a copy's reader count raised while a read is in progress
+struct ReaderSlot<'a> {
+    count: &'a AtomicUsize,
+}
+
+impl Drop for ReaderSlot<'_> {
+    fn drop(&mut self) {
+        self.count.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl<T: Default + Copy> LeftRight<T> {
+    fn enter(&self) -> (&FastSlotMap<T>, ReaderSlot<'_>) {
+        loop {
+            let side = self.active.load(Ordering::SeqCst);
+            self.readers[side].fetch_add(1, Ordering::SeqCst);
+            let slot = ReaderSlot { count: &self.readers[side] };
+            // Re-check so the writer can't have flipped and drained in between
+            if self.active.load(Ordering::SeqCst) == side {
+                return (unsafe { &*self.maps[side].get() }, slot);
+            }
+        }
+    }
+}
+
+impl<T: Default + Copy> DoubleBufferedSlotMap<T> {
+    pub fn new() -> Self {
+        // A clone, so keys from either copy are accepted by both
+        let map = FastSlotMap::new();
+        Self {
+            shared: Arc::new(LeftRight {
+                maps: [UnsafeCell::new(map.clone()), UnsafeCell::new(map)],
+                active: AtomicUsize::new(0),
+                readers: [AtomicUsize::new(0), AtomicUsize::new(0)],
+            }),
+            pending: Vec::new(),
+        }
+    }
+
+    pub fn reader(&self) -> DoubleBufferedReader<T> {
+        DoubleBufferedReader { shared: self.shared.clone() }
+    }
+
+    fn write_side(&self) -> usize {
+        1 - self.shared.active.load(Ordering::SeqCst)
+    }
+
+    fn writer_map(&self) -> &FastSlotMap<T> {
+        unsafe { &*self.shared.maps[self.write_side()].get() }
+    }
+
+    fn writer_map_mut(&mut self) -> &mut FastSlotMap<T> {
+        unsafe { &mut *self.shared.maps[self.write_side()].get() }
+    }
+
+    // Changes are invisible to readers until `publish`
+    pub fn insert(&mut self, value: T) -> Key {
+        let key = self.writer_map_mut().insert(value);
+        self.pending.push(BufferedOp::Insert(value));
+        key
+    }
+
+    pub fn remove(&mut self, key: Key) -> Option<T> {
+        let value = self.writer_map_mut().remove(key)?;
+        self.pending.push(BufferedOp::Remove(key));
+        Some(value)
+    }
+
+    pub fn get_mut(&mut self, key: Key) -> Option<&mut T> {
+        if !self.writer_map().contains(key) {
+            return None;
+        }
+        self.pending.push(BufferedOp::Write(key));
+        self.writer_map_mut().get_mut(key)
+    }
+
+    // Reads see the writer's own unpublished changes
+    pub fn get(&self, key: Key) -> Option<&T> {
+        self.writer_map().get(key)
+    }
+
+    pub fn contains(&self, key: Key) -> bool {
+        self.writer_map().contains(key)
+    }
+
+    pub fn len(&self) -> u32 {
+        self.writer_map().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.writer_map().is_empty()
+    }
+
+    // Number of changes readers can't see yet
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    // Makes every change so far visible to readers. Waits for reads still on
+    // the old copy to finish, then brings that copy up to date.
+    pub fn publish(&mut self) {
+        if self.pending.is_empty() {
+            return;
+        }
+        let old = 1 - self.write_side();
+        self.shared.active.store(1 - old, Ordering::SeqCst);
+        while self.shared.readers[old].load(Ordering::SeqCst) != 0 {
+            hint::spin_loop();
+        }
+
+        let [first, second] = &self.shared.maps;
+        let (published, stale) = if old == 0 { (second, first) } else { (first, second) };
+        let (published, stale) = unsafe { (&*published.get(), &mut *stale.get()) };
+        for op in self.pending.drain(..) {
+            match op {
+                BufferedOp::Insert(value) => {
+                    stale.insert(value);
+                }
+                BufferedOp::Remove(key) => {
+                    stale.remove(key);
+                }
+                BufferedOp::Write(key) => {
+                    if let (Some(value), Some(slot)) = (published.get(key), stale.get_mut(key)) {
+                        *slot = *value;
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl<T: Default + Copy> Default for DoubleBufferedSlotMap<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Default + Copy> DoubleBufferedReader<T> {
+    pub fn get(&self, key: Key) -> Option<T> {
+        self.shared.enter().0.get(key).copied()
+    }
+
+    pub fn contains(&self, key: Key) -> bool {
+        self.shared.enter().0.contains(key)
+    }
+
+    pub fn len(&self) -> u32 {
+        self.shared.enter().0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Runs `f` against one consistent published snapshot
+    pub fn read<R>(&self, f: impl FnOnce(&FastSlotMap<T>) -> R) -> R {
+        let (map, _slot) = self.shared.enter();
+        f(map)
+    }
+}
+
+impl<T> Clone for DoubleBufferedReader<T> {
+    fn clone(&self) -> Self {
+        Self { shared: self.shared.clone() }
+    }
+}
+
+// Key bookkeeping shared by the columns of a `#[derive(SlotMapStruct)]` map
+// (see `fastslotmap-derive`). Every column is indexed by the slot this hands out.
+pub struct SoaKeys {
+    slots: FastSlotMap<()>,
+}
+
+impl SoaKeys {
+    pub fn new() -> Self {
+        Self { slots: FastSlotMap::new() }
+    }
+
+    // Allocates a key and returns it with the slot its column entries live in
+    pub fn insert(&mut self) -> (Key, usize) {
+        let key = self.slots.insert(());
+        (key, key.index as usize)
+    }
+
+    pub fn slot(&self, key: Key) -> Option<usize> {
+        self.slots.contains(key).then_some(key.index as usize)
+    }
+
+    pub fn remove(&mut self, key: Key) -> Option<usize> {
+        self.slots.remove(key).map(|_| key.index as usize)
+    }
+
+    // Key of the entry living in `slot`, if any
+    pub fn key_at(&self, slot: usize) -> Option<Key> {
+        if slot >= self.slots.generations.len() || !self.slots.is_occupied(slot) {
+            return None;
+        }
+        Some(self.slots.issue(Key::new(slot as u32, self.slots.generations[slot])))
+    }
+
+    pub fn len(&self) -> u32 {
+        self.slots.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.slots.is_empty()
+    }
+}
+
+impl Default for SoaKeys {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Tuple form of `get_disjoint_mut` for any number of keys held in variables:
+// `if let Some((a, b, c)) = get_disjoint!(map, a, b, c) { ... }`
+#[macro_export]
+macro_rules! get_disjoint {
+    ($map:expr, $($key:ident),+ $(,)?) => {
+        $map.get_disjoint_mut([$($key),+]).map(|[$($key),+]| ($($key),+))
+    };
+}
+
+// Exports an `extern "C"` API for `FastSlotMap<$ty>`: an opaque map pointer and
+// u64 keys (`Key::as_ffi`). `$ty` must be FFI-safe, e.g. `#[repr(C)]`. Function
+// names are spelled out since `macro_rules!` cannot build identifiers; run
+// cbindgen with macro expansion enabled to get a header.
+//
+//     export_capi! {
+//         Particle => {
+//             new: particles_new, free: particles_free, insert: particles_insert,
+//             get: particles_get, remove: particles_remove,
+//             contains: particles_contains, len: particles_len,
+//         }
+//     }
+//
+// Every pointer argument must be null or come from the matching `new` (or, for
+// `out`, point to writable memory for one value). Any key value is accepted:
+// stale and garbage keys fail like null maps do, and panics (a finalizer's,
+// or from `InvalidKeyPolicy::Panic`) are caught instead of unwinding into C.
+#[cfg(feature = "capi")]
+#[macro_export]
+macro_rules! export_capi {
+    (
+        $ty:ty => {
+            new: $new:ident, free: $free:ident, insert: $insert:ident,
+            get: $get:ident, remove: $remove:ident,
+            contains: $contains:ident, len: $len:ident $(,)?
+        }
+    ) => {
+        #[no_mangle]
+        pub extern "C" fn $new() -> *mut $crate::FastSlotMap<$ty> {
+            Box::into_raw(Box::new($crate::FastSlotMap::new()))
+        }
+
+        #[no_mangle]
+        #[allow(clippy::missing_safety_doc)]
+        pub unsafe extern "C" fn $free(map: *mut $crate::FastSlotMap<$ty>) {
+            if !map.is_null() {
+                let _ = ::std::panic::catch_unwind(::std::panic::AssertUnwindSafe(|| drop(Box::from_raw(map))));
+            }
+        }
+
+        // Returns the null key's u64 if `map` is null or can't grow
+        #[no_mangle]
+        #[allow(clippy::missing_safety_doc)]
+        pub unsafe extern "C" fn $insert(map: *mut $crate::FastSlotMap<$ty>, value: $ty) -> u64 {
+            let insert = ::std::panic::AssertUnwindSafe(|| map.as_mut()?.try_insert(value));
+            let key = ::std::panic::catch_unwind(insert).ok().flatten();
+            key.unwrap_or($crate::Key::null()).as_ffi()
+        }
+
+        // Copies the value to `out` and returns true if the key is live
+        #[no_mangle]
+        #[allow(clippy::missing_safety_doc)]
+        pub unsafe extern "C" fn $get(map: *const $crate::FastSlotMap<$ty>, key: u64, out: *mut $ty) -> bool {
+            let get = ::std::panic::AssertUnwindSafe(|| map.as_ref()?.get($crate::Key::from_ffi(key)).copied());
+            match ::std::panic::catch_unwind(get) {
+                Ok(Some(value)) if !out.is_null() => {
+                    out.write(value);
+                    true
+                }
+                _ => false,
+            }
+        }
+
+        // `out` may be null to discard the removed value
+        #[no_mangle]
+        #[allow(clippy::missing_safety_doc)]
+        pub unsafe extern "C" fn $remove(map: *mut $crate::FastSlotMap<$ty>, key: u64, out: *mut $ty) -> bool {
+            let remove = ::std::panic::AssertUnwindSafe(|| map.as_mut()?.remove($crate::Key::from_ffi(key)));
+            match ::std::panic::catch_unwind(remove) {
+                Ok(Some(value)) => {
+                    if !out.is_null() {
+                        out.write(value);
+                    }
+                    true
+                }
+                _ => false,
+            }
+        }
+
+        #[no_mangle]
+        #[allow(clippy::missing_safety_doc)]
+        pub unsafe extern "C" fn $contains(map: *const $crate::FastSlotMap<$ty>, key: u64) -> bool {
+            let contains = ::std::panic::AssertUnwindSafe(|| map.as_ref().is_some_and(|map| map.contains($crate::Key::from_ffi(key))));
+            ::std::panic::catch_unwind(contains).unwrap_or(false)
+        }
+
+        #[no_mangle]
+        #[allow(clippy::missing_safety_doc)]
+        pub unsafe extern "C" fn $len(map: *const $crate::FastSlotMap<$ty>) -> u32 {
+            let len = ::std::panic::AssertUnwindSafe(|| map.as_ref().map_or(0, |map| map.len()));
+            ::std::panic::catch_unwind(len).unwrap_or(0)
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+    use std::collections::HashSet;
+    use std::hash::Hasher;
+    use std::sync::atomic::AtomicBool;
+
+    #[test]
+    fn graph_removes_edges_with_their_nodes() {
+        let mut graph: SlotGraph<u32, f32> = SlotGraph::new();
+        let (a, b, c) = (graph.add_node(1), graph.add_node(2), graph.add_node(3));
+        let ab = graph.add_edge(a, b, 1.0).unwrap();
+        graph.add_edge(b, c, 2.0).unwrap();
+        let ca = graph.add_edge(c, a, 3.0).unwrap();
+        graph.add_edge(a, a, 4.0).unwrap();
+        assert_eq!(graph.neighbors(a).collect::<Vec<_>>(), vec![b, a]);
+        assert_eq!(graph.edge_count(), 4);
+        assert_eq!(graph.remove_node(b), Some(2));
+        assert_eq!(graph.edge_count(), 2);
+        assert!(!graph.contains_edge(ab));
+        assert_eq!(graph.edges(c).collect::<Vec<_>>(), vec![ca]);
+        assert!(graph.add_edge(a, b, 0.0).is_none());
+        assert_eq!(graph.remove_node(a), Some(1));
+        assert_eq!((graph.edge_count(), graph.edges(c).count()), (0, 0));
+    }
+
+    #[test]
+    fn version_tracks_structural_changes_only() {
+        let mut map = FastSlotMap::new();
+        let token = map.version();
+        let key = map.insert(1u32);
+        assert!(map.has_changed_since(token));
+        let token = map.version();
+        *map.get_mut(key).unwrap() = 3;
+        assert!(!map.has_changed_since(token));
+        map.remove(key);
+        assert!(map.has_changed_since(token));
+        let token = map.version();
+        map.remove(key);
+        assert!(!map.has_changed_since(token));
+    }
+
+    #[test]
+    fn try_get_explains_misses() {
+        let mut map = FastSlotMap::new();
+        let key = map.insert(1u32);
+        *map.try_get_mut(key).unwrap() = 2;
+        assert_eq!(map.try_get(key), Ok(&2));
+        map.remove(key);
+        assert_eq!(map.try_get(key), Err(SlotError::StaleGeneration { current: 1 }));
+        let mut other = FastSlotMap::<u32>::new();
+        assert_eq!(other.try_get_mut(Key::from_ffi(key.as_ffi())), Err(SlotError::OutOfBounds));
+    }
+
+    #[test]
+    fn removed_slots_are_reused() {
+        let mut map = FastSlotMap::new();
+        let keys: Vec<Key> = (0..10u32).map(|value| map.insert(value)).collect();
+        assert_eq!(map.remove(keys[3]), Some(3));
+        assert_eq!(map.remove(keys[3]), None);
+        assert_eq!(map.remove(keys[7]), Some(7));
+        let reused = [map.insert(70), map.insert(30)];
+        assert!(reused.iter().all(|key| key.index == 3 || key.index == 7));
+        assert_eq!(map.get(reused[0]), Some(&70));
+        assert_eq!(map.get(keys[3]), None);
+        assert_eq!(map.len(), 10);
+    }
+
+    #[test]
+    fn growth_strategies_and_callback() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut map = FastSlotMap::with_growth(GrowthStrategy::Fixed(10));
+        let grown = log.clone();
+        map.set_grow_callback(move |from, to| grown.lock().unwrap().push((from, to)));
+        for value in 0..25u64 {
+            map.insert(value);
+        }
+        assert_eq!(*log.lock().unwrap(), vec![(0, 10), (10, 20), (20, 30)]);
+        let mut map = FastSlotMap::new();
+        for value in 0..5u64 {
+            map.insert(value);
+        }
+        assert_eq!(map.capacity(), 8);
+        let mut map = FastSlotMap::with_growth(GrowthStrategy::Custom(Arc::new(|capacity| capacity + 3)));
+        for value in 0..5u64 {
+            map.insert(value);
+        }
+        assert_eq!(map.capacity(), 6);
+    }
+
+    #[test]
+    fn concurrent_reads_never_tear() {
+        #[derive(Default, Clone, Copy, PartialEq, Debug)]
+        struct Wide([u64; 8]);
+        let map = Arc::new(ConcurrentSlotMap::with_capacity(64));
+        let keys: Vec<Key> = (0..8).map(|value| map.insert(Wide([value; 8])).unwrap()).collect();
+        let threads: Vec<_> = (0..4u64)
+            .map(|thread| {
+                let (map, keys) = (map.clone(), keys.clone());
+                std::thread::spawn(move || {
+                    for n in 0..20_000u64 {
+                        let key = keys[n as usize % keys.len()];
+                        if thread % 2 == 0 {
+                            map.write(key, Wide([n; 8]));
+                        } else if let Some(value) = map.read(key) {
+                            assert!(value.0.iter().all(|&word| word == value.0[0]));
+                        }
+                    }
+                })
+            })
+            .collect();
+        threads.into_iter().for_each(|thread| thread.join().unwrap());
+        assert_eq!(map.len(), 8);
+        let small = ConcurrentSlotMap::<u8>::with_capacity(1);
+        assert!(small.insert(1).is_some());
+        assert!(small.insert(2).is_none());
+    }
+
+    #[test]
+    fn indexed_map_follows_updates() {
+        let mut map = IndexedSlotMap::new(|entry: &(u32, i32)| entry.0);
+        let a = map.insert((1, 5));
+        let b = map.insert((2, 5));
+        let c = map.insert((1, 9));
+        assert_eq!(map.find_by(&2), Some(b));
+        assert_eq!(map.find_all_by(&1).collect::<Vec<_>>(), vec![a, c]);
+        map.update(a, |entry| entry.0 = 3);
+        assert_eq!(map.find_all_by(&1).collect::<Vec<_>>(), vec![c]);
+        assert_eq!(map.find_by(&3), Some(a));
+        map.remove(c);
+        assert_eq!(map.find_by(&1), None);
+        assert_eq!(map.len(), 2);
+    }
+
+    #[test]
+    fn unique_inserts_hand_back_the_value() {
+        let mut map = IndexedSlotMap::new(|entry: &(u32, u32)| entry.0);
+        let key = map.insert_unique((1, 1)).unwrap();
+        assert_eq!(map.insert_unique((1, 2)), Err((key, (1, 2))));
+        map.remove(key);
+        assert!(map.insert_unique((1, 2)).is_ok());
+    }
+
+    #[test]
+    fn spans_stay_contiguous() {
+        let mut map = FastSlotMap::new();
+        let single = map.insert(9u32);
+        let span = map.insert_contiguous(&[1, 2, 3, 4]);
+        assert_eq!(map.get_span(span), Some(&[1, 2, 3, 4][..]));
+        assert_eq!(map.get(span.key(2).unwrap()), Some(&3));
+        map.get_span_mut(span).unwrap()[0] = 10;
+        assert_eq!(map.get(span.base()), Some(&10));
+        map.remove(span.key(1).unwrap());
+        assert!(!map.contains_span(span));
+        assert!(!map.remove_span(span));
+        let other = map.insert_contiguous(&[5, 6]);
+        assert!(map.remove_span(other));
+        assert_eq!(map.get_span(other), None);
+        assert_eq!((map.len(), map.get(single)), (4, Some(&9)));
+    }
+
+    #[test]
+    fn absorb_remaps_keys() {
+        for fast in [true, false] {
+            let mut source = FastSlotMap::new();
+            let kept = source.insert(1u32);
+            let gone = source.insert(2);
+            source.remove(gone);
+            let mut map = FastSlotMap::new();
+            let own = (!fast).then(|| map.insert(0));
+            let remap = map.absorb(source);
+            assert_eq!(map.get(remap.remap(kept).unwrap()), Some(&1));
+            assert_eq!(map.len(), if fast { 1 } else { 2 });
+            if let Some(own) = own {
+                assert_eq!(remap.remap(gone), None);
+                assert_eq!(map.get(own), Some(&0));
+            }
+        }
+    }
+
+    #[test]
+    fn remaps_apply_to_secondary_maps() {
+        let mut map = FastSlotMap::new();
+        let mut other = FastSlotMap::new();
+        map.insert(1u32);
+        let gone = other.insert(10);
+        let kept = other.insert(20);
+        let mut names = SecondaryMap::new();
+        names.insert(gone, "one");
+        names.insert(kept, "two");
+        other.remove(gone);
+        let remap = map.absorb(other);
+        let mut keys = [gone, kept];
+        assert_eq!(remap.remap_slice(&mut keys), 1);
+        assert!(keys[0].is_null());
+        names.remap_keys(&remap);
+        assert_eq!(names.len(), 1);
+        assert_eq!(names.get(keys[1]), Some(&"two"));
+        assert_eq!(map.get(keys[1]), Some(&20));
+    }
+
+    #[test]
+    fn pins_block_removal_until_dropped() {
+        let mut map = FastSlotMap::new();
+        let key = map.insert(1u32);
+        let (first, second) = (map.pin(key).unwrap(), map.pin(key).unwrap());
+        assert!(map.is_pinned(key));
+        assert_eq!(map.remove(key), None);
+        std::thread::spawn(move || drop(first)).join().unwrap();
+        assert_eq!(map.remove(key), None);
+        drop(second);
+        assert_eq!(map.remove(key), Some(1));
+        assert!(map.pin(key).is_none());
+        let span = map.insert_contiguous(&[1, 2]);
+        let _pin = map.pin(span.key(1).unwrap()).unwrap();
+        assert!(!map.remove_span(span));
+        assert_eq!(map.len(), 2);
+    }
+
+    #[test]
+    fn dirty_ranges_cover_writes_and_removals() {
+        let mut map = FastSlotMap::new();
+        let keys: Vec<Key> = (0..200u32).map(|value| map.insert(value)).collect();
+        map.set_dirty_tracking(true);
+        for index in [3, 4, 63, 64, 127] {
+            *map.get_mut(keys[index]).unwrap() = 1;
+        }
+        map.remove(keys[199]);
+        assert_eq!(map.take_dirty_ranges(), vec![3..5, 63..65, 127..128, 199..200]);
+        assert!(map.take_dirty_ranges().is_empty());
+        map.insert_contiguous(&[0; 70]);
+        assert_eq!(map.take_dirty_ranges(), vec![200..270]);
+    }
+
+    #[cfg(feature = "slab")]
+    #[test]
+    fn slab_entries_keep_their_index() {
+        let mut slab = slab::Slab::new();
+        slab.insert(1u32);
+        let vacated = slab.insert(2);
+        slab.insert(3);
+        slab.remove(vacated);
+        let mut map: FastSlotMap<u32> = slab.into();
+        assert_eq!(map.len(), 2);
+        assert_eq!(map.get(Key::from_slab_index(2)), Some(&3));
+        assert_eq!(map.get(Key::from_slab_index(1)), None);
+        let key = map.insert(7);
+        assert_ne!(key, Key::from_slab_index(1));
+        assert_eq!(map.try_remove(key), Some(7));
+    }
+
+    #[cfg(feature = "slotmap")]
+    #[test]
+    fn slotmap_keys_stay_valid() {
+        use slotmap::Key as _;
+        let mut source = slotmap::SlotMap::new();
+        let removed = source.insert(1u32);
+        let kept = source.insert(2);
+        source.remove(removed);
+        let map: FastSlotMap<u32> = source.into();
+        assert!(map.contains_key(Key::from(kept.data())));
+        assert_eq!(map.get(Key::from(kept.data())), Some(&2));
+        assert!(!map.contains_key(Key::from(removed.data())));
+        let back: slotmap::KeyData = Key::from(kept.data()).into();
+        assert_eq!(back, kept.data());
+    }
+
+    #[test]
+    fn free_list_is_inspectable() {
+        let mut map = FastSlotMap::new();
+        let keys: Vec<Key> = (0..6u32).map(|value| map.insert(value)).collect();
+        map.remove(keys[1]);
+        map.remove(keys[4]);
+        assert_eq!(map.free_slots().collect::<Vec<_>>(), vec![4, 1]);
+        let report = map.debug_validate();
+        assert!(report.is_valid());
+        assert_eq!((report.slots, report.live, report.free), (6, 4, 2));
+        assert!(FastSlotMap::<u32>::new().debug_validate().is_valid());
+    }
+
+    #[test]
+    fn custom_storage_backends_work() {
+        // Values split over fixed-size pages
+        struct Paged(Vec<Vec<u32>>);
+        impl Index<usize> for Paged {
+            type Output = u32;
+            fn index(&self, index: usize) -> &u32 {
+                &self.0[index / 4][index % 4]
+            }
+        }
+        impl IndexMut<usize> for Paged {
+            fn index_mut(&mut self, index: usize) -> &mut u32 {
+                &mut self.0[index / 4][index % 4]
+            }
+        }
+        impl Storage<u32> for Paged {
+            fn len(&self) -> usize {
+                self.0.iter().map(Vec::len).sum()
+            }
+            fn push(&mut self, value: u32) {
+                if self.0.last().is_none_or(|page| page.len() == 4) {
+                    self.0.push(Vec::with_capacity(4));
+                }
+                self.0.last_mut().unwrap().push(value);
+            }
+            fn get(&self, index: usize) -> Option<&u32> {
+                self.0.get(index / 4)?.get(index % 4)
+            }
+            fn get_mut(&mut self, index: usize) -> Option<&mut u32> {
+                self.0.get_mut(index / 4)?.get_mut(index % 4)
+            }
+            fn swap(&mut self, a: usize, b: usize) {
+                let value = self[a];
+                self[a] = self[b];
+                self[b] = value;
+            }
+        }
+        let mut map = FastSlotMap::with_storage(Paged(Vec::new()));
+        let keys: Vec<Key> = (0..10u32).map(|value| map.insert(value)).collect();
+        assert_eq!(map.get(keys[9]), Some(&9));
+        map.remove(keys[5]);
+        let key = map.insert(50);
+        assert_eq!((map.get(key), map.len()), (Some(&50), 10));
+        let span = map.insert_contiguous(&[1, 2, 3]);
+        assert!(map.contains_span(span));
+    }
+
+    #[test]
+    fn cow_clones_share_until_written() {
+        let mut map = FastSlotMap::with_storage(CowStorage::<u64, 4>::new());
+        let keys: Vec<Key> = (0..10u64).map(|value| map.insert(value)).collect();
+        let snapshot = map.clone();
+        *map.get_mut(keys[1]).unwrap() = 100;
+        map.remove(keys[9]);
+        assert_eq!((snapshot.get(keys[1]), map.get(keys[1])), (Some(&1), Some(&100)));
+        assert_eq!((snapshot.get(keys[9]), map.get(keys[9])), (Some(&9), None));
+        assert_eq!((snapshot.len(), map.len()), (10, 9));
+    }
+
+    #[test]
+    fn split_reads_every_other_entry() {
+        let mut map = FastSlotMap::new();
+        let keys: Vec<Key> = (0..5u32).map(|value| map.insert(value * 10)).collect();
+        map.remove(keys[4]);
+        let (current, view) = map.split(keys[2]).unwrap();
+        *current = view.get(keys[1]).unwrap() + view.get(keys[3]).unwrap();
+        assert_eq!((view.get(keys[2]), view.get(keys[4])), (None, None));
+        assert_eq!((view.get(keys[0]), view.excluded()), (Some(&0), keys[2]));
+        assert_eq!(map.get(keys[2]), Some(&40));
+        assert!(map.split(keys[4]).is_none());
+    }
+
+    #[test]
+    fn dense_positions_follow_relocations() {
+        let moves = Arc::new(Mutex::new(Vec::new()));
+        let mut map = DenseSlotMap::new();
+        let log = moves.clone();
+        map.set_relocation_callback(move |key, from, to| log.lock().unwrap().push((key, from, to)));
+        let (a, b, c) = (map.insert("a"), map.insert("b"), map.insert("c"));
+        assert_eq!(map.remove(a), Some("a"));
+        assert_eq!(*moves.lock().unwrap(), vec![(c, 2, 0)]);
+        assert_eq!((map.position_of(c), map.position_of(b), map.position_of(a)), (Some(0), Some(1), None));
+        assert_eq!(map.values(), &["c", "b"]);
+        map.remove(b);
+        assert_eq!(moves.lock().unwrap().len(), 1);
+        assert_eq!(map.keys(), &[c]);
+    }
+
+    #[test]
+    fn chunks_report_occupancy() {
+        let mut map = FastSlotMap::new();
+        let keys: Vec<Key> = (0..10).map(|value| map.insert(value as f32)).collect();
+        map.remove(keys[1]);
+        map.remove(keys[8]);
+        let chunks: Vec<_> = map.iter_chunks::<4>().collect();
+        assert_eq!(chunks.len(), 3);
+        assert_eq!((chunks[0].occupancy(), chunks[2].occupancy()), (0b1101, 0b10));
+        assert_eq!(chunks[2].values().len(), 2);
+        assert_eq!((chunks[1].key(1), chunks[0].key(1)), (Some(keys[5]), None));
+        assert_eq!(chunks.iter().map(|chunk| chunk.iter().count()).sum::<usize>(), 8);
+        let sum: f32 = map.iter_chunks::<64>().flat_map(|chunk| chunk.iter().map(|(_, &value)| value).collect::<Vec<_>>()).sum();
+        assert_eq!(sum, 36.0);
+    }
+
+    #[test]
+    fn atomic_map_survives_churn() {
+        let map = Arc::new(AtomicSlotMap::<u32>::with_capacity(8));
+        let key = map.insert(5).unwrap();
+        assert!(map.write(key, 6));
+        assert_eq!(map.remove(key), Some(6));
+        assert_eq!((map.get(key), map.write(key, 1), map.remove(key)), (None, false, None));
+        let threads: Vec<_> = (0..4u32)
+            .map(|thread| {
+                let map = map.clone();
+                std::thread::spawn(move || {
+                    for n in 0..10_000u32 {
+                        if let Some(key) = map.insert(n ^ thread) {
+                            assert_eq!(map.get(key), Some(n ^ thread));
+                            assert_eq!(map.remove(key), Some(n ^ thread));
+                        }
+                    }
+                })
+            })
+            .collect();
+        threads.into_iter().for_each(|thread| thread.join().unwrap());
+        assert_eq!(map.len(), 0);
+        let floats = AtomicSlotMap::<f32>::with_capacity(1);
+        assert_eq!(floats.get(floats.insert(1.5).unwrap()), Some(1.5));
+        assert!(floats.insert(2.0).is_none());
+    }
+
+    #[test]
+    fn stable_key_bytes_round_trip() {
+        let mut map = FastSlotMap::new();
+        for _ in 0..300 {
+            let key = map.insert(1u8);
+            map.remove(key);
+        }
+        let key = map.insert(3);
+        let bytes = key.to_stable_bytes();
+        assert_eq!(bytes, [1, 0, 0, 0, 0, 44, 1, 0, 0]);
+        assert_eq!(Key::from_stable_bytes(&bytes), Some(key));
+        assert_eq!(Key::from_stable_bytes(&bytes[..STABLE_KEY_LEN - 1]), None);
+        let mut unknown = bytes;
+        unknown[0] = 2;
+        assert_eq!(Key::from_stable_bytes(&unknown), None);
+    }
+
+    #[test]
+    fn raw_inserts_rebuild_a_map() {
+        let mut source = FastSlotMap::new();
+        let keys: Vec<Key> = (0..6u32).map(|value| source.insert(value)).collect();
+        source.remove(keys[2]);
+        source.remove(keys[0]);
+        let reused = source.insert(20);
+        source.remove(keys[5]);
+        // Keys are read back out of a save file, so they carry no map id
+        let strip = |key: Key| Key::from_ffi(key.as_ffi());
+        let live = [(strip(keys[1]), 1), (strip(reused), 20), (strip(keys[3]), 3), (strip(keys[4]), 4)];
+        let mut map = FastSlotMap::new();
+        for &(key, value) in live.iter().rev() {
+            assert_eq!(map.insert_at_raw(key.index, key.generation, value), Ok(key));
+            assert!(map.debug_validate().is_valid());
+        }
+        assert!(live.iter().all(|&(key, value)| map.get(key) == Some(&value)));
+        assert!(matches!(map.insert_at_raw(1, 0, 9), Err(SlotError::Occupied { .. })));
+        assert_eq!(map.insert_at_raw(u32::MAX, 0, 1), Err(SlotError::OutOfBounds));
+        let fresh = [map.insert(100), map.insert(200)];
+        assert_eq!((map.get(fresh[0]), map.get(fresh[1])), (Some(&100), Some(&200)));
+        assert!(map.debug_validate().is_valid());
+    }
+
+    #[test]
+    fn writes_bump_the_write_version() {
+        let mut map = FastSlotMap::new();
+        let old = map.insert(1u32);
+        assert_eq!(map.write_version(old), None);
+        map.set_write_versioning(true);
+        assert_eq!(map.write_version(old), Some(0));
+        let key = map.insert(2);
+        let inserted = map.write_version(key).unwrap();
+        map.get(key);
+        assert_eq!(map.write_version(key), Some(inserted));
+        *map.get_mut(key).unwrap() += 1;
+        assert!(map.write_version(key).unwrap() > inserted);
+        assert_eq!(map.write_version(old), Some(0));
+        map.remove(key);
+        assert_eq!(map.write_version(key), None);
+    }
+
+    #[test]
+    fn compaction_steps_move_entries_down() {
+        let mut map = FastSlotMap::new();
+        let keys: Vec<Key> = (0..12u32).map(|value| map.insert(value)).collect();
+        let mut live: Vec<(Key, u32)> = keys.iter().copied().zip(0..).filter(|&(_, value)| value % 3 == 2).collect();
+        for (key, value) in keys.iter().zip(0..) {
+            if value % 3 != 2 {
+                map.remove(*key);
+            }
+        }
+        let pinned = live[3].0;
+        let _pin = map.pin(pinned).unwrap();
+        loop {
+            let progress = map.compact_step(1);
+            for (key, _) in &mut live {
+                *key = progress.remap.remap(*key).unwrap();
+            }
+            assert!(map.debug_validate().is_valid());
+            assert!(live.iter().all(|&(key, value)| map.get(key) == Some(&value)));
+            if progress.finished {
+                break;
+            }
+        }
+        assert!(!map.is_compacting());
+        // The pinned entry stays put; the rest fill the lowest slots
+        assert_eq!(live[3].0, pinned);
+        let mut indices: Vec<u32> = live.iter().map(|(key, _)| key.index).collect();
+        indices.sort_unstable();
+        assert_eq!(indices, vec![0, 1, 2, 11]);
+        assert!(keys.iter().all(|&key| live.iter().any(|&(moved, _)| moved == key) || map.get(key).is_none()));
+    }
+
+    #[test]
+    fn registry_holds_one_map_per_type() {
+        let mut registry = SlotMapRegistry::new();
+        let position = registry.map_mut::<[f32; 2]>().insert([1.0, 2.0]);
+        let speed = registry.map_mut::<f32>().insert(2.0);
+        assert_eq!(Key::from_ffi(position.as_ffi()), Key::from_ffi(speed.as_ffi()));
+        assert_eq!(registry.get_map::<[f32; 2]>().unwrap().get(position), Some(&[1.0, 2.0]));
+        assert!(registry.get_map::<u32>().is_none());
+        *registry.get_map_mut::<f32>().unwrap().get_mut(speed).unwrap() = 3.0;
+        assert_eq!(registry.remove_everywhere(Key::from_ffi(position.as_ffi())), 2);
+        assert_eq!(registry.remove_everywhere(Key::from_ffi(position.as_ffi())), 0);
+        assert!(registry.insert_map(FastSlotMap::<f32>::new()).is_some());
+        assert!(registry.remove_map::<[f32; 2]>().is_some());
+        assert_eq!(registry.len(), 1);
+        assert!(registry.contains_map::<f32>());
+    }
+
+    #[cfg(feature = "derive")]
+    #[derive(Clone, Copy, PartialEq, Debug, SlotMapStruct)]
+    pub struct Particle {
+        pos: [f32; 2],
+        vel: [f32; 2],
+        life: f32,
+    }
+
+    #[cfg(feature = "derive")]
+    #[test]
+    fn soa_columns_share_keys() {
+        let mut map = ParticleSlotMap::new();
+        let a = map.insert(Particle { pos: [1.0, 0.0], vel: [1.0, 1.0], life: 2.0 });
+        let b = map.insert(Particle { pos: [2.0, 0.0], vel: [0.0, 1.0], life: 3.0 });
+        let velocities: Vec<(Key, [f32; 2])> = map.iter_vel().map(|(key, &vel)| (key, vel)).collect();
+        for ((_, pos), (_, vel)) in map.iter_pos_mut().zip(&velocities) {
+            pos[0] += vel[0];
+        }
+        map.iter_vel_mut().for_each(|(_, vel)| vel[1] = 0.0);
+        map.iter_life_mut().for_each(|(_, life)| *life -= 1.0);
+        assert_eq!(map.get(a), Some(Particle { pos: [2.0, 0.0], vel: [1.0, 0.0], life: 1.0 }));
+        assert_eq!(map.remove(b).map(|particle| particle.life), Some(2.0));
+        assert!(!map.contains(b));
+        assert_eq!((map.len(), map.is_empty(), map.iter_life().count()), (1, false, 1));
+        assert_eq!(map.iter_pos().map(|(key, _)| key).collect::<Vec<_>>(), vec![a]);
+        *map.life_mut(a).unwrap() = 9.0;
+        assert_eq!((map.life(a), map.vel(b)), (Some(&9.0), None));
+    }
+
+    #[test]
+    fn double_buffer_readers_see_published_state() {
+        let mut map = DoubleBufferedSlotMap::new();
+        let reader = map.reader();
+        let a = map.insert(1u64);
+        assert_eq!((reader.get(a), map.get(a)), (None, Some(&1)));
+        map.publish();
+        assert_eq!(reader.get(a), Some(1));
+        *map.get_mut(a).unwrap() = 5;
+        assert_eq!(reader.get(a), Some(1));
+        map.publish();
+        assert_eq!(reader.get(a), Some(5));
+        let b = map.insert(7);
+        map.remove(a);
+        map.publish();
+        assert!(!reader.contains(a));
+        assert_eq!(reader.get(b), Some(7));
+        let keys: Vec<Key> = (0..16).map(|value| map.insert(value)).collect();
+        map.publish();
+        let stop = Arc::new(AtomicBool::new(false));
+        let threads: Vec<_> = (0..2)
+            .map(|_| {
+                let (reader, keys, stop) = (map.reader(), keys.clone(), stop.clone());
+                std::thread::spawn(move || {
+                    while !stop.load(Ordering::Relaxed) {
+                        // Every publish moves all entries together
+                        reader.read(|map| assert_eq!(map.get(keys[15]).unwrap() - map.get(keys[0]).unwrap(), 15));
+                    }
+                })
+            })
+            .collect();
+        for _ in 0..200 {
+            keys.iter().for_each(|&key| *map.get_mut(key).unwrap() += 1);
+            map.publish();
+        }
+        stop.store(true, Ordering::Relaxed);
+        threads.into_iter().for_each(|thread| thread.join().unwrap());
+        assert_eq!(reader.get(keys[0]), Some(200));
+    }
+
+    #[test]
+    fn positional_queries_skip_vacant_slots() {
+        let mut map = FastSlotMap::new();
+        assert!(map.first().is_none() && map.max_key().is_none());
+        let keys: Vec<Key> = (0..10u32).map(|value| map.insert(value)).collect();
+        assert_eq!((map.first(), map.last()), (Some((keys[0], &0)), Some((keys[9], &9))));
+        map.remove(keys[0]);
+        map.remove(keys[9]);
+        assert_eq!((map.min_key(), map.max_key()), (Some(keys[1]), Some(keys[8])));
+    }
+
+    #[test]
+    fn prefaulting_keeps_contents() {
+        let mut map = FastSlotMap::new();
+        map.set_dirty_tracking(true);
+        map.set_write_versioning(true);
+        let key = map.insert(3u64);
+        map.reserve(10_000);
+        assert!(map.capacity() >= 10_001);
+        map.touch_all_pages();
+        assert_eq!(map.get(key), Some(&3));
+        let mut shared = FastSlotMap::with_storage(CowStorage::<u64, 64>::new());
+        shared.reserve(1000);
+        let key = shared.insert(1);
+        let snapshot = shared.clone();
+        shared.touch_all_pages();
+        assert_eq!((shared.get(key), snapshot.get(key)), (Some(&1), Some(&1)));
+    }
+
+    #[test]
+    fn read_guards_hold_their_slot() {
+        let map = ConcurrentSlotMap::<[u64; 4]>::with_capacity(2);
+        let a = map.insert([1; 4]).unwrap();
+        let guard = map.read_guard(a).unwrap();
+        assert_eq!(map.remove(a), Some([1; 4]));
+        assert!(map.read_guard(a).is_none());
+        map.insert([2; 4]).unwrap();
+        // The guarded slot isn't reused until the guard drops
+        assert!(map.insert([3; 4]).is_none());
+        assert_eq!(*guard, [1; 4]);
+        drop(guard);
+        assert!(map.insert([3; 4]).is_some());
+        assert!(map.read_guard(a).is_none());
+    }
+
+    #[cfg(feature = "crossbeam-epoch")]
+    #[test]
+    fn epoch_reads_outlive_removal() {
+        let map = EpochSlotMap::with_capacity(4);
+        let key = map.insert("a".to_string()).unwrap();
+        assert_eq!(map.read(key, |value| value.clone()), Some("a".to_string()));
+        assert!(map.write(key, "b".to_string()));
+        let guard = crossbeam_epoch::pin();
+        let value = map.get(key, &guard).unwrap();
+        assert!(map.remove(key));
+        assert!(!map.remove(key));
+        assert_eq!(value, "b");
+        assert!(!map.contains(key) && map.get(key, &guard).is_none());
+        assert!(!map.write(key, "x".to_string()));
+        assert_ne!(map.insert("c".to_string()).unwrap(), key);
+        assert_eq!(map.len(), 1);
+    }
+
+    #[test]
+    fn sorted_application_reports_stale_keys() {
+        let mut map = FastSlotMap::new();
+        let keys: Vec<Key> = (0..10u32).map(|value| map.insert(value)).collect();
+        map.remove(keys[3]);
+        let mut seen = Vec::new();
+        let stale = map.apply_sorted(&[keys[7], keys[3], keys[1], keys[5]], |key, value| {
+            seen.push(key);
+            *value += 100;
+        });
+        assert_eq!((seen, stale), (vec![keys[1], keys[5], keys[7]], vec![keys[3]]));
+        assert_eq!(map.get(keys[7]), Some(&107));
+    }
+
+    #[cfg(feature = "capi")]
+    mod capi {
+        crate::export_capi! {
+            u32 => {
+                new: test_map_new, free: test_map_free, insert: test_map_insert,
+                get: test_map_get, remove: test_map_remove,
+                contains: test_map_contains, len: test_map_len,
+            }
+        }
+
+        #[test]
+        fn garbage_keys_fail_without_unwinding() {
+            unsafe {
+                let map = test_map_new();
+                let key = test_map_insert(map, 7);
+                let mut out = 0;
+                assert!(test_map_get(map, key, &mut out));
+                assert_eq!(out, 7);
+                (*map).set_invalid_key_policy(crate::InvalidKeyPolicy::Panic);
+                for garbage in [u64::MAX, 0x0000_0003_0000_0000, key + 1] {
+                    assert!(!test_map_get(map, garbage, &mut out));
+                    assert!(!test_map_remove(map, garbage, std::ptr::null_mut()));
+                    assert!(!test_map_contains(map, garbage));
+                }
+                assert!(test_map_remove(map, key, &mut out));
+                assert!(!test_map_remove(map, key, &mut out));
+                assert_eq!(test_map_len(map), 0);
+                assert_eq!(test_map_insert(std::ptr::null_mut(), 1), crate::Key::null().as_ffi());
+                test_map_free(map);
+            }
+        }
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn growth_and_compaction_are_traced() {
+        // Collects the message of every event
+        struct Events(Arc<Mutex<Vec<String>>>);
+        struct Message(String);
+        impl tracing::field::Visit for Message {
+            fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+                if field.name() == "message" {
+                    self.0 = format!("{value:?}");
+                }
+            }
+        }
+        impl tracing::Subscriber for Events {
+            fn enabled(&self, _: &tracing::Metadata<'_>) -> bool {
+                true
+            }
+            fn new_span(&self, _: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+                tracing::span::Id::from_u64(1)
+            }
+            fn record(&self, _: &tracing::span::Id, _: &tracing::span::Record<'_>) {}
+            fn record_follows_from(&self, _: &tracing::span::Id, _: &tracing::span::Id) {}
+            fn event(&self, event: &tracing::Event<'_>) {
+                let mut message = Message(String::new());
+                event.record(&mut message);
+                self.0.lock().unwrap().push(message.0);
+            }
+            fn enter(&self, _: &tracing::span::Id) {}
+            fn exit(&self, _: &tracing::span::Id) {}
+        }
+        let events = Arc::new(Mutex::new(Vec::new()));
+        tracing::subscriber::with_default(Events(events.clone()), || {
+            let mut map = FastSlotMap::new();
+            let key = map.insert(1u32);
+            map.insert(2);
+            map.remove(key);
+            map.compact();
+            map.set_invalid_key_policy(InvalidKeyPolicy::Log);
+            map.get(key);
+        });
+        let events = events.lock().unwrap();
+        for message in ["slot map grew", "slot map compaction step", "invalid slot map key"] {
+            assert!(events.iter().any(|event| event == message), "no {message:?} in {events:?}");
+        }
+    }
+
+    #[test]
+    fn iter_mut_items_outlive_later_items() {
+        let mut map = FastSlotMap::new();
+        for i in 0..6u32 {
+            map.insert(i);
+        }
+        let mut values: Vec<&mut u32> = map.iter_mut().rev().map(|(_, value)| value).collect();
+        for value in values.iter_mut() {
+            **value *= 2;
+        }
+        let (low, high) = values.split_at_mut(3);
+        for (a, b) in low.iter_mut().zip(high.iter_mut().rev()) {
+            std::mem::swap(*a, *b);
+        }
+        let mut iter = map.iter_mut();
+        let (_, first) = iter.next().unwrap();
+        let (_, last) = iter.next_back().unwrap();
+        *first += 1;
+        *last += 1;
+        *first += 1;
+        assert_eq!(map.iter().map(|(_, value)| *value).collect::<Vec<_>>(), vec![12, 8, 6, 4, 2, 1]);
+    }
+
+    #[test]
+    fn cursors_resume_across_mutations() {
+        let mut map = FastSlotMap::new();
+        let keys: Vec<Key> = (0..10u32).map(|value| map.insert(value)).collect();
+        let mut cursor = Cursor::new();
+        let mut existing = Cursor::existing(&map);
+        assert_eq!(cursor.next_batch(&map, 3), keys[0..3].to_vec());
+        map.remove(keys[3]);
+        let reused = map.insert(99);
+        assert_eq!(cursor.next_batch(&map, 2), vec![reused, keys[4]]);
+        let late = map.insert(100);
+        assert_eq!(cursor.next_batch(&map, 100).last(), Some(&late));
+        assert!(cursor.is_done(&map));
+        // Slots added after `existing` was made are left out
+        let all = existing.next_batch(&map, 100);
+        assert_eq!(all.len(), 10);
+        assert!(!all.contains(&late) && existing.is_done(&map));
+        cursor.reset();
+        assert_eq!(cursor.position(), 0);
+    }
+
+    #[test]
+    fn over_aligned_values_stay_aligned() {
+        #[derive(Default, Clone, Copy)]
+        #[repr(align(128))]
+        struct Wide(u8);
+        let mut map = FastSlotMap::new();
+        let keys: Vec<Key> = (0..10u32).map(|value| map.insert(CachePadded(value))).collect();
+        assert!(keys.iter().all(|&key| (map.get(key).unwrap() as *const _ as usize).is_multiple_of(64)));
+        let mut paged = FastSlotMap::with_storage(CowStorage::<Wide, 3>::new());
+        let keys: Vec<Key> = (0..10).map(|_| paged.insert(Wide(1))).collect();
+        assert!(keys.iter().all(|&key| (paged.get(key).unwrap() as *const _ as usize).is_multiple_of(128)));
+        assert_eq!(paged.get(keys[9]).map(|wide| wide.0), Some(1));
+        let concurrent = ConcurrentSlotMap::<CachePadded<u64>>::with_capacity(4);
+        let key = concurrent.insert(5.into()).unwrap();
+        assert_eq!(*concurrent.read(key).unwrap(), 5);
+        assert_eq!(std::mem::size_of::<CachePadded<u8>>(), 64);
+    }
+
+    #[test]
+    fn disjoint_borrows_coexist() {
+        let mut map = FastSlotMap::new();
+        let keys: Vec<Key> = (0..4u32).map(|i| map.insert(i)).collect();
+        let [a, b, c] = map.get_disjoint_mut([keys[2], keys[0], keys[3]]).unwrap();
+        *a += 10;
+        *b += 20;
+        *c += 30;
+        *a += 1;
+        assert_eq!(map.iter().map(|(_, value)| *value).collect::<Vec<_>>(), vec![20, 1, 13, 33]);
+        assert!(map.get_disjoint_mut([keys[1], keys[1]]).is_none());
+
+        let mut cow: FastSlotMap<u32, CowStorage<u32, 2>> = FastSlotMap::with_storage(CowStorage::new());
+        let keys: Vec<Key> = (0..4u32).map(|i| cow.insert(i)).collect();
+        let shared = cow.clone();
+        let (a, b) = cow.get2_mut(keys[1], keys[0]).unwrap();
+        *a += 10;
+        *b += 10;
+        *a += 1;
+        assert_eq!(cow.iter().map(|(_, value)| *value).collect::<Vec<_>>(), vec![10, 12, 2, 3]);
+        assert_eq!(shared.iter().map(|(_, value)| *value).collect::<Vec<_>>(), vec![0, 1, 2, 3]);
+
+        let mut boxed: FastSlotMap<[u64; 4], OverflowStorage<[u64; 4], 8>> = FastSlotMap::with_storage(OverflowStorage::new());
+        let (x, y) = (boxed.insert([1; 4]), boxed.insert([2; 4]));
+        let (a, b) = boxed.get2_mut(y, x).unwrap();
+        a[0] = 5;
+        b[0] = 6;
+        a[1] = 7;
+        assert_eq!(boxed[x], [6, 1, 1, 1]);
+        assert_eq!(boxed[y], [5, 7, 2, 2]);
+    }
+
+    #[test]
+    fn stale_keys_dont_refresh_idle_entries() {
+        let now = Arc::new(AtomicU64::new(0));
+        let clock = now.clone();
+        let mut map = IdleSlotMap::new(move || clock.load(Ordering::Relaxed));
+        let old = map.insert(1u32);
+        map.remove(old);
+        let session = map.insert(2);
+        now.store(100, Ordering::Relaxed);
+        assert_eq!(map.get_mut(old), None);
+        assert_eq!(map.get(old), None);
+        assert!(!map.touch(old));
+        assert_eq!(map.last_access(session), Some(0));
+        assert_eq!(map.remove_idle(50), vec![(session, 2)]);
+        let fresh = map.insert(3);
+        now.store(200, Ordering::Relaxed);
+        *map.get_mut(fresh).unwrap() += 1;
+        assert_eq!(map.last_access(fresh), Some(200));
+    }
+
+    #[test]
+    fn content_hash_ignores_history() {
+        let mut map = FastSlotMap::new();
+        let mut hashed = HashedSlotMap::new();
+        assert_eq!(map.content_hash(), 0);
+        let keys: Vec<Key> = (0..10u32).map(|value| map.insert(value)).collect();
+        (0..10u32).for_each(|value| assert_eq!(hashed.insert(value), Key::from_ffi(keys[value as usize].as_ffi())));
+        assert_eq!(map.content_hash(), hashed.content_hash());
+        map.remove(keys[3]);
+        hashed.remove(Key::from_ffi(keys[3].as_ffi()));
+        *map.get_mut(keys[4]).unwrap() = 77;
+        hashed.update(Key::from_ffi(keys[4].as_ffi()), |value| *value = 77);
+        assert_eq!(map.content_hash(), hashed.content_hash());
+        let mut other = FastSlotMap::new();
+        let keys: Vec<Key> = (0..10u32).map(|value| other.insert(value)).collect();
+        other.remove(keys[3]);
+        assert_ne!(map.content_hash(), other.content_hash());
+        *other.get_mut(keys[4]).unwrap() = 77;
+        assert_eq!(map.content_hash(), other.content_hash());
+    }
+
+    #[test]
+    fn keys_from_other_maps_are_caught() {
+        let mut map = FastSlotMap::new();
+        let mut other = FastSlotMap::new();
+        let key = map.insert(1u32);
+        let foreign = other.insert(2u32);
+        assert_eq!(key, foreign);
+        assert_eq!(map.clone().get(key), Some(&1));
+        // Keys read back from bytes carry no map id and pass anywhere
+        assert_eq!(map.get(Key::from_stable_bytes(&foreign.to_stable_bytes()).unwrap()), Some(&1));
+        let result = catch_unwind(AssertUnwindSafe(|| map.get(foreign).copied()));
+        assert_eq!(result.is_err(), cfg!(all(debug_assertions, feature = "key-provenance")));
+        // Keys met while walking a map are stamped like inserted ones
+        let excluded = other.split(foreign).unwrap().1.excluded();
+        let walked_mut = other.iter_mut().next().unwrap().0;
+        let walked = [
+            other.iter().next().unwrap().0,
+            other.first().unwrap().0,
+            other.last().unwrap().0,
+            other.iter_chunks::<8>().next().unwrap().key(0).unwrap(),
+            Cursor::new().next_batch(&other, 1)[0],
+            excluded,
+            walked_mut,
+        ];
+        for key in walked {
+            let result = catch_unwind(AssertUnwindSafe(|| map.contains(key)));
+            assert_eq!(result.is_err(), cfg!(all(debug_assertions, feature = "key-provenance")));
+        }
+    }
+
+    #[test]
+    fn shard_removes_go_through_the_map() {
+        let finalized = Arc::new(Mutex::new(Vec::new()));
+        let mut map = FastSlotMap::new();
+        let log = finalized.clone();
+        map.set_finalizer(move |_, value| log.lock().unwrap().push(value));
+        let keys: Vec<Key> = (0..8u32).map(|value| map.insert(value)).collect();
+        map.set_tombstones(true);
+        map.remove(keys[0]);
+        map.remove(keys[5]);
+        let inserted = map.scope(2, |mut shards| {
+            let moved = Key::from_ffi(keys[1].as_ffi());
+            assert_eq!(shards[0].remove(moved), Some(1));
+            assert_eq!(shards[0].get(moved), None);
+            // Neither the removed slot nor the tombstone is handed out again
+            assert_eq!(shards[0].vacant(), 0);
+            assert_eq!(shards[0].insert(10), None);
+            assert_eq!(shards[1].vacant(), 0);
+            shards[1].remove(Key::from_ffi(keys[6].as_ffi()))
+        });
+        assert_eq!(inserted, Some(6));
+        assert_eq!(*finalized.lock().unwrap(), vec![0, 5, 1, 6]);
+        assert_eq!(map.len(), 4);
+        assert_eq!(map.get_removed(keys[1]), Some(&1));
+        assert_eq!(map.get_removed(keys[0]), Some(&0));
+        assert_eq!(map.purge_tombstones(), 4);
+        assert!(map.debug_validate().is_valid());
+    }
+
+    #[test]
+    fn shard_inserts_are_counted() {
+        let mut map = FastSlotMap::new();
+        let keys: Vec<Key> = (0..4u32).map(|value| map.insert(value)).collect();
+        map.remove(keys[1]);
+        map.remove(keys[3]);
+        map.set_alive_tracking(true);
+        let new = map.scope(2, |mut shards| [shards[0].insert(7).unwrap(), shards[1].insert(8).unwrap()]);
+        assert_eq!(map.len(), 4);
+        assert_eq!(map.get(new[0]), Some(&7));
+        assert!(map.contains(new[1]));
+        assert!(!map.contains(keys[3]));
+        assert!(map.debug_validate().is_valid());
+    }
+
+    #[test]
+    fn lowest_first_reuses_low_indices() {
+        let mut map = FastSlotMap::new();
+        let keys: Vec<Key> = (0..10u32).map(|value| map.insert(value)).collect();
+        map.set_free_list_policy(FreeListPolicy::LowestFirst);
+        for index in [7, 2, 5] {
+            map.remove(keys[index]);
+        }
+        assert_eq!(map.free_slots().collect::<Vec<_>>(), vec![2, 5, 7]);
+        assert_eq!(map.insert(0).index, 2);
+        map.remove(keys[0]);
+        assert_eq!(map.insert(0).index, 0);
+        assert!(map.debug_validate().is_valid());
+        map.set_free_list_policy(FreeListPolicy::Lifo);
+        assert_eq!(map.free_slots().collect::<Vec<_>>(), vec![5, 7]);
+        assert!(map.debug_validate().is_valid());
+    }
+
+    #[test]
+    fn fifo_reuses_oldest_slots() {
+        let mut map = FastSlotMap::with_free_list_policy(FreeListPolicy::Fifo);
+        let keys: Vec<Key> = (0..6u32).map(|value| map.insert(value)).collect();
+        for index in [4, 1, 3] {
+            map.remove(keys[index]);
+        }
+        assert_eq!(map.free_slots().collect::<Vec<_>>(), vec![4, 1, 3]);
+        let key = map.insert(9);
+        assert_eq!(key.index, 4);
+        map.remove(key);
+        assert_eq!(map.free_slots().collect::<Vec<_>>(), vec![1, 3, 4]);
+        map.insert_at_raw(3, 7, 1).unwrap();
+        assert_eq!(map.free_slots().collect::<Vec<_>>(), vec![1, 4]);
+        assert!(map.debug_validate().is_valid());
+    }
+
+    #[test]
+    fn alive_bits_reject_stale_keys() {
+        let mut map = FastSlotMap::new();
+        let keys: Vec<Key> = (0..10u32).map(|value| map.insert(value)).collect();
+        map.set_alive_tracking(true);
+        map.remove(keys[3]);
+        assert!(!map.contains(keys[3]) && map.contains(keys[4]));
+        let span = map.insert_contiguous(&[1, 2]);
+        assert!(span.keys().all(|key| map.contains(key)));
+        let mut batch = keys.clone();
+        map.retain_live(&mut batch);
+        assert_eq!(batch.len(), 9);
+        map.compact();
+        assert_eq!(map.iter().filter(|&(key, _)| map.contains(key)).count(), 11);
+        map.set_alive_tracking(false);
+        assert!(map.contains(keys[4]));
+    }
+
+    #[test]
+    fn sorted_iteration_orders_by_key() {
+        let mut map = FastSlotMap::new();
+        let keys: Vec<Key> = [(5, 0), (1, 1), (5, 2), (0, 3), (9, 4)].into_iter().map(|value| map.insert(value)).collect();
+        map.remove(keys[3]);
+        let order: Vec<u32> = map.iter_sorted_by_key(|value| value.0).map(|(_, value)| value.1).collect();
+        assert_eq!(order, vec![1, 0, 2, 4]);
+        let order: Vec<Key> = map.iter_sorted_by_key(|value| Reverse(value.0)).map(|(key, _)| key).collect();
+        assert_eq!(order, vec![keys[4], keys[0], keys[2], keys[1]]);
+        assert_eq!(FastSlotMap::<u32>::new().iter_sorted_by_key(|&value| value).count(), 0);
+    }
+
+    #[test]
+    fn find_stops_at_the_first_match() {
+        let mut map = FastSlotMap::new();
+        let keys: Vec<Key> = (0..10u32).map(|value| map.insert(value * 10)).collect();
+        map.remove(keys[3]);
+        assert_eq!((map.find(|&value| value >= 30), map.find(|&value| value == 30)), (Some(keys[4]), None));
+        assert_eq!(map.find_map(|key, &value| (value > 50).then_some((key, value))), Some((keys[6], 60)));
+        assert!(map.any(|&value| value == 90) && !map.any(|&value| value == 30));
+        assert!(map.all(|&value| value % 10 == 0) && !map.all(|&value| value > 0));
+        let mut visited = 0;
+        assert!(map.any(|&value| {
+            visited += 1;
+            value == 0
+        }));
+        assert_eq!(visited, 1);
+    }
+
+    #[test]
+    fn invalidate_all_voids_outstanding_keys() {
+        let mut map = FastSlotMap::new();
+        let keys: Vec<Key> = (0..8u32).map(|value| map.insert(value)).collect();
+        assert!(map.invalidate_all());
+        assert!(map.is_empty() && keys.iter().all(|&key| !map.contains(key)));
+        let fresh: Vec<Key> = (0..8u32).map(|value| map.insert(value)).collect();
+        assert!(fresh.iter().all(|key| !keys.contains(key)));
+        assert!(keys.iter().all(|&key| map.get(key).is_none() && map.remove(key).is_none()));
+        assert_eq!(map.len(), 8);
+        assert!(map.debug_validate().is_valid());
+    }
+
+    fn encode_u32(value: &u32, out: &mut Vec<u8>) {
+        out.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn decode_u32(bytes: &[u8]) -> Option<u32> {
+        Some(u32::from_le_bytes(bytes.try_into().ok()?))
+    }
+
+    #[test]
+    fn sequenced_load_restores_every_slot() {
+        let mut map = SequencedSlotMap::new();
+        let keys: Vec<Key> = (0..5u32).map(|i| map.insert(i)).collect();
+        map.remove(keys[0]);
+        map.remove(keys[3]);
+        map.remove(keys[4]);
+        let bytes = map.save(encode_u32);
+        let mut loaded = SequencedSlotMap::load(&bytes, decode_u32).unwrap();
+        // As stored alongside the save, without the issuing map's id
+        let keys: Vec<Key> = keys.iter().map(|key| Key::from_ffi(key.as_ffi())).collect();
+        for &key in &keys {
+            assert_eq!(loaded.get(key), map.get(key));
+            assert_eq!(loaded.sequence(key), map.sequence(key));
+        }
+        assert_eq!(loaded.save(encode_u32), bytes);
+        // Reused slots get fresh generations, so the removed keys stay stale
+        let fresh = loaded.insert(9);
+        assert_eq!(loaded.sequence(fresh), Some(5));
+        assert!(keys.iter().all(|&key| loaded.get(key) != Some(&9)));
+        assert!(loaded.as_map().debug_validate().is_valid());
+    }
+
+    #[test]
+    fn sequenced_load_rejects_wild_indices() {
+        let mut blob = Vec::new();
+        blob.extend_from_slice(&10u64.to_le_bytes());
+        blob.extend_from_slice(&0u32.to_le_bytes());
+        blob.extend_from_slice(&1u32.to_le_bytes());
+        blob.extend_from_slice(&0u64.to_le_bytes());
+        blob.extend_from_slice(&Key::from_ffi(0xFFFF_FFF0).to_stable_bytes());
+        blob.extend_from_slice(&4u32.to_le_bytes());
+        blob.extend_from_slice(&7u32.to_le_bytes());
+        assert!(SequencedSlotMap::load(&blob, decode_u32).is_none());
+        blob[8..12].copy_from_slice(&0xFFFF_FFF0u32.to_le_bytes());
+        assert!(SequencedSlotMap::load(&blob, decode_u32).is_none());
+    }
+
+    #[test]
+    fn remover_applies_queue_after_the_walk() {
+        let mut map = FastSlotMap::new();
+        let keys: Vec<Key> = (0..6u32).map(|i| map.insert(i)).collect();
+        let mut seen = Vec::new();
+        map.for_each_with_remover(|key, value, remover| {
+            *value *= 10;
+            seen.push(*value);
+            if *value == 20 {
+                remover.remove(key);
+                remover.remove(keys[5]);
+                remover.remove(key);
+            }
+        });
+        // Queued entries are still visited; they leave afterwards
+        assert_eq!(seen, vec![0, 10, 20, 30, 40, 50]);
+        assert_eq!(map.len(), 4);
+        assert_eq!(map.get(keys[2]), None);
+        assert_eq!(map.get(keys[5]), None);
+        assert_eq!(map.get(keys[4]), Some(&40));
+        map.for_each_with_remover(|_, _, remover| assert!(remover.is_empty()));
+        assert!(map.debug_validate().is_valid());
+    }
+
+    #[cfg(feature = "reuse-report")]
+    #[test]
+    fn reuse_report_counts_stale_keys() {
+        let mut map = FastSlotMap::new();
+        let keys: Vec<Key> = (0..10u32).map(|value| map.insert(value)).collect();
+        map.remove(keys[0]);
+        map.remove(keys[1]);
+        assert!(map.get(keys[0]).is_none() && map.remove(keys[1]).is_none() && map.get_mut(keys[0]).is_none());
+        let report = map.reuse_report();
+        assert_eq!((report.stale_keys, report.failed_lookups, report.generation_wraps), (2, 3, 0));
+        map.invalidate_all();
+        assert_eq!(map.reuse_report().stale_keys, 10);
+        let mut map = FastSlotMap::new();
+        let key = map.insert_at_raw(0, u32::MAX, 5u32).unwrap();
+        map.remove(key);
+        assert_eq!(map.reuse_report().generation_wraps, 1);
+    }
+
+    #[test]
+    fn quotas_cap_each_subsystem() {
+        let mut map = QuotaSlotMap::new();
+        let ai = map.add_quota("ai", 2);
+        let fx = map.add_quota("fx", 1);
+        let first = map.insert_for(ai, 1u32).unwrap();
+        map.insert_for(ai, 2).unwrap();
+        assert!(map.insert_for(ai, 3).is_none());
+        let effect = map.insert_for(fx, 4).unwrap();
+        assert!(map.insert_for(fx, 5).is_none());
+        let unlimited = map.insert(9);
+        assert_eq!((map.quota_of(first), map.quota_of(unlimited)), (Some(ai), None));
+        map.remove(first);
+        assert!(map.remove(first).is_none());
+        map.remove(unlimited);
+        assert_eq!(map.quota_usage(ai).used, 1);
+        let key = map.insert_for(ai, 7).unwrap();
+        assert_eq!(map.get(key), Some(&7));
+        let usage: Vec<(String, u32)> = map.quota_stats().map(|(_, usage)| (usage.name.clone(), usage.used)).collect();
+        assert_eq!(usage, vec![("ai".to_string(), 2), ("fx".to_string(), 1)]);
+        map.remove(effect);
+        map.set_quota_limit(fx, 0);
+        assert!(map.insert_for(fx, 1).is_none());
+    }
+
+    #[test]
+    fn secondary_maps_sync_with_their_primary() {
+        let mut primary = FastSlotMap::new();
+        let keys: Vec<Key> = (0..10u32).map(|value| primary.insert(value)).collect();
+        let mut names = SecondaryMap::new();
+        keys.iter().for_each(|&key| drop(names.insert(key, format!("{key:?}"))));
+        assert_eq!(names.sync_with(&primary), 0);
+        primary.remove(keys[2]);
+        primary.remove(keys[5]);
+        assert_eq!(names.sync_with(&primary), 2);
+        assert_eq!(names.len(), 8);
+        assert!(names.get(keys[2]).is_none());
+        assert_eq!(names.sync_with(&primary), 0);
+        names.insert(primary.insert(3), "new".to_string());
+        assert_eq!(names.sync_with(&primary), 0);
+        primary.invalidate_all();
+        assert_eq!(names.sync_with(&primary), 9);
+        assert!(names.is_empty());
+    }
+
+    #[test]
+    fn derived_maps_keep_keys() {
+        let mut map = FastSlotMap::new();
+        let keys: Vec<Key> = (0..10u32).map(|value| map.insert(value)).collect();
+        map.remove(keys[3]);
+        map.remove(keys[7]);
+        let doubled = map.map(|&value| value as f32 * 2.0);
+        assert!(keys.iter().all(|&key| doubled.get(key).copied() == map.get(key).map(|&value| value as f32 * 2.0)));
+        assert!(doubled.debug_validate().is_valid());
+        let even = map.filter_map(|_, &value| (value % 2 == 0).then_some(value as u64));
+        assert_eq!((even.len(), even.get(keys[4]), even.get(keys[5])), (5, Some(&4), None));
+        assert!(even.debug_validate().is_valid());
+        let sums = map.zip(&even, |&a, &b| a as u64 + b);
+        assert_eq!((sums.len(), sums.get(keys[8])), (5, Some(&16)));
+        // Both maps hand out the same key next
+        let mut copy = doubled.clone();
+        assert_eq!(Key::from_ffi(map.insert(50).as_ffi()), Key::from_ffi(copy.insert(100.0).as_ffi()));
+    }
+
+    #[test]
+    fn zip_walks_shared_keys() {
+        let mut map = FastSlotMap::new();
+        let keys: Vec<Key> = (0..10u32).map(|value| map.insert(value)).collect();
+        let mut tens = map.filter_map(|_, &value| (value % 3 != 0).then_some(value as u64 * 10));
+        map.remove(keys[4]);
+        map.insert(44);
+        let shared: Vec<u32> = map.iter_zip(&tens).map(|(_, &value, _)| value).collect();
+        assert_eq!(shared, vec![1, 2, 5, 7, 8]);
+        for (_, a, b) in map.iter_zip_mut(&mut tens) {
+            *a += 1;
+            *b += 1;
+        }
+        assert_eq!((map.get(keys[1]), tens.get(keys[1]), map.get(keys[3])), (Some(&2), Some(&11), Some(&3)));
+        assert_eq!(map.iter_zip(&FastSlotMap::<u8>::new()).count(), 0);
+    }
+
+    #[test]
+    fn concurrent_map_grows_ahead_of_inserts() {
+        let map = ConcurrentSlotMap::with_capacity(3);
+        let keys: Vec<Key> = (0..3u64).map(|value| map.insert(value).unwrap()).collect();
+        assert!(map.insert(9).is_none());
+        assert_eq!(map.prepare_grow(4), 12);
+        let more: Vec<Key> = (0..9u64).map(|value| map.insert(100 + value).unwrap()).collect();
+        assert!(map.insert(0).is_none());
+        assert!(keys.iter().zip(0..).all(|(&key, value)| map.read(key) == Some(value)));
+        assert!(more.iter().zip(100..).all(|(&key, value)| map.read(key) == Some(value)));
+        let empty = ConcurrentSlotMap::<u32>::with_capacity(0);
+        assert!(empty.insert(1).is_none());
+        assert_eq!(empty.prepare_grow(3), 4);
+        assert_eq!(empty.read(empty.insert(5).unwrap()), Some(5));
+    }
+
+    #[test]
+    fn key_hashes_as_one_u64() {
+        // Records what the key feeds the hasher
+        struct Recorder(Vec<u64>);
+        impl Hasher for Recorder {
+            fn finish(&self) -> u64 {
+                0
+            }
+            fn write(&mut self, _: &[u8]) {
+                panic!("keys hash as a single u64");
+            }
+            fn write_u64(&mut self, value: u64) {
+                self.0.push(value);
+            }
+        }
+        let mut map = FastSlotMap::new();
+        map.insert(1u32);
+        let key = map.insert(2);
+        let mut recorder = Recorder(Vec::new());
+        key.hash(&mut recorder);
+        assert_eq!(recorder.0, vec![key.as_ffi()]);
+        assert!(HashSet::from([key]).contains(&Key::from_ffi(key.as_ffi())));
+    }
+
+    #[test]
+    fn tombstones_keep_removed_values_readable() {
+        let mut map = FastSlotMap::new();
+        map.set_tombstones(true);
+        let keys: Vec<Key> = (0..10u32).map(|value| map.insert(value)).collect();
+        assert_eq!(map.remove(keys[3]), Some(3));
+        assert_eq!((map.get(keys[3]), map.get_removed(keys[3]), map.get_removed(keys[4])), (None, Some(&3), None));
+        assert_eq!(map.insert(50).index, 10);
+        assert!(map.debug_validate().is_valid());
+        map.set_free_list_policy(FreeListPolicy::Fifo);
+        assert_eq!(map.get_removed(keys[3]), Some(&3));
+        assert_eq!(map.purge_tombstones(), 1);
+        assert!(map.get_removed(keys[3]).is_none());
+        assert_eq!(map.insert(60).index, 3);
+        map.set_tombstones(false);
+        let key = map.insert(1);
+        map.remove(key);
+        assert!(map.get_removed(key).is_none());
+    }
+
+    #[test]
+    fn synced_map_follows_external_ids() {
+        let mut map = SyncedSlotMap::new();
+        let first = map.insert(7u64, 10u32);
+        let second = map.insert(9, 20);
+        assert_eq!((map.get(7), map.id_of(second), map.key_of(7)), (Some(&10), Some(9), Some(first)));
+        let replaced = map.insert(7, 11);
+        assert!(map.get_by_key(first).is_none());
+        assert_eq!((map.get(7), map.len()), (Some(&11), 2));
+        assert_eq!(map.retain_ids(|id| id != 9), 1);
+        assert!(map.get(9).is_none() && map.id_of(second).is_none());
+        assert_eq!(map.remove_by_key(replaced), Some(11));
+        assert!(map.is_empty());
+    }
+
+    #[cfg(feature = "hecs")]
+    #[test]
+    fn hecs_entities_convert_to_keys() {
+        let entity = hecs::Entity::from_bits((3 << 32) | 9).unwrap();
+        let key = Key::from(entity);
+        assert_eq!((key.as_ffi(), key.to_hecs_entity()), ((3 << 32) | 9, Some(entity)));
+    }
+
+    #[cfg(feature = "bevy_ecs")]
+    #[test]
+    fn bevy_entities_convert_to_keys() {
+        let entity = bevy_ecs::entity::Entity::from_bits((2 << 32) | 5);
+        assert_eq!(Key::from(entity).to_bevy_entity(), Some(entity));
+        assert!(Key::from_ffi(5).to_bevy_entity().is_none());
+    }
+
+    #[test]
+    fn boxed_trait_objects_downcast() {
+        trait Named: AsAny {
+            fn name(&self) -> String;
+        }
+        struct Counter(u32);
+        struct Label(&'static str);
+        impl Named for Counter {
+            fn name(&self) -> String {
+                format!("counter {}", self.0)
+            }
+        }
+        impl Named for Label {
+            fn name(&self) -> String {
+                self.0.to_string()
+            }
+        }
+        let mut map = DenseSlotMap::<Box<dyn Named>>::new();
+        let counter = map.insert_boxed(Box::new(Counter(1)));
+        let label = map.insert_boxed(Box::new(Label("label")));
+        assert_eq!(map.get_dyn::<Counter>(counter).map(|counter| counter.0), Some(1));
+        assert!(map.get_dyn::<Label>(counter).is_none());
+        map.get_dyn_mut::<Counter>(counter).unwrap().0 = 5;
+        let names: Vec<String> = map.iter_dyn().map(|(_, value)| value.name()).collect();
+        assert_eq!(names, vec!["counter 5", "label"]);
+        assert_eq!(map.iter_dyn_mut().count(), 2);
+        map.remove(counter);
+        assert!(map.get_dyn::<Counter>(counter).is_none());
+        assert_eq!(map.get_dyn::<Label>(label).map(|label| label.0), Some("label"));
+    }
+
+    #[test]
+    fn maps_build_in_statics() {
+        static MAP: Mutex<FastSlotMap<u32>> = Mutex::new(FastSlotMap::new());
+        static CONCURRENT: ConcurrentSlotMap<u64> = ConcurrentSlotMap::new();
+        let key = MAP.lock().unwrap().insert(5);
+        assert_eq!(MAP.lock().unwrap().get(key), Some(&5));
+        assert!(CONCURRENT.insert(1).is_none());
+        assert_eq!(CONCURRENT.prepare_grow(100), 128);
+        let keys: Vec<Key> = (0..120).map(|value| CONCURRENT.insert(value).unwrap()).collect();
+        assert!(keys.iter().zip(0..).all(|(&key, value)| CONCURRENT.read(key) == Some(value)));
+    }
+
+    #[test]
+    fn hot_and_cold_halves_share_keys() {
+        let mut map: HotColdSlotMap<[f32; 3], String> = HotColdSlotMap::new();
+        let a = map.insert(([1.0; 3], "a".to_string()));
+        let b = map.insert(([2.0; 3], "b".to_string()));
+        assert_eq!(map.cold(a).map(String::as_str), Some("a"));
+        map.iter_hot_mut().for_each(|(_, hot)| hot[0] += 1.0);
+        assert_eq!(map.hot(b), Some(&[3.0, 2.0, 2.0]));
+        map.cold_mut(b).unwrap().push('!');
+        assert_eq!(map.remove(b), Some(([3.0, 2.0, 2.0], "b!".to_string())));
+        assert!(map.get(b).is_none());
+        let c = map.insert(([0.0; 3], "c".to_string()));
+        assert_eq!(map.iter().map(|(_, _, cold)| cold.clone()).collect::<Vec<_>>(), vec!["a", "c"]);
+        assert!(map.contains(c) && map.len() == 2);
+    }
+
+    #[test]
+    fn defragmented_free_list_reuses_in_slot_order() {
+        for policy in [FreeListPolicy::Lifo, FreeListPolicy::Fifo, FreeListPolicy::LowestFirst] {
+            let mut map = FastSlotMap::with_free_list_policy(policy);
+            let keys: Vec<Key> = (0..10u32).map(|value| map.insert(value)).collect();
+            for index in [7, 2, 9, 4] {
+                map.remove(keys[index]);
+            }
+            map.defragment_free_list();
+            let reused: Vec<u32> = (0..4).map(|value| map.insert(value).index).collect();
+            assert_eq!(reused, vec![2, 4, 7, 9], "{policy:?}");
+            assert!(map.debug_validate().is_valid());
+        }
+    }
+
+    #[cfg(feature = "fault-injection")]
+    #[test]
+    fn injected_failures_are_recoverable() {
+        let mut map = FastSlotMap::new();
+        inject_growth_failures(1);
+        assert!(map.try_insert(1u32).is_none());
+        assert_eq!(map.len(), 0);
+        let key = map.try_insert(2).unwrap();
+        inject_growth_failures(5);
+        assert!(!map.try_reserve(100));
+        map.remove(key);
+        // Reusing the freed slot needs no allocation
+        assert!(map.try_insert(3).is_some());
+        inject_growth_failures(1);
+        assert!(catch_unwind(AssertUnwindSafe(|| map.reserve(1000))).is_err());
+        inject_growth_failures(0);
+
+        let concurrent = ConcurrentSlotMap::<u64>::with_capacity(4);
+        inject_growth_failures(1);
+        assert_eq!(concurrent.prepare_grow(10), 4);
+        assert!(concurrent.prepare_grow(10) >= 14);
+        inject_cas_failures(50);
+        let key = concurrent.insert(7).unwrap();
+        assert_eq!((concurrent.read(key), concurrent.remove(key)), (Some(7), Some(7)));
+        inject_cas_failures(3);
+        let atomic = AtomicSlotMap::<u32>::with_capacity(4);
+        let key = atomic.insert(9).unwrap();
+        assert!(atomic.write(key, 10));
+        assert_eq!(atomic.remove(key), Some(10));
+        inject_cas_failures(0);
+    }
+
+    #[test]
+    fn secondary_retains_live_keys() {
+        let mut primary = FastSlotMap::new();
+        let keys: Vec<Key> = (0..5u32).map(|value| primary.insert(value)).collect();
+        let mut secondary = SecondaryMap::new();
+        for &key in &keys {
+            secondary.insert(key, "x");
+        }
+        primary.remove(keys[1]);
+        primary.remove(keys[3]);
+        assert_eq!(secondary.retain_with_primary(&primary), 2);
+        assert_eq!(secondary.retain_with_primary(&primary), 0);
+        assert_eq!(secondary.len(), 3);
+        assert!(secondary.get(keys[1]).is_none() && secondary.get(keys[0]).is_some());
+    }
+
+    #[test]
+    fn referenced_entries_are_not_evicted() {
+        let map = ConcurrentSlotMap::with_capacity(8);
+        let key = map.insert(5u64).unwrap();
+        assert!(map.acquire(key) && map.acquire(key));
+        assert_eq!(map.ref_count(key), 2);
+        assert!(map.remove_if_unreferenced(key).is_none());
+        assert!(map.release(key) && map.release(key) && !map.release(key));
+        assert_eq!(map.remove_if_unreferenced(key), Some(5));
+        assert!(!map.acquire(key) && !map.release(Key::from_ffi(u32::MAX as u64)));
+        assert_eq!(map.ref_count(key), 0);
+        // A handle outliving its entry neither holds up nor releases the next one
+        let held = map.insert(6).unwrap();
+        assert!(map.acquire(held));
+        assert_eq!(map.remove(held), Some(6));
+        let reused = map.insert(7).unwrap();
+        assert_eq!(reused.index, held.index);
+        assert!(map.acquire(reused) && !map.release(held));
+        assert_eq!(map.ref_count(reused), 1);
+        assert!(map.release(reused));
+        assert_eq!(map.remove_if_unreferenced(reused), Some(7));
+
+        let map = Arc::new(ConcurrentSlotMap::with_capacity(8));
+        for round in 0..20u64 {
+            let key = map.insert(round).unwrap();
+            let workers: Vec<_> = (0..2)
+                .map(|_| {
+                    let map = map.clone();
+                    std::thread::spawn(move || {
+                        for _ in 0..100 {
+                            if map.acquire(key) {
+                                assert_eq!(map.read(key), Some(round));
+                                assert!(map.release(key));
+                            }
+                        }
+                    })
+                })
+                .collect();
+            while map.remove_if_unreferenced(key).is_none() {}
+            workers.into_iter().for_each(|worker| worker.join().unwrap());
+        }
+        assert_eq!(map.len(), 0);
+    }
+
+    #[test]
+    fn delta_replays_onto_an_older_copy() {
+        let mut map = FastSlotMap::new();
+        map.set_write_versioning(true);
+        let a = map.insert(1u32);
+        let b = map.insert(2);
+        let mut copy = map.clone();
+        let since = map.export_since(0, encode_u32).unwrap().version().unwrap();
+        map.remove(a);
+        *map.get_mut(b).unwrap() = 20;
+        let far = map.insert_at_raw(9, 4, 90).unwrap();
+        let reused = map.insert(3);
+
+        let finalized = Arc::new(Mutex::new(Vec::new()));
+        let log = finalized.clone();
+        copy.set_finalizer(move |_, value| log.lock().unwrap().push(value));
+        assert!(copy.apply_delta(&map.export_since(since, encode_u32).unwrap(), decode_u32));
+        assert!(finalized.lock().unwrap().is_empty());
+        assert_eq!(copy.get(a), None);
+        assert_eq!(copy.get(b), Some(&20));
+        assert_eq!(copy.get(far), Some(&90));
+        assert_eq!(copy.get(reused), Some(&3));
+        assert_eq!(copy.len(), map.len());
+        assert!(copy.debug_validate().is_valid());
+    }
+
+    #[test]
+    fn malformed_deltas_change_nothing() {
+        let mut map = FastSlotMap::new();
+        map.insert(1u32);
+        let header = |slots: u32, count: u32| {
+            let mut blob = vec![0; 16];
+            blob.extend_from_slice(&slots.to_le_bytes());
+            blob.extend_from_slice(&count.to_le_bytes());
+            DeltaBlob(blob)
+        };
+        assert!(!map.apply_delta(&header(0xFFFF_FFF0, 0), decode_u32));
+        assert!(!map.apply_delta(&header(1, u32::MAX), decode_u32));
+        let mut blob = header(0xFFFF_FFF0, 1);
+        blob.0.extend_from_slice(&[0xF0, 0xFF, 0xFF, 0xFF, 0, 0, 0, 0, 0xFF, 0xFF, 0xFF, 0xFF]);
+        assert!(!map.apply_delta(&blob, decode_u32));
+        assert!(!map.apply_delta(&DeltaBlob(vec![1, 2, 3]), decode_u32));
+        assert_eq!(map.capacity(), 1);
+        assert_eq!(map.len(), 1);
+    }
+
+    #[test]
+    fn full_tagged_map_refuses_inserts() {
+        // 31 tag bits leave room for slot 0 only
+        let mut map = TaggedSlotMap::<u32, 31>::new();
+        let key = map.insert(5, 1).unwrap();
+        assert_eq!((key.tag(), map.get(key)), (5, Some(&1)));
+        assert_eq!(map.insert(2, 2), None);
+        assert_eq!(map.len(), 1);
+        map.remove(key);
+        let reused = map.insert(2, 3).unwrap();
+        assert_eq!((reused.tag(), map.get(reused)), (2, Some(&3)));
+        assert!(!map.contains(key));
+    }
+
+    #[test]
+    fn internal_checks_are_not_profiled() {
+        let mut map = FastSlotMap::new();
+        map.set_access_profiling(true);
+        let key = map.insert(1u32);
+        let _pin = map.pin(key).unwrap();
+        assert!(map.is_pinned(key));
+        assert!(!map.is_provisional(key));
+        assert_eq!(map.remove(key), None);
+        assert!(map.hottest(1).is_empty());
+        map.get(key);
+        assert_eq!(map.hottest(1), vec![(key, 1)]);
+    }
+
+    #[cfg(feature = "reuse-report")]
+    #[test]
+    fn internal_checks_are_not_failed_lookups() {
+        let mut map = FastSlotMap::new();
+        let key = map.insert(1u32);
+        let pin = map.pin(key).unwrap();
+        assert_eq!(map.remove(key), None);
+        drop(pin);
+        map.remove(key);
+        assert!(!map.is_pinned(key));
+        assert!(!map.is_provisional(key));
+        assert_eq!(map.reuse_report().failed_lookups, 0);
+        assert_eq!(map.remove(key), None);
+        assert_eq!(map.reuse_report().failed_lookups, 1);
+    }
+
+    #[test]
+    fn pooled_objects_are_reset_on_reuse() {
+        #[derive(Default)]
+        struct Trail {
+            points: Vec<f32>,
+            resets: u32,
+        }
+        impl Reset for Trail {
+            fn reset(&mut self) {
+                self.points.clear();
+                self.resets += 1;
+            }
+        }
+        let mut pool: Pool<Trail> = Pool::new();
+        let (a, trail) = pool.acquire();
+        trail.points.extend([1.0, 2.0]);
+        let (b, _) = pool.acquire();
+        let capacity = pool.get(a).unwrap().points.capacity();
+        assert!(pool.release(a));
+        assert!(!pool.release(a));
+        assert_eq!((pool.len(), pool.idle()), (1, 1));
+        let (c, trail) = pool.acquire();
+        assert_eq!(trail.resets, 1);
+        assert!(trail.points.is_empty() && trail.points.capacity() == capacity);
+        assert!(pool.get(a).is_none());
+        pool.get_mut(c).unwrap().points.push(9.0);
+        assert_eq!(pool.iter().count(), 2);
+        assert!(pool.contains(b));
+    }
+
+    #[test]
+    fn key_lists_skip_stale_keys() {
+        let mut map = FastSlotMap::new();
+        let keys: Vec<Key> = (0..5u32).map(|value| map.insert(value)).collect();
+        map.remove(keys[2]);
+        let order = [keys[4], keys[2], keys[0], keys[3]];
+        assert_eq!(map.iter_keys(&order).map(|(_, &value)| value).collect::<Vec<_>>(), vec![4, 0, 3]);
+        let mut stale = Vec::new();
+        let live: Vec<Key> = map.iter_keys_with_stale(&order, &mut stale).map(|(key, _)| key).collect();
+        assert_eq!((live, stale), (vec![keys[4], keys[0], keys[3]], vec![keys[2]]));
+    }
+
+    #[test]
+    fn grid_forgets_stale_occupants() {
+        let mut map = FastSlotMap::new();
+        let mut grid = SlotGrid::new(1.0);
+        let old = map.insert(1u32);
+        grid.update_position(old, [0.5, 0.5]);
+        map.remove(old);
+        let new = map.insert(2);
+        assert_eq!(new.index, old.index);
+        grid.update_position(new, [0.5, 0.5]);
+        assert_eq!(grid.query_aabb([0.0, 0.0], [1.0, 1.0]), vec![new]);
+        grid.update_position(new, [5.5, 5.5]);
+        assert!(grid.query_aabb([0.0, 0.0], [1.0, 1.0]).is_empty());
+        assert_eq!(grid.query_aabb([5.0, 5.0], [6.0, 6.0]), vec![new]);
+        assert_eq!(grid.len(), 1);
+    }
+
+    #[test]
+    fn queued_inserts_keep_producer_order() {
+        let queue = Arc::new(InsertQueue::new());
+        let mut map = FastSlotMap::new();
+        queue.push(1u64);
+        queue.push(2);
+        let keys = queue.flush(&mut map);
+        assert_eq!(keys.iter().map(|&key| map[key]).collect::<Vec<_>>(), vec![1, 2]);
+        assert!(queue.is_empty());
+        let producers: Vec<_> = (0..4u64)
+            .map(|producer| {
+                let queue = queue.clone();
+                std::thread::spawn(move || (0..500).for_each(|n| queue.push(producer * 10_000 + n)))
+            })
+            .collect();
+        let mut keys = Vec::new();
+        for _ in 0..20 {
+            keys.extend(queue.flush(&mut map));
+        }
+        producers.into_iter().for_each(|producer| producer.join().unwrap());
+        keys.extend(queue.flush(&mut map));
+        assert_eq!(keys.len(), 2000);
+        let values: Vec<u64> = keys.iter().map(|&key| map[key]).collect();
+        for producer in 0..4 {
+            let own: Vec<u64> = values.iter().copied().filter(|value| value / 10_000 == producer).collect();
+            assert!(own.windows(2).all(|pair| pair[0] < pair[1]));
+        }
+    }
+
+    #[test]
+    fn scoped_inserts_are_removed_at_scope_end() {
+        let mut map = FastSlotMap::new();
+        let keep = map.insert(1u32);
+        let (a, b);
+        {
+            let mut scope = map.scoped();
+            a = scope.insert(2);
+            b = scope.insert(3);
+            assert_eq!((scope.get(a), scope.len()), (Some(&2), 3));
+            scope.remove(b);
+            *scope.get_mut(keep).unwrap() = 10;
+            assert_eq!(scope.keys(), &[a, b]);
+        }
+        assert_eq!(map.len(), 1);
+        assert!(map.get(a).is_none());
+        assert_eq!(map.get(keep), Some(&10));
+        assert!(map.debug_validate().is_valid());
+    }
+
+    #[test]
+    fn frozen_view_matches_the_map() {
+        let empty = FastSlotMap::<u32>::new().freeze();
+        assert!(empty.get(Key::null()).is_none() && empty.get(Key::from_ffi(0)).is_none() && empty.is_empty());
+        let mut map = FastSlotMap::new();
+        let keys: Vec<Key> = (0..5u32).map(|value| map.insert(value)).collect();
+        map.remove(keys[1]);
+        let reused = map.insert(11);
+        map.remove(keys[3]);
+        let frozen = map.freeze();
+        assert_eq!((frozen.len(), frozen.get(keys[0]), frozen.get(reused)), (4, Some(&0), Some(&11)));
+        assert!(frozen.get(keys[1]).is_none() && frozen.get(keys[3]).is_none());
+        // An index past the end that masks onto a live slot
+        assert!(frozen.get(Key::from_ffi(keys[0].as_ffi() + 8)).is_none());
+        for raw in (0..64u64).flat_map(|index| (0..3u64).map(move |generation| generation << 32 | index)) {
+            let key = Key::from_ffi(raw);
+            assert_eq!(frozen.get(key), map.iter().find(|&(live, _)| live == key).map(|(_, value)| value));
+        }
+        assert!(frozen.iter().eq(map.iter()));
+    }
+
+    #[test]
+    fn finalizers_run_once_per_entry() {
+        let finalized = Arc::new(Mutex::new(Vec::new()));
+        let mut map = FastSlotMap::new();
+        let log = finalized.clone();
+        map.set_finalizer(move |_, value| log.lock().unwrap().push(value));
+        let keys: Vec<Key> = (0..6u32).map(|value| map.insert(value)).collect();
+        assert_eq!(map.remove(keys[0]), Some(0));
+        drop(map.clone());
+        assert_eq!(*finalized.lock().unwrap(), vec![0]);
+        let pin = map.pin(keys[1]).unwrap();
+        map.clear();
+        assert_eq!(map.len(), 1);
+        drop(pin);
+        assert_eq!(*finalized.lock().unwrap(), vec![0, 2, 3, 4, 5]);
+        map.insert(10);
+        // Absorbed entries live on, so the source's finalizer doesn't run
+        let mut source = FastSlotMap::new();
+        let log = finalized.clone();
+        source.set_finalizer(move |_, value| log.lock().unwrap().push(1000 + value));
+        source.insert(7);
+        map.absorb(source);
+        assert_eq!(finalized.lock().unwrap().len(), 5);
+        assert!(map.invalidate_all());
+        map.insert(20);
+        drop(map);
+        let mut finalized = finalized.lock().unwrap().clone();
+        finalized.sort_unstable();
+        assert_eq!(finalized, vec![0, 1, 2, 3, 4, 5, 7, 10, 20]);
+    }
+
+    #[test]
+    fn scratch_entries_reset_per_frame() {
+        let mut map = FastSlotMap::new();
+        map.reserve(16);
+        let a = map.insert(1u32);
+        let b = map.insert(2);
+        let mut scratch: ScratchMap<Vec<u32>> = ScratchMap::for_map(&map);
+        scratch.get_or_default(a).push(5);
+        scratch.get_or_default(a).push(6);
+        scratch.insert(b, vec![9]);
+        assert_eq!((scratch.get(a), scratch.len()), (Some(&vec![5, 6]), 2));
+        scratch.reset();
+        assert!(scratch.get(a).is_none() && scratch.is_empty());
+        assert!(scratch.get_or_default(a).is_empty());
+        map.remove(b);
+        let reused = map.insert(3);
+        scratch.insert(reused, vec![1]);
+        assert!(scratch.get(b).is_none());
+        assert_eq!(scratch.get_mut(reused), Some(&mut vec![1]));
+        (0..5).for_each(|_| scratch.reset());
+        assert!(!scratch.contains_key(a));
+    }
+
+    #[test]
+    fn steal_iter_cursor_stops_at_the_end() {
+        let mut map = FastSlotMap::new();
+        for i in 0..10u32 {
+            map.insert(i);
+        }
+        let steal = map.steal_iter(u32::MAX);
+        let batch: Vec<u32> = steal.next_batch().unwrap().map(|(_, value)| *value).collect();
+        assert_eq!(batch, (0..10).collect::<Vec<_>>());
+        for _ in 0..1000 {
+            assert!(steal.next_batch().is_none());
+        }
+        assert_eq!(steal.cursor.load(Ordering::Relaxed), 10);
+
+        let steal = map.steal_iter(4);
+        let mut total = 0;
+        while let Some(batch) = steal.next_batch() {
+            for (_, value) in batch {
+                *value += 1;
+                total += 1;
+            }
+        }
+        assert_eq!(total, 10);
+        assert_eq!(map.iter().map(|(_, value)| *value).sum::<u32>(), 55);
+    }
+
+    #[test]
+    fn large_values_overflow_into_boxes() {
+        #[derive(Default, Clone, Copy, PartialEq, Debug)]
+        struct Large([u64; 32]);
+        assert!(OverflowStorage::<Large, 64>::new().is_boxed());
+        assert!(!OverflowStorage::<u32, 64>::new().is_boxed());
+        let mut map = FastSlotMap::with_storage(OverflowStorage::<Large, 64>::new());
+        let a = map.insert(Large([1; 32]));
+        let b = map.insert(Large([2; 32]));
+        map.get_mut(a).unwrap().0[0] = 9;
+        assert_eq!(map.get(a).unwrap().0[0], 9);
+        assert_eq!(map.remove(b), Some(Large([2; 32])));
+        assert_eq!(map.iter().count(), 1);
+        assert_eq!(map.clone().get(a).unwrap().0[0], 9);
+        let remap = map.compact();
+        assert_eq!(map.get(remap.remap(a).unwrap()).unwrap().0[0], 9);
+    }
+
+    #[test]
+    fn key_sets_combine_across_maps() {
+        let mut map = FastSlotMap::new();
+        let keys: Vec<Key> = (0..6u32).map(|value| map.insert(value)).collect();
+        let mut other = map.clone();
+        map.remove(keys[1]);
+        other.remove(keys[2]);
+        other.remove(keys[4]);
+        let reused = other.insert(44);
+        assert_eq!(reused.index, 4);
+        assert_eq!(map.intersect_keys(&other).collect::<Vec<_>>(), vec![keys[0], keys[3], keys[5]]);
+        assert_eq!(map.union_keys(&other).collect::<Vec<_>>(), vec![keys[0], keys[1], keys[2], keys[3], keys[4], reused, keys[5]]);
+        assert_eq!(map.difference_keys(&other).collect::<Vec<_>>(), vec![keys[2], keys[4]]);
+        assert_eq!(map.live_keys_bitset(), vec![0b111101]);
+        let mut secondary = SecondaryMap::new();
+        for (index, tag) in [(0, 'x'), (1, 'y'), (5, 'z')] {
+            secondary.insert(keys[index], tag);
+        }
+        assert_eq!(secondary.intersect_keys(&map).collect::<Vec<_>>(), vec![keys[0], keys[5]]);
+        assert_eq!(secondary.iter().map(|(_, &tag)| tag).collect::<String>(), "xyz");
+    }
+
+    #[test]
+    fn transaction_rollback_keeps_keys_stale() {
+        let mut map = FastSlotMap::new();
+        let kept = map.insert(1u32);
+        let mut inside = None;
+        let result: Result<(), ()> = map.transaction(|tx| {
+            assert_eq!(tx.remove(kept), Some(1));
+            assert_eq!(tx.remove(kept), None);
+            assert_eq!(tx.get(kept), None);
+            assert_eq!(tx.len(), 0);
+            inside = Some(tx.insert(2));
+            Err(())
+        });
+        assert!(result.is_err());
+        let inside = inside.unwrap();
+        assert_eq!(map.get(kept), Some(&1));
+        assert_eq!(map.get(inside), None);
+        map.remove(kept);
+        let reused = map.insert(3);
+        assert_eq!(map.get(reused), Some(&3));
+        assert_eq!(map.get(inside), None);
+        assert!(map.debug_validate().is_valid());
+    }
+
+    #[test]
+    fn transaction_finalizes_on_commit_only() {
+        let finalized = Arc::new(Mutex::new(Vec::new()));
+        let mut map = FastSlotMap::new();
+        let log = finalized.clone();
+        map.set_finalizer(move |_, value: u32| log.lock().unwrap().push(value));
+        let a = map.insert(1);
+        let _: Result<(), ()> = map.transaction(|tx| {
+            tx.insert(2);
+            tx.remove(a);
+            Err(())
+        });
+        assert!(finalized.lock().unwrap().is_empty());
+        let b = map
+            .transaction(|tx| {
+                tx.remove(a);
+                Ok::<_, ()>(tx.insert(3))
+            })
+            .unwrap();
+        assert_eq!(*finalized.lock().unwrap(), vec![1]);
+        assert_eq!(map.get(a), None);
+        assert_eq!(map.get(b), Some(&3));
+        assert_eq!(map.len(), 1);
+    }
+
+    #[test]
+    fn invalid_keys_follow_the_policy() {
+        let mut map = FastSlotMap::new();
+        let key = map.insert(1u32);
+        let wild = Key::from_ffi(0x0000_0007_0000_0005);
+        for invalid in [Key::null(), wild] {
+            assert_eq!(map.get(invalid), None);
+            assert_eq!(map.get_mut(invalid), None);
+            assert_eq!(map.remove(invalid), None);
+            assert!(!map.contains(invalid));
+        }
+        map.set_invalid_key_policy(InvalidKeyPolicy::Panic);
+        assert_eq!(map.get(key), Some(&1));
+        for invalid in [Key::null(), wild] {
+            assert!(catch_unwind(AssertUnwindSafe(|| map.remove(invalid))).is_err());
+        }
+        assert_eq!(map.remove(key), Some(1));
+        assert!(catch_unwind(AssertUnwindSafe(|| map.remove(key))).is_err());
+        // Index panics whatever the policy
+        map.set_invalid_key_policy(InvalidKeyPolicy::ReturnNone);
+        assert!(catch_unwind(AssertUnwindSafe(|| map[Key::null()])).is_err());
+    }
+
+    #[test]
+    fn saved_maps_load_back() {
+        let mut map = FastSlotMap::new();
+        let keys: Vec<Key> = (0..500u64).map(|value| map.insert(value * 3)).collect();
+        for &key in keys.iter().step_by(7) {
+            map.remove(key);
+        }
+        let encode = |value: &u64, out: &mut Vec<u8>| out.extend_from_slice(&value.to_le_bytes());
+        let decode = |bytes: &[u8]| Some(u64::from_le_bytes(bytes.try_into().ok()?));
+        let blob = map.save(encode);
+        let mut loaded = vec![FastSlotMap::<u64>::load(&blob, decode).unwrap()];
+        #[cfg(feature = "rayon")]
+        loaded.push(FastSlotMap::<u64>::par_load(&blob, decode).unwrap());
+        for copy in &mut loaded {
+            assert_eq!(copy.len(), map.len());
+            for (index, &key) in keys.iter().enumerate() {
+                let key = Key::from_ffi(key.as_ffi());
+                let expected = if index % 7 == 0 { None } else { Some(index as u64 * 3) };
+                assert_eq!(copy.get(key).copied(), expected);
+            }
+            assert_eq!(copy.insert(9).index, 0);
+        }
+        assert!(FastSlotMap::<u64>::load(&blob[..blob.len() - 1], decode).is_none());
+        assert!(FastSlotMap::<u64>::load(&blob, |_| None).is_none());
+        map.invalidate_all();
+        let cleared = FastSlotMap::<u64>::load(&map.save(encode), decode).unwrap();
+        assert!(cleared.is_empty() && cleared.get(Key::from_ffi(keys[1].as_ffi())).is_none());
+    }
+
+    #[test]
+    fn slot_buffer_reuses_freed_bytes() {
+        let mut buffer = SlotBuffer::new();
+        let a = buffer.insert(b"hello");
+        let b = buffer.insert(b"world!");
+        let c = buffer.insert(b"xyz");
+        let empty = buffer.insert(b"");
+        assert_eq!((buffer.get(a), buffer.get(empty)), (Some(&b"hello"[..]), Some(&b""[..])));
+        assert_eq!(buffer.buffer_len(), 14);
+        assert!(buffer.remove(a) && !buffer.remove(a));
+        assert_eq!(buffer.free_bytes(), 5);
+        assert!(buffer.remove(b));
+        assert_eq!(buffer.free_bytes(), 11);
+        let d = buffer.insert(b"abcdefgh");
+        assert_eq!((buffer.free_bytes(), buffer.get(d)), (3, Some(&b"abcdefgh"[..])));
+        buffer.get_mut(c).unwrap()[0] = b'X';
+        assert_eq!(buffer.get(c), Some(&b"Xyz"[..]));
+        assert!(buffer.remove(c));
+        // Trailing free space is trimmed
+        assert_eq!((buffer.buffer_len(), buffer.free_bytes()), (8, 0));
+        assert!(buffer.get(a).is_none());
+        assert_eq!((buffer.len(), buffer.iter().count()), (2, 2));
+    }
+
+    #[test]
+    fn collect_into_reuses_buffers() {
+        let mut map = FastSlotMap::new();
+        let keys: Vec<Key> = (0..10u32).map(|value| map.insert(value)).collect();
+        map.remove(keys[3]);
+        let (mut values, mut live) = (vec![99], Vec::new());
+        map.collect_values_into(&mut values);
+        map.collect_keys_into(&mut live);
+        assert_eq!(values, vec![0, 1, 2, 4, 5, 6, 7, 8, 9]);
+        assert_eq!(live, map.iter().map(|(key, _)| key).collect::<Vec<_>>());
+        assert_eq!(map.iter().len(), 9);
+        map.invalidate_all();
+        map.collect_values_into(&mut values);
+        assert!(values.is_empty());
+    }
+
+    #[test]
+    fn watermarks_fire_for_every_insert_path() {
+        let fired = Arc::new(Mutex::new(Vec::new()));
+        let mut map = FastSlotMap::new();
+        map.reserve(8);
+        let log = fired.clone();
+        map.on_occupancy_above(0.5, move |_| log.lock().unwrap().push("above"));
+        let log = fired.clone();
+        map.on_occupancy_below(0.25, move |_| log.lock().unwrap().push("below"));
+        let span = map.insert_contiguous(&[1u32; 5]);
+        assert_eq!(*fired.lock().unwrap(), vec!["above"]);
+        map.remove_span(span);
+        assert_eq!(*fired.lock().unwrap(), vec!["above", "below"]);
+        for index in 0..5 {
+            map.insert_at_raw(index, 3, 0).unwrap();
+        }
+        assert_eq!(*fired.lock().unwrap(), vec!["above", "below", "above"]);
+        assert_eq!(map.len(), 5);
+        assert!(map.debug_validate().is_valid());
+    }
+
+    #[test]
+    fn pinned_values_never_move() {
+        use std::cell::Cell;
+        use std::marker::PhantomPinned;
+        use std::pin::Pin;
+        use std::rc::Rc;
+        struct Node {
+            me: *const Node,
+            drops: Rc<Cell<u32>>,
+            _pin: PhantomPinned,
+        }
+        impl Drop for Node {
+            fn drop(&mut self) {
+                self.drops.set(self.drops.get() + 1);
+            }
+        }
+        let drops = Rc::new(Cell::new(0));
+        let mut map: PinnedSlotMap<Node, 4> = PinnedSlotMap::new();
+        let mut keys = Vec::new();
+        for _ in 0..20 {
+            let (key, mut node) = map.insert_pinned(Node { me: std::ptr::null(), drops: drops.clone(), _pin: PhantomPinned });
+            let address = &*node as *const Node;
+            unsafe { node.as_mut().get_unchecked_mut().me = address };
+            keys.push(key);
+        }
+        for &key in &keys {
+            let node = map.get(key).unwrap();
+            assert_eq!(node.me, node as *const Node);
+        }
+        let node: Pin<&mut Node> = map.get_pin(keys[3]).unwrap();
+        assert_eq!(node.me, &*node as *const Node);
+        assert!(map.remove(keys[3]) && !map.remove(keys[3]));
+        assert_eq!(drops.get(), 1);
+        assert!(map.get(Key::from_ffi(keys[3].as_ffi() + (1 << 32))).is_none());
+        assert_eq!((map.len(), map.iter().count()), (19, 19));
+        drop(map);
+        assert_eq!(drops.get(), 20);
+        let mut strings: PinnedSlotMap<String> = PinnedSlotMap::default();
+        let key = strings.insert("a".into());
+        strings.get_mut(key).unwrap().push('b');
+        assert_eq!(strings.get(key).unwrap(), "ab");
+    }
+
+    fn block_on<F: std::future::Future>(future: F) -> (F::Output, u32) {
+        use std::task::{Context, Poll, Waker};
+        let mut future = std::pin::pin!(future);
+        let mut context = Context::from_waker(Waker::noop());
+        let mut polls = 0;
+        loop {
+            polls += 1;
+            if let Poll::Ready(output) = future.as_mut().poll(&mut context) {
+                return (output, polls);
+            }
+        }
+    }
+
+    #[test]
+    fn async_compaction_yields_between_steps() {
+        let mut map = FastSlotMap::new();
+        let keys: Vec<Key> = (0..100u32).map(|value| map.insert(value)).collect();
+        for &key in &keys[..60] {
+            map.remove(key);
+        }
+        let (remap, polls) = block_on(map.compact_async(5));
+        assert!(polls > 5);
+        for (value, &key) in keys.iter().enumerate().skip(60) {
+            assert_eq!(map.get(remap.remap(key).unwrap()), Some(&(value as u32)));
+        }
+        let mut indices: Vec<u32> = map.iter().map(|(key, _)| key.index).collect();
+        indices.sort_unstable();
+        assert_eq!(indices, (0..40).collect::<Vec<_>>());
+        assert!(!map.is_compacting());
+        let (_, polls) = block_on(map.clear_async(10));
+        assert!(polls >= 4 && map.is_empty());
+    }
+
+    #[test]
+    fn provisional_inserts_commit_or_abort() {
+        let mut map = FastSlotMap::new();
+        let committed = map.insert(1u32);
+        let p = map.insert_provisional(2);
+        let q = map.insert_provisional(3);
+        assert!(map.is_provisional(p) && !map.is_provisional(committed));
+        assert_eq!(map.get(p), Some(&2));
+        assert!(map.commit(p) && !map.commit(p) && !map.is_provisional(p));
+        assert_eq!(map.abort(p), None);
+        assert_eq!(map.abort(q), Some(3));
+        assert!(map.get(q).is_none() && map.abort(q).is_none());
+        #[cfg(feature = "reuse-report")]
+        assert_eq!((map.reuse_report().stale_keys, map.reuse_report().aborted_inserts), (0, 1));
+        let reused = map.insert(9);
+        assert!(!map.is_provisional(reused));
+        let removed = map.insert_provisional(4);
+        map.remove(removed);
+        let reused = map.insert(5);
+        assert!(!map.is_provisional(reused));
+
+        let mut map = FastSlotMap::new();
+        let keys: Vec<Key> = (0..5u32).map(|value| map.insert(value)).collect();
+        let pending = map.insert_provisional(7);
+        for &key in &keys {
+            map.remove(key);
+        }
+        let pending = map.compact().remap(pending).unwrap();
+        assert!(map.is_provisional(pending));
+        assert_eq!(map.abort(pending), Some(7));
+        let pending = map.insert_provisional(1);
+        map.invalidate_all();
+        let fresh = map.insert(2);
+        assert!(!map.is_provisional(pending) && !map.is_provisional(fresh));
+    }
+
+    #[test]
+    fn split_map_keeps_large_values_apart() {
+        let mut map: SplitSlotMap<u16, Vec<f32>> = SplitSlotMap::new();
+        let a = map.insert(Split::Small(3));
+        let b = map.insert(Split::Large(vec![1.0, 2.0]));
+        assert_eq!((map.get(a), map.get(b)), (Some(Split::Small(&3)), Some(Split::Large(&vec![1.0, 2.0]))));
+        if let Some(Split::Large(points)) = map.get_mut(b) {
+            points.push(3.0);
+        }
+        assert_eq!(map.large_len(), 1);
+        assert_eq!(map.replace(a, Split::Large(vec![9.0])), Some(Split::Small(3)));
+        assert_eq!(map.large_len(), 2);
+        assert_eq!(map.replace(b, Split::Small(7)), Some(Split::Large(vec![1.0, 2.0, 3.0])));
+        assert_eq!(map.large_len(), 1);
+        assert_eq!(map.remove(a), Some(Split::Large(vec![9.0])));
+        assert_eq!((map.large_len(), map.remove(a)), (0, None));
+        assert_eq!((map.len(), map.iter().count()), (1, 1));
+    }
+
+    #[test]
+    fn shuffled_iteration_is_seeded() {
+        let mut map = FastSlotMap::new();
+        let keys: Vec<Key> = (0..100u32).map(|value| map.insert(value)).collect();
+        map.remove(keys[10]);
+        let values = |map: &FastSlotMap<u32>| map.iter().map(|(_, &value)| value).collect::<Vec<_>>();
+        let plain = values(&map);
+        map.set_iteration_shuffle(Some(7));
+        let shuffled = values(&map);
+        assert_eq!(shuffled, values(&map));
+        assert_ne!(shuffled, plain);
+        let mut sorted = shuffled.clone();
+        sorted.sort_unstable();
+        assert_eq!(sorted, plain);
+        assert!(map.iter().rev().map(|(_, &value)| value).eq(shuffled.iter().rev().copied()));
+        map.set_iteration_shuffle(Some(8));
+        let reseeded = values(&map);
+        assert_ne!(reseeded, shuffled);
+        assert!(map.iter_mut().map(|(_, value)| *value).eq(reseeded.iter().copied()));
+        assert_eq!(map.iter().len(), 99);
+        assert_eq!(map.intersect_keys(&map.clone()).count(), 99);
+        assert_eq!(map.find(|&value| value > 50), Some(keys[51]));
+        map.set_iteration_shuffle(None);
+        assert_eq!(values(&map), plain);
+    }
+
+    #[test]
+    fn batch_validation_sets_one_bit_per_key() {
+        let mut map = FastSlotMap::new();
+        let mut keys: Vec<Key> = (0..300u32).map(|value| map.insert(value)).collect();
+        for &key in keys.iter().step_by(3) {
+            map.remove(key);
+        }
+        keys.extend([Key::from_ffi(5000), Key::from_ffi(u32::MAX as u64), Key::from_ffi(1 << 32 | 2999)]);
+        let mut bits = Vec::new();
+        assert_eq!(map.validate_batch(&keys, &mut bits), 200);
+        assert_eq!(bits.len(), keys.len().div_ceil(64));
+        for (index, &key) in keys.iter().enumerate() {
+            assert_eq!(bits[index / 64] >> (index % 64) & 1 == 1, map.contains(key), "key {index}");
+        }
+        map.invalidate_all();
+        assert_eq!(map.validate_batch(&keys, &mut bits), 0);
+        let key = map.insert(1);
+        assert_eq!(map.validate_batch(&[key; 9], &mut bits), 9);
+    }
+
+    #[test]
+    fn references_map_back_to_keys() {
+        let mut map = FastSlotMap::new();
+        let keys: Vec<Key> = (0..10u64).map(|value| map.insert(value)).collect();
+        for &key in &keys {
+            assert_eq!(map.key_of(map.get(key).unwrap()), Some(key));
+        }
+        let removed = map.get(keys[3]).unwrap() as *const u64;
+        map.remove(keys[3]);
+        assert_eq!(map.key_of_ptr(removed), None);
+        assert_eq!(map.key_of(&5), None);
+        assert_eq!(map.key_of_ptr((removed as usize + 1) as *const u64), None);
+        assert_eq!(map.key_of_ptr(std::ptr::null()), None);
+        // Zero-sized values share one address
+        let mut units = FastSlotMap::new();
+        let key = units.insert(());
+        assert_eq!(units.key_of(units.get(key).unwrap()), None);
+    }
+
+    #[test]
+    fn snapshots_see_a_fixed_version() {
+        let map = MvccSlotMap::new();
+        let a = map.insert(1u32);
+        let b = map.insert(2);
+        let first = map.snapshot();
+        assert!(map.write(a, 10));
+        let c = map.insert(3);
+        assert_eq!(map.remove(b), Some(2));
+        let d = map.insert(4);
+        assert_eq!(d.index, b.index);
+        assert_eq!([first.get(a), first.get(b), first.get(c), first.get(d)], [Some(1), Some(2), None, None]);
+        assert_eq!([map.get(a), map.get(b), map.get(d)], [Some(10), None, Some(4)]);
+        let mut entries: Vec<(Key, u32)> = first.iter().collect();
+        entries.sort_by_key(|&(_, value)| value);
+        assert_eq!((first.len(), entries), (2, vec![(a, 1), (b, 2)]));
+        assert!(map.retained_versions() > 0);
+        let second = map.snapshot();
+        for value in 11..14 {
+            map.write(a, value);
+        }
+        assert_eq!(second.get(a), Some(10));
+        drop(first);
+        assert_eq!((second.get(a), map.retained_versions()), (Some(10), 1));
+        drop(second);
+        assert_eq!(map.retained_versions(), 0);
+        assert!(!map.write(b, 5));
+
+        let map = Arc::new(MvccSlotMap::new());
+        let keys: Vec<Key> = (0..100).map(|_| map.insert(0u64)).collect();
+        let writer = {
+            let map = map.clone();
+            std::thread::spawn(move || {
+                for round in 1..100u64 {
+                    keys.iter().for_each(|&key| assert!(map.write(key, round)));
+                }
+            })
+        };
+        for _ in 0..50 {
+            let snapshot = map.snapshot();
+            let values: Vec<u64> = snapshot.iter().map(|(_, value)| value).collect();
+            assert_eq!(values.len(), 100);
+            // Rounds are written in slot order, so a consistent view is one
+            // round up to some slot and the round before after it
+            assert!(values.windows(2).all(|pair| pair[0] == pair[1] || pair[0] == pair[1] + 1));
+            assert!(values[0] - values[99] <= 1);
+            assert_eq!(values, snapshot.iter().map(|(_, value)| value).collect::<Vec<_>>());
+        }
+        writer.join().unwrap();
+        assert_eq!(map.retained_versions(), 0);
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn parallel_updates_touch_live_entries_only() {
+        let mut map = FastSlotMap::new();
+        let keys: Vec<Key> = (0..1000u32).map(|value| map.insert(value)).collect();
+        for &key in &keys[100..900] {
+            map.remove(key);
+        }
+        map.par_update(|key, value| *value = *value * 2 + (key.index == *value) as u32);
+        for (index, &key) in keys.iter().enumerate() {
+            let expected = (!(100..900).contains(&index)).then_some(index as u32 * 2 + 1);
+            assert_eq!(map.get(key).copied(), expected);
+        }
+        map.invalidate_all();
+        map.par_update(|_, _| panic!("no live entries"));
+    }
+
+    #[test]
+    fn lifetimes_cover_every_insert_path() {
+        let now = Arc::new(AtomicU64::new(0));
+        let clock = now.clone();
+        let mut map = FastSlotMap::new();
+        map.set_lifetime_telemetry(move || clock.load(Ordering::Relaxed));
+        let span = map.insert_contiguous(&[1u32, 2]);
+        let raw = map.insert_at_raw(5, 3, 0).unwrap();
+        now.store(10, Ordering::Relaxed);
+        map.remove_span(span);
+        map.remove(raw);
+        let stats = map.lifetime_stats().unwrap();
+        assert_eq!((stats.lifetimes.count(), stats.lifetimes.max()), (3, 10));
+
+        map.scope(1, |mut shards| while shards[0].insert(4).is_some() {});
+        now.store(15, Ordering::Relaxed);
+        assert!(map.invalidate_all());
+        now.store(18, Ordering::Relaxed);
+        map.insert(5);
+        let stats = map.lifetime_stats().unwrap();
+        // Vacated at 10 and refilled by the shard; voided at 15 and reused at 18
+        assert_eq!(stats.lifetimes.count(), 3);
+        assert_eq!(stats.idle.count(), 4);
+        assert_eq!(stats.idle.max(), 3);
+    }
+
+    #[test]
+    fn absorb_keeps_side_tables() {
+        let now = Arc::new(AtomicU64::new(0));
+        for fast in [true, false] {
+            let mut source = FastSlotMap::new();
+            let permanent = source.insert(1u32);
+            let provisional = source.insert_provisional(2);
+            let mut map = FastSlotMap::new();
+            if !fast {
+                map.insert(0);
+            }
+            let clock = now.clone();
+            map.set_lifetime_telemetry(move || clock.load(Ordering::Relaxed));
+            map.set_write_versioning(true);
+            let remap = map.absorb(source);
+            let permanent = Key::from_ffi(remap.remap(permanent).unwrap().as_ffi());
+            let provisional = Key::from_ffi(remap.remap(provisional).unwrap().as_ffi());
+            assert!(map.is_provisional(provisional));
+            assert!(!map.is_provisional(permanent));
+            assert!(map.write_version(permanent).unwrap() > 0);
+            now.store(4, Ordering::Relaxed);
+            assert_eq!(map.abort(provisional), Some(2));
+            map.remove(permanent);
+            let stats = map.lifetime_stats().unwrap();
+            assert_eq!((stats.lifetimes.count(), stats.lifetimes.max()), (2, 4));
+            now.store(0, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn union_secondary_map_spans_two_spaces() {
+        let mut statics = FastSlotMap::new();
+        let mut dynamics = FastSlotMap::new();
+        let s = statics.insert(1u32);
+        let d = dynamics.insert(2.0f32);
+        assert_eq!(s.index, d.index);
+        let (tagged_s, tagged_d) = (TaggedKey::<1>::new(0, s), TaggedKey::<1>::new(1, d));
+        let mut names = UnionSecondaryMap::new();
+        names.insert(tagged_s, "rock");
+        names.insert(tagged_d, "bird");
+        assert_eq!((names.get(tagged_s), names.get(tagged_d), names.len()), (Some(&"rock"), Some(&"bird"), 2));
+        assert_eq!(names.get_live(tagged_d, &statics, &dynamics), Some(&"bird"));
+        dynamics.remove(d);
+        assert_eq!(names.get_live(tagged_d, &statics, &dynamics), None);
+        assert_eq!(names.get_live(tagged_s, &statics, &dynamics), Some(&"rock"));
+        assert_eq!(names.sync_with(&statics, &dynamics), 1);
+        assert!(!names.contains_key(tagged_d));
+        assert_eq!(names.iter().map(|(key, _)| key).collect::<Vec<_>>(), vec![tagged_s]);
+        assert_eq!(names.space(0).get(s), Some(&"rock"));
+        assert_eq!((tagged_d.tag(), tagged_d.untagged()), (1, d));
+    }
+}